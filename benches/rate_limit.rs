@@ -0,0 +1,58 @@
+//! Throughput of the in-memory rate limit store under many distinct clients.
+//!
+//! Run with `cargo bench --features ssr --bench rate_limit`. Every thread
+//! spreads its requests over a million client addresses, first creating
+//! their buckets and then updating them.
+
+use futures::FutureExt;
+use std::{
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr},
+    thread,
+    time::{Duration, Instant},
+};
+use webapp::rate_limit::{MemoryStore, Policy, RateLimitStore};
+
+const CLIENTS: u32 = 1_000_000;
+const REQUESTS: u32 = 2_000_000;
+const POLICY: Policy = Policy::new(20, Duration::from_secs(60), 20);
+
+/// Sends `REQUESTS` requests spread over all threads and returns the
+/// requests per second.
+fn run(store: &MemoryStore, threads: u32) -> f64 {
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                for i in (thread..REQUESTS).step_by(threads as usize) {
+                    // Scatter consecutive requests over the address range
+                    let client = i.wrapping_mul(2_654_435_761) % CLIENTS;
+                    let client = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + client));
+                    let decision = store
+                        .take(client, "default", POLICY)
+                        .now_or_never()
+                        .expect("memory store never waits")
+                        .expect("memory store never fails");
+                    black_box(decision);
+                }
+            });
+        }
+    });
+    f64::from(REQUESTS) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let parallelism = thread::available_parallelism().map_or(1, |n| n.get() as u32);
+    let mut threads = 1;
+    while threads <= parallelism {
+        let store = MemoryStore::new();
+        let created = run(&store, threads);
+        let updated = run(&store, threads);
+        println!(
+            "{threads:>3} threads: {created:>12.0} new clients/s, {updated:>12.0} requests/s, \
+             {} buckets",
+            store.len()
+        );
+        threads *= 2;
+    }
+}
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS users_username_skeleton_key;
+DROP INDEX IF EXISTS users_username_folded_key;
+
+ALTER TABLE users DROP COLUMN IF EXISTS username_skeleton;
+ALTER TABLE users DROP COLUMN IF EXISTS username_folded;
@@ -0,0 +1,24 @@
+-- Case-folded and confusable-skeleton forms of the username, computed by the
+-- application (see src/username.rs). Existing rows are backfilled with LOWER()
+-- here and with the application's forms after migrating.
+ALTER TABLE users ADD COLUMN username_folded TEXT;
+ALTER TABLE users ADD COLUMN username_skeleton TEXT;
+
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(names, '; ') INTO duplicates FROM (
+        SELECT string_agg(username, ', ' ORDER BY username) AS names
+        FROM users GROUP BY LOWER(username) HAVING COUNT(*) > 1
+    ) d;
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'usernames differing only in case must be renamed first: %', duplicates;
+    END IF;
+END $$;
+
+UPDATE users SET username_folded = LOWER(username);
+ALTER TABLE users ALTER COLUMN username_folded SET NOT NULL;
+
+CREATE UNIQUE INDEX users_username_folded_key ON users (username_folded);
+CREATE UNIQUE INDEX users_username_skeleton_key ON users (username_skeleton);
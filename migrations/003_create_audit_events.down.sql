@@ -0,0 +1,2 @@
+DROP VIEW IF EXISTS audit_event_summary;
+DROP TABLE IF EXISTS audit_events;
@@ -0,0 +1,26 @@
+-- Security-relevant events. The actor is deliberately not a foreign key so
+-- that events survive account deletion and can record unknown usernames.
+CREATE TABLE audit_events (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    event_type TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    actor TEXT,
+    ip_address TEXT,
+    user_agent TEXT
+);
+
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at);
+CREATE INDEX audit_events_actor_idx ON audit_events (actor, created_at);
+
+-- Daily per-event counts for operators querying the database directly
+CREATE VIEW audit_event_summary AS
+SELECT
+    date_trunc('day', created_at) AS day,
+    event_type,
+    outcome,
+    COUNT(*) AS events,
+    COUNT(DISTINCT actor) AS actors,
+    COUNT(DISTINCT ip_address) AS ip_addresses
+FROM audit_events
+GROUP BY 1, 2, 3;
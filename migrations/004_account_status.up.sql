@@ -0,0 +1,9 @@
+ALTER TABLE users ADD COLUMN disabled_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Accounts previously disabled by locking their password hash with a "!" prefix
+UPDATE users
+SET disabled_at = NOW(), password_hash = SUBSTRING(password_hash FROM 2)
+WHERE password_hash LIKE '!%';
+
+CREATE INDEX users_deleted_at_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Users without a row here have the default profile
+CREATE TABLE profiles (
+    username TEXT PRIMARY KEY REFERENCES users(username) ON DELETE CASCADE,
+    display_name TEXT,
+    bio TEXT,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    locale TEXT NOT NULL DEFAULT 'en',
+    avatar TEXT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,6 @@
+ALTER TABLE notes ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', title), 'A') ||
+    setweight(to_tsvector('english', body), 'B')
+) STORED;
+
+CREATE INDEX notes_search_idx ON notes USING GIN (search);
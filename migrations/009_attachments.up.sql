@@ -0,0 +1,19 @@
+-- File contents, stored once per SHA-256 digest and shared by attachments
+CREATE TABLE blobs (
+    digest TEXT PRIMARY KEY,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE attachments (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+    digest TEXT NOT NULL REFERENCES blobs(digest),
+    filename TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX attachments_username_created_at_idx ON attachments (username, created_at DESC, id DESC);
+CREATE INDEX attachments_digest_idx ON attachments (digest);
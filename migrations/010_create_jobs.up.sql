@@ -0,0 +1,21 @@
+CREATE TABLE jobs (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'running', 'done', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Occurrence of a scheduled job, set once per occurrence by any replica
+    scheduled_for TIMESTAMPTZ,
+    locked_by TEXT,
+    locked_at TIMESTAMPTZ,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX jobs_due_idx ON jobs (run_at, id) WHERE status IN ('pending', 'running');
+CREATE UNIQUE INDEX jobs_scheduled_for_idx ON jobs (kind, scheduled_for)
+    WHERE scheduled_for IS NOT NULL;
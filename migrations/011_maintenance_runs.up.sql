@@ -0,0 +1,10 @@
+-- Last run of each maintenance task, written by the instance holding the
+-- task's advisory lock
+CREATE TABLE maintenance_runs (
+    task TEXT PRIMARY KEY,
+    slot BIGINT NOT NULL,
+    instance TEXT NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL,
+    affected BIGINT,
+    error TEXT
+);
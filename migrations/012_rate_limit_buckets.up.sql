@@ -0,0 +1,11 @@
+-- Token buckets of the Postgres rate limit store, shared by all replicas
+CREATE TABLE rate_limit_buckets (
+    client TEXT NOT NULL,
+    name TEXT NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    allowed BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (client, name)
+);
+
+CREATE INDEX rate_limit_buckets_updated_at_idx ON rate_limit_buckets (updated_at);
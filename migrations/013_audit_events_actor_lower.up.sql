@@ -0,0 +1,3 @@
+-- Audit events are filtered by actor case-insensitively, since failed logins
+-- record the username as it was given
+CREATE INDEX audit_events_actor_lower_idx ON audit_events (LOWER(actor), created_at);
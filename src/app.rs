@@ -5,15 +5,22 @@ use leptos_router::{
     components::{Route, Router, Routes},
 };
 
-use crate::pages::{content::ContentPage, login::LoginPage};
+use crate::pages::{content::ContentPage, login::LoginPage, profile::ProfilePage};
+use crate::profile::Profile;
 
 pub fn shell(options: LeptosOptions) -> impl IntoView {
+    #[cfg(feature = "ssr")]
+    let csrf_token = crate::csrf::issued();
+    #[cfg(not(feature = "ssr"))]
+    let csrf_token = Option::<String>::None;
+
     view! {
         <!DOCTYPE html>
         <html lang="en">
             <head>
                 <meta charset="utf-8"/>
                 <meta name="viewport" content="width=device-width, initial-scale=1"/>
+                {csrf_token.map(|token| view! { <meta name=crate::csrf::META content=token/> })}
                 <AutoReload options=options.clone()/>
                 <HydrationScripts options/>
                 <MetaTags/>
@@ -37,116 +44,266 @@ pub fn App() -> impl IntoView {
                 <Routes fallback=|| "Page not found.".into_view()>
                     <Route path=StaticSegment("") view=LoginPage/>
                     <Route path=StaticSegment("content") view=ContentPage/>
+                    <Route path=StaticSegment("profile") view=ProfilePage/>
                 </Routes>
             </main>
         </Router>
     }
 }
 
-#[server]
-pub async fn register(username: String, password: String) -> Result<(), ServerFnError> {
-    use crate::{auth, database};
+/// Error message returned when a disabled user authenticates or uses a session.
+pub const ACCOUNT_DISABLED: &str = "Account disabled";
 
-    if username.is_empty() || password.is_empty() {
-        return Err(ServerFnError::new("Username and password are required"));
-    }
+/// Fails unless the user exists, is not disabled and is not deleted.
+#[cfg(feature = "ssr")]
+async fn ensure_active(username: &str) -> Result<(), ServerFnError> {
+    use crate::database::{self, AccountStatus};
 
-    if username.len() > 64 || password.len() > 128 {
-        return Err(ServerFnError::new("Input too long"));
+    match database::account_status(username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+    {
+        Some(AccountStatus::Active) => Ok(()),
+        Some(AccountStatus::Disabled) => Err(ServerFnError::new(ACCOUNT_DISABLED)),
+        Some(AccountStatus::Deleted) | None => Err(ServerFnError::new("Session not found")),
     }
+}
 
-    if database::user_exists(&username)
+/// Verifies a session token and returns its contents if the session exists
+/// and belongs to an active user.
+#[cfg(feature = "ssr")]
+pub(crate) async fn authenticate(token: &str) -> Result<crate::auth::TokenData, ServerFnError> {
+    use crate::{auth, database};
+
+    let session = auth::decode_token(token).map_err(|e| ServerFnError::new(e.to_string()))?;
+    ensure_active(&session.username).await?;
+
+    if !database::session_exists(token)
         .await
         .map_err(|e| ServerFnError::new(e.to_string()))?
     {
-        return Err(ServerFnError::new("User already exists"));
+        return Err(ServerFnError::new("Session not found"));
     }
 
-    let hash = auth::hash_password(&password).map_err(ServerFnError::new)?;
-    database::create_user(&username, &hash)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    Ok(session)
+}
 
-    Ok(())
+/// Creates an account, optionally joining an organization by accepting an
+/// invitation.
+#[server(endpoint = "register", client = crate::client::Client)]
+pub async fn register(
+    username: String,
+    password: String,
+    invitation: Option<String>,
+) -> Result<(), ServerFnError> {
+    use crate::{
+        audit::{self, EventType, Outcome},
+        auth, database,
+    };
+
+    let ctx = audit::Context::current();
+    let result = async {
+        if password.is_empty() {
+            return Err(ServerFnError::new("Username and password are required"));
+        }
+
+        if password.len() > 128 {
+            return Err(ServerFnError::new("Input too long"));
+        }
+
+        let username =
+            crate::username::validate(&username).map_err(|e| ServerFnError::new(e.to_string()))?;
+
+        if database::user_exists(&username)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+        {
+            return Err(ServerFnError::new("User already exists"));
+        }
+
+        let hash = auth::hash_password(&password).map_err(ServerFnError::new)?;
+        match invitation
+            .as_deref()
+            .map(str::trim)
+            .filter(|i| !i.is_empty())
+        {
+            Some(invitation) => {
+                if !database::create_user_with_invitation(&username, &hash, invitation)
+                    .await
+                    .map_err(|e| ServerFnError::new(e.to_string()))?
+                {
+                    return Err(ServerFnError::new("Invalid invitation"));
+                }
+                Ok(())
+            }
+            None => database::create_user(&username, &hash)
+                .await
+                .map_err(|e| ServerFnError::new(e.to_string())),
+        }
+    }
+    .await;
+
+    let actor = crate::username::candidate(&username);
+    audit::record(
+        EventType::Register,
+        Outcome::of(&result),
+        actor.as_deref(),
+        &ctx,
+    )
+    .await;
+    result
 }
 
-#[server]
+#[server(endpoint = "login", client = crate::client::Client)]
 pub async fn login(username: String, password: String) -> Result<String, ServerFnError> {
-    use crate::{auth, database};
+    use crate::{
+        audit::{self, EventType, Outcome},
+        auth, database,
+    };
 
-    if username.is_empty() || password.is_empty() {
-        return Err(ServerFnError::new("Invalid credentials"));
-    }
+    let ctx = audit::Context::current();
+    let actor = crate::username::candidate(&username);
+    let result = async {
+        let username = actor.as_deref().unwrap_or_default();
+        if username.is_empty() || password.is_empty() {
+            return Err(ServerFnError::new("Invalid credentials"));
+        }
 
-    let hash = database::get_password_hash(&username)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?
-        .ok_or_else(|| ServerFnError::new("Invalid credentials"))?;
+        let (username, hash) = database::get_credentials(username)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+            .ok_or_else(|| ServerFnError::new("Invalid credentials"))?;
 
-    if !auth::verify_password(&password, &hash).map_err(ServerFnError::new)? {
-        return Err(ServerFnError::new("Invalid credentials"));
-    }
+        if !auth::verify_password(&password, &hash).map_err(ServerFnError::new)? {
+            return Err(ServerFnError::new("Invalid credentials"));
+        }
 
-    let token = auth::create_token(&username).map_err(|e| ServerFnError::new(e.to_string()))?;
-    let expires_at = auth::token_expiry();
-    database::create_session(&token, &username, expires_at)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?;
+        // Only reveal that an account is disabled to someone knowing its password
+        ensure_active(&username).await?;
+
+        let token = auth::create_token(&username).map_err(|e| ServerFnError::new(e.to_string()))?;
+        let expires_at = auth::token_expiry();
+        database::create_session(&token, &username, expires_at)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        crate::csrf::rotate(Some(&token));
+
+        Ok(token)
+    }
+    .await;
 
-    Ok(token)
+    audit::record(
+        EventType::Login,
+        Outcome::of(&result),
+        actor.as_deref(),
+        &ctx,
+    )
+    .await;
+    result
 }
 
-#[server]
+#[server(endpoint = "renew_session", client = crate::client::Client)]
 pub async fn renew_session(token: String) -> Result<String, ServerFnError> {
-    use crate::{auth, database};
+    use crate::{
+        audit::{self, EventType, Outcome},
+        auth, database,
+    };
 
-    let username = auth::verify_token(&token).map_err(|e| ServerFnError::new(e.to_string()))?;
+    let ctx = audit::Context::current();
+    let actor = auth::verify_token(&token).ok();
+    let result = async {
+        let session = authenticate(&token).await?;
 
-    if !database::session_exists(&token)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?
-    {
-        return Err(ServerFnError::new("Session not found"));
+        // Drop the active organization if the membership has been revoked
+        let organization = crate::organizations::current(&session)
+            .await?
+            .map(|(id, _)| id);
+        let new_token = auth::renew_token(&session, organization)
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        let expires_at = auth::token_expiry();
+        database::update_session(&token, &new_token, expires_at)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+
+        Ok(new_token)
     }
+    .await;
 
-    let new_token = auth::create_token(&username).map_err(|e| ServerFnError::new(e.to_string()))?;
-    let expires_at = auth::token_expiry();
-    database::update_session(&token, &new_token, expires_at)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    audit::record(
+        EventType::RenewSession,
+        Outcome::of(&result),
+        actor.as_deref(),
+        &ctx,
+    )
+    .await;
+    result
+}
 
-    Ok(new_token)
+#[server(endpoint = "whoami", client = crate::client::Client)]
+pub async fn whoami(token: String) -> Result<Profile, ServerFnError> {
+    let username = authenticate(&token).await?.username;
+    crate::profile::load(&username).await
 }
 
-#[server]
-pub async fn whoami(token: String) -> Result<String, ServerFnError> {
-    use crate::{auth, database};
+#[server(endpoint = "logout", client = crate::client::Client)]
+pub async fn logout(token: String) -> Result<(), ServerFnError> {
+    use crate::{
+        audit::{self, EventType, Outcome},
+        auth, database,
+    };
 
-    let username = auth::verify_token(&token).map_err(|e| ServerFnError::new(e.to_string()))?;
+    let ctx = audit::Context::current();
+    let actor = auth::verify_token(&token).ok();
+    let result = async {
+        // Verify the token is valid before attempting deletion
+        auth::verify_token(&token).map_err(|e| ServerFnError::new(e.to_string()))?;
 
-    if !database::session_exists(&token)
-        .await
-        .map_err(|e| ServerFnError::new(e.to_string()))?
-    {
-        return Err(ServerFnError::new("Session not found"));
+        if !database::delete_session(&token)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+        {
+            return Err(ServerFnError::new("Session not found"));
+        }
+        crate::csrf::rotate(None);
+
+        Ok(())
     }
+    .await;
 
-    Ok(username)
+    audit::record(
+        EventType::Logout,
+        Outcome::of(&result),
+        actor.as_deref(),
+        &ctx,
+    )
+    .await;
+    result
 }
 
-#[server]
-pub async fn logout(token: String) -> Result<(), ServerFnError> {
-    use crate::{auth, database};
-
-    // Verify the token is valid before attempting deletion
-    auth::verify_token(&token).map_err(|e| ServerFnError::new(e.to_string()))?;
+/// Returns a JSON export of everything stored about the current user.
+#[server(endpoint = "export_account", client = crate::client::Client)]
+pub async fn export_account(token: String) -> Result<String, ServerFnError> {
+    use crate::{
+        audit::{self, EventType, Outcome},
+        export,
+    };
 
-    if !database::delete_session(&token)
+    let ctx = audit::Context::current();
+    let username = authenticate(&token).await?.username;
+    let result = export::collect(&username)
         .await
         .map_err(|e| ServerFnError::new(e.to_string()))?
-    {
-        return Err(ServerFnError::new("Session not found"));
-    }
+        .ok_or_else(|| ServerFnError::new("Session not found"))
+        .and_then(|export| {
+            serde_json::to_string_pretty(&export).map_err(|e| ServerFnError::new(e.to_string()))
+        });
 
-    Ok(())
+    audit::record(
+        EventType::DataExport,
+        Outcome::of(&result),
+        Some(&username),
+        &ctx,
+    )
+    .await;
+    result
 }
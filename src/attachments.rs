@@ -0,0 +1,126 @@
+//! File attachments of the current user.
+//!
+//! Files are uploaded and downloaded through plain HTTP routes (see [`http`])
+//! so that they are streamed instead of being buffered in memory. Their
+//! contents are kept in the content-addressed [`ContentStore`], which stores
+//! identical files only once. Every user has a quota on the total size of
+//! their attachments.
+//!
+//! [`ContentStore`]: crate::storage::ContentStore
+
+use chrono::{DateTime, Utc};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "ssr")]
+pub mod http;
+
+/// Maximum size of a single uploaded file in bytes.
+pub const MAX_FILE_BYTES: u64 = 25 * 1024 * 1024;
+
+#[cfg(feature = "ssr")]
+const DEFAULT_QUOTA_BYTES: i64 = 100 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct Attachment {
+    pub id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Attachment {
+    pub fn url(&self) -> String {
+        format!("/attachments/{}", self.id)
+    }
+}
+
+/// The attachments of a user, newest first, and their quota usage in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachments {
+    pub attachments: Vec<Attachment>,
+    pub used: i64,
+    pub quota: i64,
+}
+
+/// Formats a size in bytes for humans, e.g. `1.5 MiB`.
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Reads `ATTACHMENT_QUOTA_BYTES`, the total size of attachments allowed per
+/// user, on first use. Invalid values are logged and replaced by the default.
+#[cfg(feature = "ssr")]
+pub fn quota() -> i64 {
+    static QUOTA: std::sync::OnceLock<i64> = std::sync::OnceLock::new();
+    *QUOTA.get_or_init(|| {
+        let Ok(v) = std::env::var("ATTACHMENT_QUOTA_BYTES") else {
+            return DEFAULT_QUOTA_BYTES;
+        };
+        v.parse().ok().filter(|bytes| *bytes >= 0).unwrap_or_else(|| {
+            tracing::warn!(
+                "ignoring invalid ATTACHMENT_QUOTA_BYTES {v}, allowing {DEFAULT_QUOTA_BYTES} bytes"
+            );
+            DEFAULT_QUOTA_BYTES
+        })
+    })
+}
+
+#[server(endpoint = "list_attachments", client = crate::client::Client)]
+pub async fn list_attachments(token: String) -> Result<Attachments, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    let attachments = database::list_attachments(&username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    let used = attachments.iter().map(|a| a.size).sum();
+    Ok(Attachments {
+        attachments,
+        used,
+        quota: quota(),
+    })
+}
+
+/// Deletes an attachment of the user. Its contents are removed from storage
+/// once no attachment refers to them anymore.
+#[server(endpoint = "delete_attachment", client = crate::client::Client)]
+pub async fn delete_attachment(token: String, id: i64) -> Result<(), ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    if database::delete_attachment(id, &username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+    {
+        Ok(())
+    } else {
+        Err(ServerFnError::new("Attachment not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(25 * 1024 * 1024), "25.0 MiB");
+        assert_eq!(format_size(i64::MAX), "8388608.0 TiB");
+    }
+}
@@ -0,0 +1,768 @@
+//! Streaming upload and download routes for attachments.
+//!
+//! Both routes authenticate with the session token, either sent as a bearer
+//! token or as the `session_token` cookie.
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Multipart, Path, multipart::MultipartError},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::{fmt, io::SeekFrom};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::{Attachment, MAX_FILE_BYTES, format_size, quota};
+use crate::{
+    app::authenticate,
+    database,
+    storage::{self, BlobWriter, ContentStore},
+};
+
+/// Name of the multipart field holding the file.
+pub const FIELD: &str = "file";
+
+/// Allowance for multipart boundaries and headers on top of the file size.
+pub const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
+/// Bytes inspected to determine the type of an upload.
+const SNIFF_LEN: usize = 512;
+
+const MAX_FILENAME_LENGTH: usize = 255;
+
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+];
+
+const TEXT: &str = "text/plain; charset=utf-8";
+
+#[derive(Debug)]
+pub enum UploadError {
+    Unauthorized,
+    MissingFile,
+    EmptyFile,
+    TooLarge,
+    QuotaExceeded,
+    UnsupportedType,
+    Malformed(String),
+    Internal(String),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => f.write_str("Not authenticated"),
+            Self::MissingFile => write!(f, "Missing multipart field {FIELD}"),
+            Self::EmptyFile => f.write_str("File is empty"),
+            Self::TooLarge => write!(
+                f,
+                "File must be at most {}",
+                format_size(MAX_FILE_BYTES as i64)
+            ),
+            Self::QuotaExceeded => write!(
+                f,
+                "Storage quota of {} exceeded",
+                format_size(quota())
+            ),
+            Self::UnsupportedType => f.write_str(
+                "File must be an image (GIF, JPEG, PNG or WebP), a PDF, a ZIP archive or plain text",
+            ),
+            Self::Malformed(e) => write!(f, "Malformed upload: {e}"),
+            Self::Internal(_) => f.write_str("Internal error"),
+        }
+    }
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::MissingFile | Self::EmptyFile | Self::Malformed(_) => StatusCode::BAD_REQUEST,
+            Self::TooLarge | Self::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Internal(e) => {
+                tracing::error!("Failed to store attachment: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+impl From<MultipartError> for UploadError {
+    fn from(e: MultipartError) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            Self::TooLarge
+        } else {
+            Self::Malformed(e.body_text())
+        }
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Internal(e.to_string())
+    }
+}
+
+impl From<sqlx::Error> for UploadError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(e.to_string())
+    }
+}
+
+/// Returns the session token of a request, preferring the `Authorization`
+/// header over the cookie.
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer.or_else(|| {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .find_map(|c| c.trim().strip_prefix("session_token="))
+    })
+}
+
+async fn username(headers: &HeaderMap) -> Option<String> {
+    let token = session_token(headers)?;
+    authenticate(token).await.ok().map(|t| t.username)
+}
+
+/// Determines the content type of an upload from its first bytes, ignoring
+/// the type claimed by the client. `complete` tells whether `head` holds the
+/// whole file. Markup is rejected, as browsers may render it as a document.
+fn sniff(head: &[u8], complete: bool) -> Option<&'static str> {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return Some(content_type);
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // Allow a multi-byte character to be cut off at the end of the head
+    let utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => !complete && e.error_len().is_none(),
+    };
+    let markup = head
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|&b| b == b'<');
+    let binary = head
+        .iter()
+        .any(|&b| (b < 0x20 && !b"\t\n\x0c\r\x1b".contains(&b)) || b == 0x7f);
+    (utf8 && !markup && !binary).then_some(TEXT)
+}
+
+/// Strips directories and control characters from a client supplied file
+/// name.
+fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let clean: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    match clean.trim() {
+        "" | "." | ".." => "file".into(),
+        trimmed => trimmed.into(),
+    }
+}
+
+/// Formats a `Content-Disposition` header forcing a download, with an ASCII
+/// fallback and the UTF-8 file name as of RFC 6266.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(b as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// The part of a file requested by a `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a file of the given size. Only single byte
+/// ranges are supported; other or malformed headers are ignored and the full
+/// file is served, as RFC 9110 allows.
+fn parse_range(value: &str, size: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    if first.is_empty() {
+        // Suffix range of the last bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(size.saturating_sub(n), size - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(first) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let last = match last {
+        "" => None,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= first => Some(last),
+            _ => return ByteRange::Full,
+        },
+    };
+    if first >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last.map_or(size - 1, |l| l.min(size - 1)))
+}
+
+/// Handles `POST /attachments`, a `multipart/form-data` upload with the file
+/// in the [`FIELD`] field.
+pub async fn upload(headers: HeaderMap, multipart: Multipart) -> Response {
+    receive(storage::content_store(), &headers, multipart)
+        .await
+        .map(|attachment| (StatusCode::CREATED, Json(attachment)))
+        .into_response()
+}
+
+/// Streams the file of a multipart upload into the store and records it as
+/// an attachment of the authenticated user.
+pub async fn receive(
+    store: &ContentStore,
+    headers: &HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Attachment, UploadError> {
+    let username = username(headers).await.ok_or(UploadError::Unauthorized)?;
+    let quota = quota();
+    let remaining = quota - database::attachment_usage(&username).await?;
+    let limit = MAX_FILE_BYTES.min(u64::try_from(remaining).unwrap_or(0));
+    let over_limit = || {
+        if limit < MAX_FILE_BYTES {
+            UploadError::QuotaExceeded
+        } else {
+            UploadError::TooLarge
+        }
+    };
+
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() != Some(FIELD) {
+            continue;
+        }
+        let filename = sanitize_filename(field.file_name().unwrap_or_default());
+
+        // The first bytes are held back until the type is known, everything
+        // after them is written to the store as it arrives
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut writer: Option<(BlobWriter, &str)> = None;
+        let mut received = 0u64;
+        while let Some(chunk) = field.chunk().await? {
+            received += chunk.len() as u64;
+            if received > limit {
+                return Err(over_limit());
+            }
+            match writer.as_mut() {
+                Some((writer, _)) => writer.write(&chunk).await?,
+                None => {
+                    head.extend_from_slice(&chunk);
+                    if head.len() >= SNIFF_LEN {
+                        writer = Some(start(store, &head, false).await?);
+                    }
+                }
+            }
+        }
+        let (writer, content_type) = match writer {
+            Some(writer) => writer,
+            None => start(store, &head, true).await?,
+        };
+
+        let blob = writer.finish().await?;
+        if blob.size == 0 {
+            return Err(UploadError::EmptyFile);
+        }
+        let (digest, size) = (blob.digest.clone(), blob.size as i64);
+        return database::create_attachment(
+            &username,
+            &filename,
+            content_type,
+            &digest,
+            size,
+            quota,
+            store.commit(blob),
+        )
+        .await?
+        .ok_or(UploadError::QuotaExceeded);
+    }
+    Err(UploadError::MissingFile)
+}
+
+/// Determines the type of an upload and starts writing it.
+async fn start(
+    store: &ContentStore,
+    head: &[u8],
+    complete: bool,
+) -> Result<(BlobWriter, &'static str), UploadError> {
+    let content_type = sniff(head, complete).ok_or(UploadError::UnsupportedType)?;
+    let mut writer = store.create().await?;
+    writer.write(head).await?;
+    Ok((writer, content_type))
+}
+
+/// Handles `GET /attachments/{id}`. Only the owner of an attachment may
+/// download it; for everybody else it does not exist.
+pub async fn download(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    send(storage::content_store(), &headers, id).await
+}
+
+pub async fn send(store: &ContentStore, headers: &HeaderMap, id: i64) -> Response {
+    let Some(username) = username(headers).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let stored = match database::get_attachment(id, &username).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("Failed to load attachment {id}: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let mut file = match store.open(&stored.digest).await {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            tracing::error!("Contents of attachment {id} are missing");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(e) => {
+            tracing::error!("Failed to open attachment {id}: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let attachment = stored.attachment;
+    let size = attachment.size as u64;
+    // Contents never change for a digest, so it makes a strong validator
+    let etag = format!("\"{}\"", stored.digest);
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+
+    let mut response = if header(header::IF_NONE_MATCH)
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+    {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let range = match header(header::RANGE) {
+            Some(_) if header(header::IF_RANGE).is_some_and(|v| v != etag) => ByteRange::Full,
+            Some(range) => parse_range(range, size),
+            None => ByteRange::Full,
+        };
+        match range {
+            ByteRange::Full => (
+                [(header::CONTENT_LENGTH, size.to_string())],
+                Body::from_stream(ReaderStream::new(file)),
+            )
+                .into_response(),
+            ByteRange::Partial(first, last) => {
+                if let Err(e) = file.seek(SeekFrom::Start(first)).await {
+                    tracing::error!("Failed to seek in attachment {id}: {e}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                let len = last - first + 1;
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [
+                        (header::CONTENT_LENGTH, len.to_string()),
+                        (
+                            header::CONTENT_RANGE,
+                            format!("bytes {first}-{last}/{size}"),
+                        ),
+                    ],
+                    Body::from_stream(ReaderStream::new(file.take(len))),
+                )
+                    .into_response()
+            }
+            ByteRange::Unsatisfiable => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{size}"))],
+            )
+                .into_response(),
+        }
+    };
+
+    let values = [
+        (header::CONTENT_TYPE, attachment.content_type),
+        (
+            header::CONTENT_DISPOSITION,
+            content_disposition(&attachment.filename),
+        ),
+        (header::ACCEPT_RANGES, "bytes".into()),
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "private, no-cache".into()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".into()),
+        (
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; sandbox".into(),
+        ),
+    ];
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth, testing};
+    use axum::{
+        Router,
+        http::Request,
+        routing::{get, post},
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn app(store: &'static ContentStore) -> Router {
+        Router::new()
+            .route(
+                "/attachments",
+                post(move |headers: HeaderMap, multipart: Multipart| async move {
+                    receive(store, &headers, multipart)
+                        .await
+                        .map(|attachment| (StatusCode::CREATED, Json(attachment)))
+                        .into_response()
+                }),
+            )
+            .route(
+                "/attachments/{id}",
+                get(move |headers: HeaderMap, Path(id): Path<i64>| async move {
+                    send(store, &headers, id).await
+                }),
+            )
+    }
+
+    async fn login(username: &str) -> String {
+        database::create_user(username, "$argon2id$test")
+            .await
+            .unwrap();
+        let token = auth::create_token(username).unwrap();
+        database::create_session(&token, username, auth::token_expiry())
+            .await
+            .unwrap();
+        token
+    }
+
+    fn upload_request(token: &str, contents: &[u8]) -> Request<Body> {
+        let mut body = b"--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"../hello.txt\"\r\n\
+            Content-Type: text/html\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n--BOUNDARY--\r\n");
+        Request::post("/attachments")
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=BOUNDARY",
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn download_request(token: &str, id: i64, range: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(format!("/attachments/{id}"))
+            .header(header::COOKIE, format!("session_token={token}"));
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn upload_and_download() {
+        testing::isolated(async {
+            let root =
+                std::env::temp_dir().join(format!("webapp-{}", uuid::Uuid::new_v4().simple()));
+            let store: &'static ContentStore = Box::leak(Box::new(ContentStore::new(&root)));
+            let app = app(store);
+            let alice = login("alice").await;
+            let bob = login("bob").await;
+
+            // The claimed content type and directories are ignored
+            let response = app
+                .clone()
+                .oneshot(upload_request(&alice, b"hello world"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let attachment: Attachment = serde_json::from_slice(&body(response).await).unwrap();
+            assert_eq!(attachment.filename, "hello.txt");
+            assert_eq!(attachment.content_type, TEXT);
+            assert_eq!(attachment.size, 11);
+
+            // Identical contents are stored once
+            let response = app
+                .clone()
+                .oneshot(upload_request(&bob, b"hello world"))
+                .await
+                .unwrap();
+            let copy: Attachment = serde_json::from_slice(&body(response).await).unwrap();
+            let (blobs,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blobs")
+                .fetch_one(&database::pool())
+                .await
+                .unwrap();
+            assert_eq!(blobs, 1);
+
+            let response = app
+                .clone()
+                .oneshot(upload_request(&alice, b"<html><script>"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            let response = app
+                .clone()
+                .oneshot(upload_request("invalid", b"hello"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            let response = app
+                .clone()
+                .oneshot(download_request(&alice, attachment.id, None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], TEXT);
+            assert_eq!(
+                response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+                "nosniff"
+            );
+            assert!(
+                response.headers()[header::CONTENT_DISPOSITION]
+                    .to_str()
+                    .unwrap()
+                    .starts_with("attachment;")
+            );
+            let etag = response.headers()[header::ETAG].clone();
+            assert_eq!(body(response).await, b"hello world");
+
+            let response = app
+                .clone()
+                .oneshot(download_request(&alice, attachment.id, Some("bytes=6-")))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 6-10/11");
+            assert_eq!(body(response).await, b"world");
+
+            let response = app
+                .clone()
+                .oneshot(download_request(&alice, attachment.id, Some("bytes=11-")))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */11");
+
+            let mut request = download_request(&alice, attachment.id, Some("bytes=0-4"));
+            request
+                .headers_mut()
+                .insert(header::IF_RANGE, HeaderValue::from_static("\"stale\""));
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let mut request = download_request(&alice, attachment.id, None);
+            request.headers_mut().insert(header::IF_NONE_MATCH, etag);
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+            // Attachments of other users do not exist
+            let response = app
+                .clone()
+                .oneshot(download_request(&bob, attachment.id, None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            // Contents are removed once no attachment refers to them
+            assert!(
+                database::delete_attachment(attachment.id, "alice")
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(database::purge_orphaned_blobs(store).await.unwrap(), 0);
+            assert!(database::delete_attachment(copy.id, "bob").await.unwrap());
+            assert_eq!(database::purge_orphaned_blobs(store).await.unwrap(), 1);
+            let digest = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+            assert!(store.open(digest).await.unwrap().is_none());
+
+            std::fs::remove_dir_all(root).unwrap();
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn quota_is_enforced() {
+        testing::isolated(async {
+            database::create_user("carol", "$argon2id$test")
+                .await
+                .unwrap();
+            let create = |digest: &'static str, size| {
+                database::create_attachment("carol", "a.txt", TEXT, digest, size, 100, async {
+                    Ok(())
+                })
+            };
+            let (a, b) = (&*"a".repeat(64).leak(), &*"b".repeat(64).leak());
+            assert!(create(a, 60).await.unwrap().is_some());
+            assert!(create(b, 50).await.unwrap().is_none());
+            assert!(create(b, 40).await.unwrap().is_some());
+            assert_eq!(database::attachment_usage("carol").await.unwrap(), 100);
+        })
+        .await;
+    }
+
+    #[test]
+    fn sniffs_content_types() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n....", true), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0", true), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a", true), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 ", true), Some("image/webp"));
+        assert_eq!(sniff(b"%PDF-1.7", true), Some("application/pdf"));
+        assert_eq!(sniff(b"PK\x03\x04", true), Some("application/zip"));
+        assert_eq!(sniff("Grüße\n".as_bytes(), true), Some(TEXT));
+
+        // A character cut off by the sniffing window is fine, unless the
+        // file ends there
+        let cut = &"ü".as_bytes()[..1];
+        assert_eq!(sniff(cut, false), Some(TEXT));
+        assert_eq!(sniff(cut, true), None);
+
+        assert_eq!(sniff(b"  <!DOCTYPE html><script>", true), None);
+        assert_eq!(
+            sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>", true),
+            None
+        );
+        assert_eq!(sniff(b"MZ\x90\0\x03\0", true), None);
+        assert_eq!(sniff(b"\x7fELF\x02\x01", true), None);
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        assert_eq!(sanitize_filename("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\photo.png"), "photo.png");
+        assert_eq!(sanitize_filename("a\r\nb.txt"), "ab.txt");
+        assert_eq!(sanitize_filename(" .. "), "file");
+        assert_eq!(sanitize_filename(""), "file");
+        assert_eq!(
+            sanitize_filename(&"x".repeat(300)).len(),
+            MAX_FILENAME_LENGTH
+        );
+    }
+
+    #[test]
+    fn content_disposition_encodes_names() {
+        assert_eq!(
+            content_disposition("notes.txt"),
+            "attachment; filename=\"notes.txt\"; filename*=UTF-8''notes.txt"
+        );
+        assert_eq!(
+            content_disposition("über \"x\".txt"),
+            "attachment; filename=\"_ber _x_.txt\"; \
+             filename*=UTF-8''%C3%BCber%20%22x%22.txt"
+        );
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            ByteRange::Partial(500, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Partial(0, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), ByteRange::Unsatisfiable);
+
+        // Ignored, the full file is sent
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), ByteRange::Full);
+        assert_eq!(parse_range("lines=1-2", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn session_token_from_header_or_cookie() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(session_token(&headers), None);
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; session_token=abc"),
+        );
+        assert_eq!(session_token(&headers), Some("abc"));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer xyz"),
+        );
+        assert_eq!(session_token(&headers), Some("xyz"));
+    }
+}
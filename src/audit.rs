@@ -0,0 +1,248 @@
+use axum::http::{HeaderMap, header, request::Parts};
+use chrono::{DateTime, Duration, Utc};
+use leptos::prelude::use_context;
+use serde::Serialize;
+use std::{env, fmt, net::IpAddr, str::FromStr, sync::OnceLock};
+
+use crate::{client_ip::ClientIp, database};
+
+const DEFAULT_RETENTION_DAYS: i64 = 90;
+
+/// Longer user agents are truncated, they are supplied by the client.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    Register,
+    Login,
+    RenewSession,
+    Logout,
+    RateLimited,
+    DataExport,
+    CreateOrganization,
+    CreateInvitation,
+    AcceptInvitation,
+}
+
+impl EventType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Register => "register",
+            Self::Login => "login",
+            Self::RenewSession => "renew_session",
+            Self::Logout => "logout",
+            Self::RateLimited => "rate_limited",
+            Self::DataExport => "data_export",
+            Self::CreateOrganization => "create_organization",
+            Self::CreateInvitation => "create_invitation",
+            Self::AcceptInvitation => "accept_invitation",
+        }
+    }
+}
+
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "register" => Ok(Self::Register),
+            "login" => Ok(Self::Login),
+            "renew_session" => Ok(Self::RenewSession),
+            "logout" => Ok(Self::Logout),
+            "rate_limited" => Ok(Self::RateLimited),
+            "data_export" => Ok(Self::DataExport),
+            "create_organization" => Ok(Self::CreateOrganization),
+            "create_invitation" => Ok(Self::CreateInvitation),
+            "accept_invitation" => Ok(Self::AcceptInvitation),
+            other => Err(format!("unknown audit event type: {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub fn of<T, E>(result: &Result<T, E>) -> Self {
+        if result.is_ok() {
+            Self::Success
+        } else {
+            Self::Failure
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+/// Request metadata attached to every audit event.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl Context {
+    pub fn new(ip: Option<IpAddr>, headers: &HeaderMap) -> Self {
+        Self {
+            ip,
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        }
+    }
+
+    /// Extracts the context of the request currently handled by a server
+    /// function, or an empty context outside of one.
+    pub fn current() -> Self {
+        use_context::<Parts>()
+            .map(|parts| {
+                let ip = parts.extensions.get().map(|ClientIp(ip)| *ip);
+                Self::new(ip, &parts.headers)
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub event_type: String,
+    pub outcome: String,
+    pub actor: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Events of a type and outcome on a day, from the `audit_event_summary`
+/// view.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Summary {
+    pub day: DateTime<Utc>,
+    pub event_type: String,
+    pub outcome: String,
+    pub events: i64,
+    pub actors: i64,
+    pub ip_addresses: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub actor: Option<String>,
+    pub event_type: Option<EventType>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            actor: None,
+            event_type: None,
+            since: None,
+            limit: 100,
+        }
+    }
+}
+
+/// Records an audit event. Failures are logged rather than propagated so that
+/// auditing never breaks the audited operation.
+pub async fn record(event: EventType, outcome: Outcome, actor: Option<&str>, ctx: &Context) {
+    if !database::is_initialized() {
+        return;
+    }
+    if let Err(e) = database::insert_audit_event(event, outcome, actor, ctx).await {
+        tracing::warn!("failed to record {event} audit event: {e}");
+    }
+}
+
+/// Reads `AUDIT_RETENTION_DAYS` on first use, warning about invalid values.
+pub fn retention() -> Duration {
+    static DAYS: OnceLock<i64> = OnceLock::new();
+    let days = *DAYS.get_or_init(|| {
+        let Ok(v) = env::var("AUDIT_RETENTION_DAYS") else {
+            return DEFAULT_RETENTION_DAYS;
+        };
+        v.parse().ok().filter(|days| *days >= 0).unwrap_or_else(|| {
+            tracing::warn!(
+                "ignoring invalid AUDIT_RETENTION_DAYS {v}, keeping events for \
+                 {DEFAULT_RETENTION_DAYS} days"
+            );
+            DEFAULT_RETENTION_DAYS
+        })
+    });
+    Duration::days(days)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_types_round_trip() {
+        for event in [
+            EventType::Register,
+            EventType::Login,
+            EventType::RenewSession,
+            EventType::Logout,
+            EventType::RateLimited,
+            EventType::DataExport,
+            EventType::CreateOrganization,
+            EventType::CreateInvitation,
+            EventType::AcceptInvitation,
+        ] {
+            assert_eq!(event.as_str().parse(), Ok(event));
+        }
+        assert!("signup".parse::<EventType>().is_err());
+    }
+
+    #[test]
+    fn user_agents_are_truncated() {
+        let mut headers = HeaderMap::new();
+        let agent = "a".repeat(MAX_USER_AGENT_LENGTH + 1);
+        headers.insert(header::USER_AGENT, agent.parse().unwrap());
+        let ctx = Context::new(None, &headers);
+        assert_eq!(ctx.user_agent, Some(agent[1..].to_owned()));
+    }
+
+    #[test]
+    fn outcome_of_result() {
+        assert_eq!(Outcome::of(&Ok::<_, ()>(())), Outcome::Success);
+        assert_eq!(Outcome::of(&Err::<(), _>(())), Outcome::Failure);
+    }
+
+    #[test]
+    fn context_of_current_request() {
+        let (mut parts, ()) = axum::http::Request::get("/api/login")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header(header::USER_AGENT, "test-agent")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+            .extensions
+            .insert(ClientIp(IpAddr::from([192, 168, 1, 1])));
+
+        let owner = leptos::prelude::Owner::new();
+        owner.set();
+        leptos::prelude::provide_context(parts);
+        let ctx = Context::current();
+        assert_eq!(ctx.ip, Some(IpAddr::from([192, 168, 1, 1])));
+        assert_eq!(ctx.user_agent.as_deref(), Some("test-agent"));
+    }
+}
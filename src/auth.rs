@@ -13,6 +13,28 @@ struct Claims {
     exp: i64,
     iat: i64,
     jti: String,
+    /// Active organization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    org: Option<i64>,
+    /// Session, kept when the token is renewed. Tokens issued before it
+    /// existed use their ID instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<String>,
+}
+
+impl Claims {
+    fn session(&self) -> String {
+        self.sid.clone().unwrap_or_else(|| self.jti.clone())
+    }
+}
+
+/// Verified contents of a session token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenData {
+    pub username: String,
+    pub organization: Option<i64>,
+    /// Identifies the session across renewals of its token.
+    pub session: String,
 }
 
 fn secret() -> Vec<u8> {
@@ -38,12 +60,40 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, String> {
 }
 
 pub fn create_token(username: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    create_token_for(username, None)
+}
+
+/// Creates a token for a new session with the given organization as active
+/// organization.
+pub fn create_token_for(
+    username: &str,
+    organization: Option<i64>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_token(username, organization, uuid::Uuid::new_v4().to_string())
+}
+
+/// Creates the next token of a session, with the given organization as
+/// active organization.
+pub fn renew_token(
+    session: &TokenData,
+    organization: Option<i64>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_token(&session.username, organization, session.session.clone())
+}
+
+fn encode_token(
+    username: &str,
+    organization: Option<i64>,
+    session: String,
+) -> Result<String, jsonwebtoken::errors::Error> {
     let now = Utc::now();
     let claims = Claims {
         sub: username.to_owned(),
         exp: (now + Duration::hours(1)).timestamp(),
         iat: now.timestamp(),
         jti: uuid::Uuid::new_v4().to_string(),
+        org: organization,
+        sid: Some(session),
     };
     encode(
         &Header::default(),
@@ -57,12 +107,29 @@ pub fn token_expiry() -> chrono::DateTime<Utc> {
 }
 
 pub fn verify_token(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    decode_token(token).map(|data| data.username)
+}
+
+pub fn decode_token(token: &str) -> Result<TokenData, jsonwebtoken::errors::Error> {
     let data = decode::<Claims>(
         token,
         &DecodingKey::from_secret(&secret()),
         &Validation::default(),
     )?;
-    Ok(data.claims.sub)
+    Ok(TokenData {
+        session: data.claims.session(),
+        username: data.claims.sub,
+        organization: data.claims.org,
+    })
+}
+
+/// The session of a token signed by us, even if it has expired.
+pub fn session_id(token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+    decode::<Claims>(token, &DecodingKey::from_secret(&secret()), &validation)
+        .ok()
+        .map(|data| data.claims.session())
 }
 
 #[cfg(test)]
@@ -76,6 +143,33 @@ mod tests {
         assert_eq!(username, "testuser");
     }
 
+    #[test]
+    fn token_carries_active_organization() {
+        let token = create_token("testuser").unwrap();
+        assert_eq!(decode_token(&token).unwrap().organization, None);
+
+        let token = create_token_for("testuser", Some(7)).unwrap();
+        let data = decode_token(&token).unwrap();
+        assert_eq!(data.username, "testuser");
+        assert_eq!(data.organization, Some(7));
+    }
+
+    #[test]
+    fn renewed_tokens_keep_their_session() {
+        let first = decode_token(&create_token("testuser").unwrap()).unwrap();
+        let renewed = decode_token(&renew_token(&first, Some(3)).unwrap()).unwrap();
+        assert_eq!(renewed.session, first.session);
+        assert_eq!(renewed.organization, Some(3));
+        assert_eq!(
+            session_id(&renew_token(&first, None).unwrap()),
+            Some(first.session.clone())
+        );
+
+        let other = decode_token(&create_token("testuser").unwrap()).unwrap();
+        assert_ne!(other.session, first.session);
+        assert_eq!(session_id("invalid-token"), None);
+    }
+
     #[test]
     fn verify_invalid_token_fails() {
         assert!(verify_token("invalid-token").is_err());
@@ -87,7 +181,9 @@ mod tests {
             sub: "testuser".to_owned(),
             exp: (Utc::now() - Duration::hours(1)).timestamp(),
             iat: (Utc::now() - Duration::hours(2)).timestamp(),
-            jti: uuid::Uuid::new_v4().to_string(),
+            jti: "expired".to_owned(),
+            org: None,
+            sid: None,
         };
         let token = encode(
             &Header::default(),
@@ -96,6 +192,8 @@ mod tests {
         )
         .unwrap();
         assert!(verify_token(&token).is_err());
+        // Tokens without a session ID use their own
+        assert_eq!(session_id(&token).as_deref(), Some("expired"));
     }
 
     #[test]
@@ -0,0 +1,160 @@
+//! Avatar image validation, resizing and serving.
+
+use axum::{
+    extract::Path,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use image::{ImageFormat, ImageReader, imageops::FilterType};
+use std::{fmt, io::Cursor};
+
+use crate::storage;
+
+/// Maximum size of an uploaded avatar in bytes.
+pub const MAX_UPLOAD_BYTES: usize = 5 * 1024 * 1024;
+
+/// Width and height of stored avatars in pixels.
+pub const SIZE: u32 = 256;
+
+/// Uploads with larger dimensions are rejected before being decoded.
+const MAX_DIMENSION: u32 = 4096;
+
+const ALLOWED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Gif,
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvatarError {
+    TooLarge,
+    UnsupportedFormat,
+    DimensionsTooLarge,
+    Invalid(String),
+}
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge => write!(f, "Image must be at most {MAX_UPLOAD_BYTES} bytes"),
+            Self::UnsupportedFormat => f.write_str("Image must be a GIF, JPEG, PNG or WebP"),
+            Self::DimensionsTooLarge => write!(
+                f,
+                "Image must be at most {MAX_DIMENSION}x{MAX_DIMENSION} pixels"
+            ),
+            Self::Invalid(e) => write!(f, "Invalid image: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AvatarError {}
+
+/// Validates an uploaded image by its contents, regardless of the claimed
+/// content type, and returns it cropped and resized to a [`SIZE`] pixel PNG.
+pub fn process(data: &[u8]) -> Result<Vec<u8>, AvatarError> {
+    if data.len() > MAX_UPLOAD_BYTES {
+        return Err(AvatarError::TooLarge);
+    }
+
+    let format = image::guess_format(data).map_err(|_| AvatarError::UnsupportedFormat)?;
+    if !ALLOWED_FORMATS.contains(&format) {
+        return Err(AvatarError::UnsupportedFormat);
+    }
+
+    let reader = || ImageReader::with_format(Cursor::new(data), format);
+    let (width, height) = reader()
+        .into_dimensions()
+        .map_err(|e| AvatarError::Invalid(e.to_string()))?;
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(AvatarError::DimensionsTooLarge);
+    }
+
+    let image = reader()
+        .decode()
+        .map_err(|e| AvatarError::Invalid(e.to_string()))?
+        .resize_to_fill(SIZE, SIZE, FilterType::Lanczos3);
+
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| AvatarError::Invalid(e.to_string()))?;
+    Ok(png)
+}
+
+/// Storage key of an avatar file name.
+pub fn key(name: &str) -> String {
+    format!("avatars/{name}")
+}
+
+/// Serves a stored avatar. Avatar names are never reused, so responses can be
+/// cached indefinitely.
+pub async fn serve(Path(name): Path<String>) -> Response {
+    if name.contains('/') {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match storage::storage().get(&key(&name)).await {
+        Ok(Some(data)) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ],
+            data,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read avatar {name}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn resizes_to_square_png() {
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif] {
+            let png = process(&encode(640, 320, format)).unwrap();
+            assert_eq!(image::guess_format(&png).unwrap(), ImageFormat::Png);
+            let image = image::load_from_memory(&png).unwrap();
+            assert_eq!((image.width(), image.height()), (SIZE, SIZE));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_uploads() {
+        assert_eq!(
+            process(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            Err(AvatarError::UnsupportedFormat)
+        );
+        assert_eq!(
+            process(b"BM\0\0\0\0\0\0\0\0\0\0"),
+            Err(AvatarError::UnsupportedFormat)
+        );
+        assert_eq!(
+            process(&vec![0; MAX_UPLOAD_BYTES + 1]),
+            Err(AvatarError::TooLarge)
+        );
+        assert_eq!(
+            process(&encode(MAX_DIMENSION + 1, 1, ImageFormat::Png)),
+            Err(AvatarError::DimensionsTooLarge)
+        );
+
+        let mut truncated = encode(64, 64, ImageFormat::Png);
+        truncated.truncate(truncated.len() / 2);
+        assert!(matches!(process(&truncated), Err(AvatarError::Invalid(_))));
+    }
+}
@@ -0,0 +1,627 @@
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::{error::Error, fs, io, path::PathBuf};
+
+use crate::{
+    audit::{self, EventType},
+    auth, avatar,
+    database::{self, AccountStatus},
+    export,
+    jobs::JobStatus,
+    migrations, storage, username,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the web server (default)
+    Serve(ServeArgs),
+    /// Manage database migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage login sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Inspect and retry background jobs
+    #[command(subcommand)]
+    Job(JobCommand),
+    /// Query the audit log
+    #[command(subcommand)]
+    Audit(AuditCommand),
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self::Serve(ServeArgs::default())
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Do not apply migrations on startup and refuse to start if any are pending
+    #[arg(long, env = "SKIP_MIGRATIONS")]
+    pub skip_migrations: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MigrateCommand {
+    /// Apply all pending migrations
+    Up,
+    /// Show applied and pending migrations
+    Status,
+    /// Revert the most recent migration, or all migrations newer than a version
+    Down {
+        /// Revert all migrations newer than this version
+        #[arg(long)]
+        to: Option<i64>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct PasswordArgs {
+    /// Read the password from the first line of stdin instead of prompting
+    #[arg(long)]
+    pub password_stdin: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a new user
+    Create {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Delete a user and revoke all of their sessions
+    Delete {
+        username: String,
+        /// Remove the user immediately instead of after the retention period
+        #[arg(long)]
+        purge: bool,
+    },
+    /// List all users
+    List,
+    /// Set a new password
+    ResetPassword {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Disable a user and revoke all of their sessions
+    Disable { username: String },
+    /// Re-enable a disabled user
+    Enable { username: String },
+    /// Export everything stored about a user as JSON
+    Export {
+        username: String,
+        /// Write the export to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SessionCommand {
+    /// List active sessions
+    List {
+        /// Only list sessions of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Revoke a single session or all sessions of a user
+    Revoke {
+        /// Token of the session to revoke
+        #[arg(required_unless_present = "user", conflicts_with = "user")]
+        token: Option<String>,
+        /// Revoke all sessions of this user
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum JobCommand {
+    /// List jobs, most recently due first
+    List {
+        /// Only list jobs with this status: pending, running, done or failed
+        #[arg(long)]
+        status: Option<JobStatus>,
+        /// Maximum number of jobs to list
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Run a failed job again
+    Retry { id: i64 },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// List audit events, most recent first
+    List {
+        /// Only list events of this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only list events of this type, e.g. login or rate_limited
+        #[arg(long)]
+        event: Option<EventType>,
+        /// Only list events since this time, e.g. 2024-05-01T00:00:00Z
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Maximum number of events to list
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Count events per day, type and outcome
+    Summary {
+        /// Only count events since the day of this time
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+    },
+}
+
+pub async fn migrate(command: MigrateCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    database::connect().await?;
+    let pool = &database::pool();
+
+    match command {
+        MigrateCommand::Up => {
+            let pending = migrations::pending(pool).await?;
+            migrations::up(pool).await?;
+            output(json, &pending, || {
+                for m in &pending {
+                    println!("applied {} {}", m.version, m.description);
+                }
+                if pending.is_empty() {
+                    println!("no pending migrations");
+                }
+            })
+        }
+        MigrateCommand::Status => {
+            let status = migrations::status(pool).await?;
+            output(json, &status, || {
+                for m in &status {
+                    println!(
+                        "{:>4} {:<8} {}{}",
+                        m.version,
+                        if m.applied { "applied" } else { "pending" },
+                        m.description,
+                        if m.reversible { "" } else { " (irreversible)" },
+                    );
+                }
+            })
+        }
+        MigrateCommand::Down { to } => {
+            let reverted = migrations::down(pool, to).await?;
+            output(json, &reverted, || {
+                for version in &reverted {
+                    println!("reverted {version}");
+                }
+                if reverted.is_empty() {
+                    println!("nothing to revert");
+                }
+            })
+        }
+    }
+}
+
+pub async fn user(command: UserCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    connect().await?;
+
+    match command {
+        UserCommand::Create { username, password } => {
+            let username = username::validate(&username)?;
+            if database::user_exists(&username).await? {
+                return Err(format!("user {username} already exists").into());
+            }
+            let hash = auth::hash_password(&read_password(&password)?)?;
+            database::create_user(&username, &hash).await?;
+            output(json, &username, || println!("created user {username}"))
+        }
+        UserCommand::Delete { username, purge } => {
+            if purge {
+                let avatar = database::get_profile(&username)
+                    .await?
+                    .and_then(|p| p.avatar);
+                ensure(database::delete_user(&username).await?, &username)?;
+                if let Some(avatar) = avatar {
+                    storage::storage().delete(&avatar::key(&avatar)).await?;
+                }
+            } else {
+                ensure(
+                    database::soft_delete_user(&username).await?
+                        || database::account_status(&username).await?
+                            == Some(AccountStatus::Deleted),
+                    &username,
+                )?;
+            }
+            output(json, &username, || println!("deleted user {username}"))
+        }
+        UserCommand::List => {
+            let users = database::list_users().await?;
+            output(json, &users, || {
+                for u in &users {
+                    let status = if u.deleted_at.is_some() {
+                        "deleted"
+                    } else if u.disabled_at.is_some() {
+                        "disabled"
+                    } else {
+                        "active"
+                    };
+                    println!(
+                        "{}\t{}\t{status}\t{} sessions",
+                        u.username,
+                        u.created_at.to_rfc3339(),
+                        u.sessions,
+                    );
+                }
+            })
+        }
+        UserCommand::ResetPassword { username, password } => {
+            let hash = auth::hash_password(&read_password(&password)?)?;
+            ensure(
+                database::set_password_hash(&username, &hash).await?,
+                &username,
+            )?;
+            output(json, &username, || println!("reset password of {username}"))
+        }
+        UserCommand::Disable { username } => {
+            ensure(
+                database::disable_user(&username).await?
+                    || database::account_status(&username).await?.is_some(),
+                &username,
+            )?;
+            output(json, &username, || println!("disabled user {username}"))
+        }
+        UserCommand::Enable { username } => {
+            ensure(
+                database::enable_user(&username).await?
+                    || database::account_status(&username).await? == Some(AccountStatus::Active),
+                &username,
+            )?;
+            output(json, &username, || println!("enabled user {username}"))
+        }
+        UserCommand::Export {
+            username,
+            output: path,
+        } => {
+            let export = export::collect(&username)
+                .await?
+                .ok_or_else(|| format!("user {username} not found"))?;
+            let contents = serde_json::to_string_pretty(&export)?;
+            match path {
+                Some(path) => fs::write(path, contents + "\n")?,
+                None => println!("{contents}"),
+            }
+            Ok(())
+        }
+    }
+}
+
+pub async fn session(command: SessionCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    connect().await?;
+
+    match command {
+        SessionCommand::List { user } => {
+            let sessions = database::list_sessions(user.as_deref()).await?;
+            output(json, &sessions, || {
+                for s in &sessions {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        s.username,
+                        s.created_at.to_rfc3339(),
+                        s.expires_at.to_rfc3339(),
+                        s.token,
+                    );
+                }
+            })
+        }
+        SessionCommand::Revoke { token, user } => {
+            let revoked = match (token, user) {
+                (Some(token), _) => u64::from(database::delete_session(&token).await?),
+                (None, Some(user)) => database::delete_user_sessions(&user).await?,
+                (None, None) => unreachable!("clap requires a token or user"),
+            };
+            output(json, &revoked, || println!("revoked {revoked} sessions"))
+        }
+    }
+}
+
+pub async fn job(command: JobCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    connect().await?;
+
+    match command {
+        JobCommand::List { status, limit } => {
+            let jobs = database::list_jobs(status, limit).await?;
+            output(json, &jobs, || {
+                for j in &jobs {
+                    println!(
+                        "{}\t{}\t{}\t{}/{}\t{}\t{}",
+                        j.id,
+                        j.kind,
+                        j.status,
+                        j.attempts,
+                        j.max_attempts,
+                        j.run_at.to_rfc3339(),
+                        j.last_error.as_deref().unwrap_or_default(),
+                    );
+                }
+            })
+        }
+        JobCommand::Retry { id } => {
+            if !database::retry_failed_job(id).await? {
+                return Err(format!("no failed job {id}").into());
+            }
+            output(json, &id, || println!("job {id} will run again"))
+        }
+    }
+}
+
+pub async fn audit(command: AuditCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    connect().await?;
+
+    match command {
+        AuditCommand::List {
+            user,
+            event,
+            since,
+            limit,
+        } => {
+            let events = database::list_audit_events(&audit::Filter {
+                actor: user,
+                event_type: event,
+                since,
+                limit,
+            })
+            .await?;
+            output(json, &events, || {
+                for e in &events {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        e.created_at.to_rfc3339(),
+                        e.event_type,
+                        e.outcome,
+                        e.actor.as_deref().unwrap_or("-"),
+                        e.ip_address.as_deref().unwrap_or("-"),
+                        e.user_agent.as_deref().unwrap_or_default(),
+                    );
+                }
+            })
+        }
+        AuditCommand::Summary { since } => {
+            let summary = database::audit_event_summary(since).await?;
+            output(json, &summary, || {
+                for s in &summary {
+                    println!(
+                        "{}\t{}\t{}\t{} events\t{} actors\t{} addresses",
+                        s.day.date_naive(),
+                        s.event_type,
+                        s.outcome,
+                        s.events,
+                        s.actors,
+                        s.ip_addresses,
+                    );
+                }
+            })
+        }
+    }
+}
+
+/// Connects to the database for administration, which must not migrate it as
+/// a side effect. Refuses to run against a schema with pending migrations.
+async fn connect() -> Result<(), Box<dyn Error>> {
+    database::connect().await?;
+    let pending = migrations::pending(&database::pool()).await?;
+    match pending.first() {
+        Some(m) => Err(format!(
+            "{} pending migrations, starting at {} {}; run `webapp migrate up` first",
+            pending.len(),
+            m.version,
+            m.description
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+fn output<T: Serialize>(json: bool, value: &T, text: impl FnOnce()) -> Result<(), Box<dyn Error>> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        text();
+    }
+    Ok(())
+}
+
+fn ensure(found: bool, username: &str) -> Result<(), Box<dyn Error>> {
+    if found {
+        Ok(())
+    } else {
+        Err(format!("user {username} not found").into())
+    }
+}
+
+fn read_password(args: &PasswordArgs) -> Result<String, Box<dyn Error>> {
+    let password = if args.password_stdin {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    } else {
+        let password = rpassword::prompt_password("Password: ")?;
+        if password != rpassword::prompt_password("Confirm password: ")? {
+            return Err("passwords do not match".into());
+        }
+        password
+    };
+
+    if password.is_empty() {
+        return Err("password must not be empty".into());
+    }
+    if password.len() > 128 {
+        return Err("password too long".into());
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_to_serve() {
+        let cli = Cli::try_parse_from(["webapp"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(matches!(
+            cli.command.unwrap_or_default(),
+            Command::Serve(ServeArgs {
+                skip_migrations: false
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_serve() {
+        let cli = Cli::try_parse_from(["webapp", "serve", "--skip-migrations"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Serve(ServeArgs {
+                skip_migrations: true
+            }))
+        ));
+    }
+
+    #[test]
+    fn parse_migrate() {
+        let cli = Cli::try_parse_from(["webapp", "migrate", "status"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Migrate(MigrateCommand::Status))
+        ));
+
+        let cli = Cli::try_parse_from(["webapp", "migrate", "down", "--to", "1"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Migrate(MigrateCommand::Down { to: Some(1) }))
+        ));
+
+        assert!(Cli::try_parse_from(["webapp", "migrate"]).is_err());
+    }
+
+    #[test]
+    fn parse_user() {
+        let cli =
+            Cli::try_parse_from(["webapp", "user", "create", "alice", "--password-stdin"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::User(UserCommand::Create {
+                ref username,
+                password: PasswordArgs {
+                    password_stdin: true
+                },
+            })) if username == "alice"
+        ));
+
+        let cli = Cli::try_parse_from(["webapp", "user", "list", "--json"]).unwrap();
+        assert!(cli.json);
+        assert!(matches!(
+            cli.command,
+            Some(Command::User(UserCommand::List))
+        ));
+
+        let cli = Cli::try_parse_from(["webapp", "user", "delete", "bob", "--purge"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::User(UserCommand::Delete { purge: true, .. }))
+        ));
+
+        assert!(Cli::try_parse_from(["webapp", "user", "delete"]).is_err());
+    }
+
+    #[test]
+    fn parse_session() {
+        let cli = Cli::try_parse_from(["webapp", "session", "revoke", "--user", "alice"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Session(SessionCommand::Revoke { token: None, user: Some(ref u) }))
+                if u == "alice"
+        ));
+
+        assert!(Cli::try_parse_from(["webapp", "session", "revoke"]).is_err());
+        assert!(
+            Cli::try_parse_from(["webapp", "session", "revoke", "tok", "--user", "a"]).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_job() {
+        let cli = Cli::try_parse_from(["webapp", "job", "list", "--status", "failed"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Job(JobCommand::List {
+                status: Some(JobStatus::Failed),
+                limit: 50
+            }))
+        ));
+
+        let cli = Cli::try_parse_from(["webapp", "job", "retry", "7"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Job(JobCommand::Retry { id: 7 }))
+        ));
+
+        assert!(Cli::try_parse_from(["webapp", "job", "list", "--status", "lost"]).is_err());
+    }
+
+    #[test]
+    fn parse_audit() {
+        let cli = Cli::try_parse_from([
+            "webapp",
+            "audit",
+            "list",
+            "--user",
+            "alice",
+            "--event",
+            "rate_limited",
+            "--since",
+            "2024-05-01T00:00:00Z",
+        ])
+        .unwrap();
+        let Some(Command::Audit(AuditCommand::List {
+            user,
+            event,
+            since,
+            limit,
+        })) = cli.command
+        else {
+            panic!("expected audit list");
+        };
+        assert_eq!(user.as_deref(), Some("alice"));
+        assert_eq!(event, Some(EventType::RateLimited));
+        assert_eq!(
+            since.map(|s| s.to_rfc3339()).as_deref(),
+            Some("2024-05-01T00:00:00+00:00")
+        );
+        assert_eq!(limit, 100);
+
+        let cli = Cli::try_parse_from(["webapp", "audit", "summary"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Audit(AuditCommand::Summary { since: None }))
+        ));
+
+        assert!(Cli::try_parse_from(["webapp", "audit", "list", "--event", "signup"]).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! The client used by all server functions.
+//!
+//! It wraps the default browser client to send the CSRF token of the page
+//! with every call, picks up tokens issued along with responses, and turns
+//! throttled calls into an error which tells the user when to try again (see
+//! [`throttled`]).
+
+use futures::{
+    Sink, Stream,
+    future::{Either, ready},
+};
+use leptos::server_fn::{
+    Bytes, ServerFnError,
+    client::{Client as ServerFnClient, browser::BrowserClient},
+    error::{FromServerFnError, ServerFnErrorErr},
+    request::browser::BrowserRequest,
+    response::{ClientRes, browser::BrowserResponse},
+};
+use std::future::Future;
+
+const THROTTLED: &str = "Too many requests, try again in ";
+
+/// The message of throttled calls.
+pub fn throttled_message(seconds: u64) -> String {
+    let unit = if seconds == 1 { "second" } else { "seconds" };
+    format!("{THROTTLED}{seconds} {unit}")
+}
+
+/// The seconds to wait before retrying, if the call was throttled.
+pub fn throttled(error: &ServerFnError) -> Option<u64> {
+    let ServerFnError::ServerError(msg) = error else {
+        return None;
+    };
+    let seconds = msg.strip_prefix(THROTTLED)?;
+    seconds
+        .strip_suffix(" seconds")
+        .or_else(|| seconds.strip_suffix(" second"))?
+        .parse()
+        .ok()
+}
+
+/// Use it for every server function with
+/// `#[server(endpoint = "<name>", client = crate::client::Client)]`. The
+/// endpoint keeps the path free of the generated hash, so that the name
+/// serves as the route of rate limit policies.
+pub struct Client;
+
+impl<E, IS, OS> ServerFnClient<E, IS, OS> for Client
+where
+    E: FromServerFnError + Send,
+    IS: FromServerFnError,
+    OS: FromServerFnError,
+{
+    type Request = BrowserRequest;
+    type Response = Response;
+
+    fn send(req: Self::Request) -> impl Future<Output = Result<Self::Response, E>> + Send {
+        #[cfg(feature = "hydrate")]
+        if let Some(token) = crate::csrf::token() {
+            req.headers().set(crate::csrf::HEADER, &token);
+        }
+        let sent = <BrowserClient as ServerFnClient<E, IS, OS>>::send(req);
+        async move {
+            let inner = sent.await?;
+            #[cfg(feature = "hydrate")]
+            if let Some(token) = inner.generate_headers().get(crate::csrf::HEADER) {
+                crate::csrf::replace(token.to_str().unwrap_or_default());
+            }
+            let retry_after = (ClientRes::<E>::status(&inner) == 429).then(|| {
+                inner
+                    .generate_headers()
+                    .get("retry-after")
+                    .and_then(|v| v.to_str().ok()?.parse().ok())
+                    .unwrap_or(1)
+            });
+            Ok(Response { inner, retry_after })
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn open_websocket(
+        path: &str,
+    ) -> impl Future<
+        Output = Result<
+            (
+                impl Stream<Item = Result<Bytes, Bytes>> + Send + 'static,
+                impl Sink<Bytes> + Send + 'static,
+            ),
+            E,
+        >,
+    > + Send {
+        <BrowserClient as ServerFnClient<E, IS, OS>>::open_websocket(path)
+    }
+
+    fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+        <BrowserClient as ServerFnClient<E, IS, OS>>::spawn(future)
+    }
+}
+
+/// A response of [`Client`]. The body of throttled responses is replaced by
+/// the encoded [`throttled_message`] error, as server functions expect
+/// errors in their own encoding.
+pub struct Response {
+    inner: BrowserResponse,
+    retry_after: Option<u64>,
+}
+
+impl Response {
+    fn throttled<E: FromServerFnError>(&self) -> Option<Bytes> {
+        let seconds = self.retry_after?;
+        Some(
+            E::from_server_fn_error(ServerFnErrorErr::ServerError(throttled_message(seconds)))
+                .ser(),
+        )
+    }
+}
+
+impl<E: FromServerFnError + Send> ClientRes<E> for Response {
+    fn try_into_string(self) -> impl Future<Output = Result<String, E>> + Send {
+        match self.throttled::<E>() {
+            Some(body) => Either::Left(ready(Ok(String::from_utf8_lossy(&body).into_owned()))),
+            None => Either::Right(ClientRes::<E>::try_into_string(self.inner)),
+        }
+    }
+
+    fn try_into_bytes(self) -> impl Future<Output = Result<Bytes, E>> + Send {
+        match self.throttled::<E>() {
+            Some(body) => Either::Left(ready(Ok(body))),
+            None => Either::Right(ClientRes::<E>::try_into_bytes(self.inner)),
+        }
+    }
+
+    fn try_into_stream(
+        self,
+    ) -> Result<impl Stream<Item = Result<Bytes, Bytes>> + Send + Sync + 'static, E> {
+        ClientRes::<E>::try_into_stream(self.inner)
+    }
+
+    fn status(&self) -> u16 {
+        ClientRes::<E>::status(&self.inner)
+    }
+
+    fn status_text(&self) -> String {
+        ClientRes::<E>::status_text(&self.inner)
+    }
+
+    fn location(&self) -> String {
+        ClientRes::<E>::location(&self.inner)
+    }
+
+    fn has_redirect(&self) -> bool {
+        ClientRes::<E>::has_redirect(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttled_errors() {
+        for seconds in [0, 1, 42] {
+            let error = ServerFnError::ServerError(throttled_message(seconds));
+            assert_eq!(throttled(&error), Some(seconds));
+        }
+        assert_eq!(
+            throttled_message(1),
+            "Too many requests, try again in 1 second"
+        );
+        assert_eq!(
+            throttled(&ServerFnError::ServerError("Invalid credentials".into())),
+            None
+        );
+        assert_eq!(
+            throttled(&ServerFnError::Request(throttled_message(3))),
+            None
+        );
+    }
+}
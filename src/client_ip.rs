@@ -0,0 +1,304 @@
+//! The address of the client behind a request.
+//!
+//! Without proxies this is the peer address of the connection. Behind
+//! reverse proxies the peer is the closest proxy, which reports the address
+//! it received the request from in `Forwarded` or `X-Forwarded-For`. Those
+//! headers are only believed from configured trusted proxies, walking the
+//! chain of proxies from the right until the first untrusted address, as
+//! everything left of it may have been made up by the client.
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderMap, Request, header},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+/// The resolved client address, available as a request extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8`. A plain address is a
+/// network of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(net), u32::from(ip), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask<T>(net: T, ip: T, prefix: u8, bits: u8) -> bool
+where
+    T: Copy + PartialEq + std::ops::Shr<u8, Output = T>,
+{
+    prefix == 0 || net >> (bits - prefix) == ip >> (bits - prefix)
+}
+
+/// Treats IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network {s}");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = canonical(addr.trim().parse().map_err(|_| invalid())?);
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Reads the comma separated networks of `TRUSTED_PROXIES` on first use.
+/// Invalid entries are logged and skipped.
+pub fn trusted_proxies() -> &'static [Cidr] {
+    static PROXIES: OnceLock<Vec<Cidr>> = OnceLock::new();
+    PROXIES.get_or_init(|| parse_list("TRUSTED_PROXIES"))
+}
+
+/// Parses a comma separated list of networks from an environment variable,
+/// skipping invalid entries. Only for lists where that fails closed.
+fn parse_list(name: &str) -> Vec<Cidr> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            entry
+                .parse()
+                .inspect_err(|e| tracing::warn!("ignoring {name} entry: {e}"))
+                .ok()
+        })
+        .collect()
+}
+
+/// Parses a node of `Forwarded` or `X-Forwarded-For`, which may be quoted
+/// and carry a port, e.g. `"[2001:db8::1]:4711"`.
+fn node(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Some(v6) = s.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    s.parse()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The proxy chain, from the client to the closest proxy. `Forwarded` takes
+/// precedence over `X-Forwarded-For`. Unknown or obfuscated nodes are kept
+/// as `None`.
+fn chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| node(value))?
+                })
+            })
+            .collect();
+    }
+    values("x-forwarded-for").into_iter().map(node).collect()
+}
+
+/// Resolves the client address of a request received from `peer`.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    let mut client = canonical(peer);
+    if !is_trusted(client) {
+        return client;
+    }
+    for node in chain(headers).into_iter().rev() {
+        // The last trusted proxy is as close to the client as we can get
+        let Some(ip) = node else {
+            break;
+        };
+        client = canonical(ip);
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// The client address of a request, as resolved by [`layer`], or resolved
+/// on the spot otherwise. It is unknown if the server was started without
+/// connect info, see `Router::into_make_service_with_connect_info`.
+pub fn of(req: &Request<Body>) -> Option<IpAddr> {
+    if let Some(ClientIp(ip)) = req.extensions().get() {
+        return Some(*ip);
+    }
+    let ConnectInfo(peer) = req.extensions().get::<ConnectInfo<SocketAddr>>()?;
+    Some(resolve(peer.ip(), req.headers(), trusted_proxies()))
+}
+
+/// Resolves the client address and stores it as [`ClientIp`] extension.
+pub async fn layer(mut req: Request<Body>, next: Next) -> Response {
+    match of(&req) {
+        Some(ip) => {
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        None => tracing::error!("unknown client address, the server lacks connect info"),
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn networks(list: &str) -> Vec<Cidr> {
+        list.split(',').map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        let host: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(host.contains(ip("192.168.1.1")));
+        assert!(!host.contains(ip("192.168.1.2")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let headers = headers("x-forwarded-for", "1.2.3.4");
+        assert_eq!(resolve(ip("5.6.7.8"), &headers, &[]), ip("5.6.7.8"));
+        assert_eq!(
+            resolve(ip("5.6.7.8"), &headers, &networks("10.0.0.0/8")),
+            ip("5.6.7.8")
+        );
+    }
+
+    #[test]
+    fn chain_is_walked_from_the_right() {
+        let trusted = networks("10.0.0.0/8,192.168.0.1");
+        // The client made up the first entry
+        let headers = headers("x-forwarded-for", "6.6.6.6, 1.2.3.4, 192.168.0.1");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("1.2.3.4"));
+
+        // Only trusted proxies, so the leftmost is the client
+        let headers = self::headers("x-forwarded-for", "10.0.0.3, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("10.0.0.3"));
+
+        // Unknown nodes end the walk at the last trusted proxy
+        let headers = self::headers("x-forwarded-for", "1.2.3.4, unknown, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("10.0.0.2"));
+
+        // No header at all
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let trusted = networks("10.0.0.0/8");
+        let mut headers = headers(
+            "forwarded",
+            "for=6.6.6.6, for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2:8080",
+        );
+        headers.insert("x-forwarded-for", HeaderValue::from_static("7.7.7.7"));
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+
+        let headers = self::headers("forwarded", "for=_hidden, for=1.2.3.4");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn mapped_addresses_are_canonical() {
+        assert_eq!(
+            resolve(ip("::ffff:5.6.7.8"), &HeaderMap::new(), &[]),
+            ip("5.6.7.8")
+        );
+    }
+
+    #[test]
+    fn clients_of_requests() {
+        let request = || Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(of(&request()), None);
+
+        let mut req = request();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([5, 6, 7, 8], 4711))));
+        assert_eq!(of(&req), Some(ip("5.6.7.8")));
+        req.extensions_mut().insert(ClientIp(ip("1.2.3.4")));
+        assert_eq!(of(&req), Some(ip("1.2.3.4")));
+    }
+}
@@ -1,152 +1,67 @@
-use axum::{
-    body::Body,
-    http::{Method, Request, StatusCode, header},
-    middleware::Next,
-    response::Response,
-};
+//! Cross-site request forgery protection.
+//!
+//! By default state-changing requests must carry an `Origin` or `Referer`
+//! header matching the requested host or one of the trusted origins of
+//! [`Config`]. Privacy-hardened browsers and some proxies strip
+//! both, so deployments can set `CSRF_MODE=token` to require a signed
+//! double-submit token instead (see [`Mode`]). The token is signed for the
+//! login session, issued in an HTTP-only cookie and embedded into server
+//! rendered pages, from where the server function [`Client`] sends it along
+//! with every call. Responses carrying a new token in the token header, e.g.
+//! after logging in, replace the token of the page.
+//!
+//! Browsers sending Fetch Metadata (`Sec-Fetch-Site` and friends) are
+//! additionally protected by a resource isolation policy, which blocks
+//! cross-site requests other than navigations for all methods (see
+//! [`FetchMetadata`]). Requests the browser reports as same-origin skip the
+//! Origin checks, while same-site ones from sibling subdomains do not.
+//!
+//! [`Client`]: crate::client::Client
 
-pub async fn validate(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    if req.method() != Method::POST {
-        return Ok(next.run(req).await);
-    }
+#[cfg(feature = "ssr")]
+mod middleware;
 
-    let host = req
-        .headers()
-        .get(header::HOST)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or_default();
+#[cfg(feature = "ssr")]
+pub use middleware::{
+    Config, Exempt, FetchMetadata, Mode, Origin, Token, config, rotate, validate,
+};
 
-    let origin = req
-        .headers()
-        .get(header::ORIGIN)
-        .and_then(|v| v.to_str().ok());
+/// Request header carrying the token.
+pub const HEADER: &str = "x-csrf-token";
 
-    let referer = req
-        .headers()
-        .get(header::REFERER)
-        .and_then(|v| v.to_str().ok());
+/// Form field carrying the token in URL-encoded form submissions.
+pub const FIELD: &str = "csrf_token";
 
-    let valid = match (origin, referer) {
-        (Some(origin), _) => origin_matches_host(origin, host),
-        (None, Some(referer)) => origin_matches_host(referer, host),
-        (None, None) => false,
-    };
+/// Cookie holding the token issued to the browser session.
+pub const COOKIE: &str = "csrf_token";
 
-    if valid {
-        Ok(next.run(req).await)
-    } else {
-        Err(StatusCode::FORBIDDEN)
-    }
-}
+/// Name of the `<meta>` tag with the token in server rendered pages.
+pub const META: &str = "csrf-token";
 
-fn origin_matches_host(origin: &str, host: &str) -> bool {
-    // Extract host portion from origin URL (e.g., "http://localhost:3000" -> "localhost:3000")
-    origin
-        .split("//")
-        .nth(1)
-        .map(|h| h.split('/').next().unwrap_or(h))
-        .is_some_and(|origin_host| origin_host == host)
+/// The token the page was rendered with, if the deployment uses tokens.
+#[cfg(feature = "hydrate")]
+pub fn token() -> Option<String> {
+    leptos::prelude::document()
+        .query_selector(&format!("meta[name=\"{META}\"]"))
+        .ok()??
+        .get_attribute("content")
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::{Router, middleware, routing::get, routing::post};
-    use http_body_util::BodyExt;
-    use tower::ServiceExt;
-
-    fn app() -> Router {
-        Router::new()
-            .route("/test", post(|| async { "ok" }))
-            .route("/get", get(|| async { "ok" }))
-            .layer(middleware::from_fn(validate))
-    }
-
-    #[tokio::test]
-    async fn get_requests_bypass_csrf() {
-        let resp = app()
-            .oneshot(Request::get("/get").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
-    }
-
-    #[tokio::test]
-    async fn post_without_origin_is_forbidden() {
-        let resp = app()
-            .oneshot(
-                Request::post("/test")
-                    .header("host", "localhost:3000")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
-    }
-
-    #[tokio::test]
-    async fn post_with_matching_origin_succeeds() {
-        let resp = app()
-            .oneshot(
-                Request::post("/test")
-                    .header("host", "localhost:3000")
-                    .header("origin", "http://localhost:3000")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
-        let body = resp.into_body().collect().await.unwrap().to_bytes();
-        assert_eq!(&body[..], b"ok");
-    }
-
-    #[tokio::test]
-    async fn post_with_wrong_origin_is_forbidden() {
-        let resp = app()
-            .oneshot(
-                Request::post("/test")
-                    .header("host", "localhost:3000")
-                    .header("origin", "http://evil.com")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
-    }
-
-    #[tokio::test]
-    async fn post_with_matching_referer_succeeds() {
-        let resp = app()
-            .oneshot(
-                Request::post("/test")
-                    .header("host", "localhost:3000")
-                    .header("referer", "http://localhost:3000/page")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
+/// Replaces the token of the page, e.g. with one issued for a new session.
+#[cfg(feature = "hydrate")]
+pub fn replace(token: &str) {
+    if let Ok(Some(meta)) =
+        leptos::prelude::document().query_selector(&format!("meta[name=\"{META}\"]"))
+    {
+        let _ = meta.set_attribute("content", token);
     }
+}
 
-    #[test]
-    fn origin_matching() {
-        assert!(origin_matches_host(
-            "http://localhost:3000",
-            "localhost:3000"
-        ));
-        assert!(origin_matches_host("https://example.com", "example.com"));
-        assert!(origin_matches_host(
-            "http://localhost:3000/some/path",
-            "localhost:3000"
-        ));
-        assert!(!origin_matches_host("http://evil.com", "localhost:3000"));
-        assert!(!origin_matches_host(
-            "http://localhost:9999",
-            "localhost:3000"
-        ));
-    }
+/// The token issued for the request being rendered, if any.
+#[cfg(feature = "ssr")]
+pub fn issued() -> Option<String> {
+    leptos::prelude::use_context::<axum::http::request::Parts>()?
+        .extensions
+        .get::<Token>()
+        .map(|token| token.0.clone())
 }
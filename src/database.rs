@@ -1,59 +1,492 @@
+use crate::{
+    attachments::Attachment,
+    audit::{self, AuditEvent, EventType, Outcome},
+    jobs::JobStatus,
+    maintenance, migrations,
+    notes::Note,
+    organizations::Role,
+    profile::{Profile, ProfileUpdate},
+    search,
+    storage::ContentStore,
+    username,
+};
 use chrono::{DateTime, Utc};
-use sqlx::{PgPool, postgres::PgPoolOptions};
-use std::{env, sync::OnceLock};
+use serde::Serialize;
+use sqlx::{
+    Connection, PgConnection, PgPool,
+    postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
+};
+use std::{env, future::Future, str::FromStr, sync::OnceLock, time::Duration};
 
 static POOL: OnceLock<PgPool> = OnceLock::new();
 
+tokio::task_local! {
+    static SCOPED_POOL: PgPool;
+}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection settings, read from `DATABASE_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub url: String,
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub statement_timeout: Option<Duration>,
+    pub ssl_mode: Option<PgSslMode>,
+    pub connect_retries: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, sqlx::Error> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, sqlx::Error> {
+        let parse = |key: &str| -> Result<Option<u64>, sqlx::Error> {
+            var(key)
+                .map(|v| {
+                    v.parse().map_err(|_| {
+                        sqlx::Error::Configuration(format!("invalid value for {key}: {v}").into())
+                    })
+                })
+                .transpose()
+        };
+        let secs = |key: &str| parse(key).map(|v| v.map(Duration::from_secs));
+
+        Ok(Self {
+            url: var("DATABASE_URL").unwrap_or_else(|| "postgres://localhost/webapp".into()),
+            max_connections: parse("DATABASE_MAX_CONNECTIONS")?
+                .map_or(Ok(5), u32::try_from)
+                .map_err(|e| sqlx::Error::Configuration(e.into()))?,
+            acquire_timeout: secs("DATABASE_ACQUIRE_TIMEOUT_SECS")?
+                .unwrap_or(Duration::from_secs(30)),
+            // Zero disables the idle and statement timeouts
+            idle_timeout: Some(
+                secs("DATABASE_IDLE_TIMEOUT_SECS")?.unwrap_or(Duration::from_secs(600)),
+            )
+            .filter(|d| !d.is_zero()),
+            statement_timeout: parse("DATABASE_STATEMENT_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .filter(|d| !d.is_zero()),
+            ssl_mode: var("DATABASE_SSL_MODE").map(|v| v.parse()).transpose()?,
+            connect_retries: parse("DATABASE_CONNECT_RETRIES")?
+                .map_or(Ok(5), u32::try_from)
+                .map_err(|e| sqlx::Error::Configuration(e.into()))?,
+        })
+    }
+
+    pub fn connect_options(&self) -> Result<PgConnectOptions, sqlx::Error> {
+        let mut options = PgConnectOptions::from_str(&self.url)?;
+        if let Some(ssl_mode) = self.ssl_mode {
+            options = options.ssl_mode(ssl_mode);
+        }
+        if let Some(timeout) = self.statement_timeout {
+            options = options.options([("statement_timeout", timeout.as_millis())]);
+        }
+        Ok(options)
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+}
+
 pub async fn init() -> Result<(), sqlx::Error> {
-    let database_url =
-        env::var("DATABASE_URL").unwrap_or_else(|_| "postgres://localhost/webapp".into());
+    connect().await?;
+    migrations::up(&pool()).await?;
+    Ok(())
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await?;
+/// Connects to the database without applying migrations, retrying with
+/// exponential backoff while the database is unreachable.
+pub async fn connect() -> Result<(), sqlx::Error> {
+    let config = Config::from_env()?;
+    let options = config.connect_options()?;
 
-    sqlx::migrate!().run(&pool).await?;
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 0;
+    let pool = loop {
+        match config.pool_options().connect_with(options.clone()).await {
+            Ok(pool) => break pool,
+            Err(e) if attempt < config.connect_retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "failed to connect to database (attempt {attempt}/{}), retrying in {delay:?}: {e}",
+                    config.connect_retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    };
 
     POOL.set(pool).expect("database pool already initialized");
 
     Ok(())
 }
 
-pub fn pool() -> &'static PgPool {
-    POOL.get().expect("database pool not initialized")
+pub fn is_initialized() -> bool {
+    SCOPED_POOL.try_with(|_| ()).is_ok() || POOL.get().is_some()
+}
+
+/// Returns a handle to the pool of the current task, which is cheap to clone.
+pub fn pool() -> PgPool {
+    SCOPED_POOL
+        .try_with(PgPool::clone)
+        .unwrap_or_else(|_| POOL.get().expect("database pool not initialized").clone())
+}
+
+/// Runs a future with [`pool`] resolving to the given pool instead of the
+/// global one. Used to give tests an isolated database.
+pub async fn with_pool<F: Future>(pool: PgPool, f: F) -> F::Output {
+    SCOPED_POOL.scope(pool, f).await
 }
 
 // User management
 
 pub async fn create_user(username: &str, password_hash: &str) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO users (username, password_hash) VALUES ($1, $2)")
-        .bind(username)
-        .bind(password_hash)
-        .execute(pool())
-        .await?;
+    let mut conn = pool().acquire().await?;
+    insert_user(&mut conn, username, password_hash).await
+}
+
+/// Creates a user and accepts an invitation for them in one transaction.
+/// Returns false without creating the user if the invitation is invalid.
+pub async fn create_user_with_invitation(
+    username: &str,
+    password_hash: &str,
+    invitation: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool().begin().await?;
+    insert_user(&mut tx, username, password_hash).await?;
+    if redeem_invitation(&mut tx, invitation, username)
+        .await?
+        .is_none()
+    {
+        return Ok(false);
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+async fn insert_user(
+    conn: &mut PgConnection,
+    username: &str,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO users (username, username_folded, username_skeleton, password_hash) \
+         VALUES ($1, $2, $3, $4)",
+    )
+    .bind(username)
+    .bind(username::fold(username))
+    .bind(username::skeleton(username))
+    .bind(password_hash)
+    .execute(conn)
+    .await?;
     Ok(())
 }
 
-pub async fn get_password_hash(username: &str) -> Result<Option<String>, sqlx::Error> {
-    let row: Option<(String,)> =
-        sqlx::query_as("SELECT password_hash FROM users WHERE username = $1")
-            .bind(username)
-            .fetch_optional(pool())
+/// Recomputes the case-folded and skeleton forms of usernames stored before
+/// they were computed by the application, or by an older folding. Returns the
+/// usernames left unchanged because their new form collides with another user.
+pub async fn backfill_usernames(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    let users: Vec<(String, String, Option<String>)> =
+        sqlx::query_as("SELECT username, username_folded, username_skeleton FROM users")
+            .fetch_all(pool)
             .await?;
-    Ok(row.map(|r| r.0))
+
+    let mut conflicts = Vec::new();
+    for (name, folded, skeleton) in users {
+        let (new_folded, new_skeleton) = (username::fold(&name), username::skeleton(&name));
+        if folded == new_folded && skeleton.as_ref() == Some(&new_skeleton) {
+            continue;
+        }
+        let result = sqlx::query(
+            "UPDATE users SET username_folded = $2, username_skeleton = $3 WHERE username = $1",
+        )
+        .bind(&name)
+        .bind(new_folded)
+        .bind(new_skeleton)
+        .execute(pool)
+        .await;
+        match result {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => conflicts.push(name),
+            result => {
+                result?;
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+pub async fn get_password_hash(username: &str) -> Result<Option<String>, sqlx::Error> {
+    Ok(get_credentials(username).await?.map(|(_, hash)| hash))
+}
+
+/// Looks up a user case-insensitively, returning the stored username together
+/// with its password hash. Deleted users are not found.
+pub async fn get_credentials(username: &str) -> Result<Option<(String, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT username, password_hash FROM users \
+         WHERE username_folded = $1 AND deleted_at IS NULL",
+    )
+    .bind(username::fold(username))
+    .fetch_optional(&pool())
+    .await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Disabled,
+    Deleted,
+}
+
+pub async fn account_status(username: &str) -> Result<Option<AccountStatus>, sqlx::Error> {
+    let row: Option<(bool, bool)> = sqlx::query_as(
+        "SELECT disabled_at IS NOT NULL, deleted_at IS NOT NULL FROM users \
+         WHERE username_folded = $1",
+    )
+    .bind(username::fold(username))
+    .fetch_optional(&pool())
+    .await?;
+    Ok(row.map(|row| match row {
+        (_, true) => AccountStatus::Deleted,
+        (true, false) => AccountStatus::Disabled,
+        (false, false) => AccountStatus::Active,
+    }))
 }
 
+/// Returns true if a user with the same case-folded username or the same
+/// confusable skeleton exists. Soft-deleted users keep their username until
+/// they are purged.
 pub async fn user_exists(username: &str) -> Result<bool, sqlx::Error> {
-    let row: Option<(i32,)> = sqlx::query_as("SELECT 1 FROM users WHERE username = $1 LIMIT 1")
-        .bind(username)
-        .fetch_optional(pool())
+    let row: Option<(i32,)> = sqlx::query_as(
+        "SELECT 1 FROM users WHERE username_folded = $1 OR username_skeleton = $2 LIMIT 1",
+    )
+    .bind(username::fold(username))
+    .bind(username::skeleton(username))
+    .fetch_optional(&pool())
+    .await?;
+    Ok(row.is_some())
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub sessions: i64,
+}
+
+pub async fn list_users() -> Result<Vec<User>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT u.username, u.created_at, u.disabled_at, u.deleted_at, \
+                COUNT(s.token) AS sessions \
+         FROM users u LEFT JOIN sessions s ON s.username = u.username \
+         GROUP BY u.username ORDER BY u.username",
+    )
+    .fetch_all(&pool())
+    .await
+}
+
+pub async fn get_user(username: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT u.username, u.created_at, u.disabled_at, u.deleted_at, \
+                COUNT(s.token) AS sessions \
+         FROM users u LEFT JOIN sessions s ON s.username = u.username \
+         WHERE u.username_folded = $1 GROUP BY u.username",
+    )
+    .bind(username::fold(username))
+    .fetch_optional(&pool())
+    .await
+}
+
+/// Permanently deletes a user together with all of their sessions.
+pub async fn delete_user(username: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM users WHERE username_folded = $1")
+        .bind(username::fold(username))
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn set_password_hash(username: &str, password_hash: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE users SET password_hash = $1 WHERE username_folded = $2")
+        .bind(password_hash)
+        .bind(username::fold(username))
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Disables a user and revokes all of their sessions. Returns false if the
+/// user does not exist or is already disabled.
+pub async fn disable_user(username: &str) -> Result<bool, sqlx::Error> {
+    set_status_and_revoke(
+        "UPDATE users SET disabled_at = NOW() \
+         WHERE username_folded = $1 AND disabled_at IS NULL AND deleted_at IS NULL \
+         RETURNING username",
+        username,
+    )
+    .await
+}
+
+pub async fn enable_user(username: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE users SET disabled_at = NULL \
+         WHERE username_folded = $1 AND disabled_at IS NOT NULL AND deleted_at IS NULL",
+    )
+    .bind(username::fold(username))
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Marks a user as deleted and revokes all of their sessions. The row is kept
+/// until [`purge_deleted_users`] removes it.
+pub async fn soft_delete_user(username: &str) -> Result<bool, sqlx::Error> {
+    set_status_and_revoke(
+        "UPDATE users SET deleted_at = NOW() \
+         WHERE username_folded = $1 AND deleted_at IS NULL RETURNING username",
+        username,
+    )
+    .await
+}
+
+async fn set_status_and_revoke(query: &str, username: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool().begin().await?;
+    let row: Option<(String,)> = sqlx::query_as(query)
+        .bind(username::fold(username))
+        .fetch_optional(&mut *tx)
         .await?;
+    if let Some((username,)) = &row {
+        sqlx::query("DELETE FROM sessions WHERE username = $1")
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
     Ok(row.is_some())
 }
 
+pub async fn purge_deleted_users(cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM users WHERE deleted_at < $1")
+        .bind(cutoff)
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Reads `DELETED_USER_RETENTION_DAYS` on first use, warning about invalid
+/// values.
+pub fn deleted_user_retention() -> chrono::Duration {
+    const DEFAULT_DAYS: i64 = 30;
+    static DAYS: OnceLock<i64> = OnceLock::new();
+    let days = *DAYS.get_or_init(|| {
+        let Ok(v) = env::var("DELETED_USER_RETENTION_DAYS") else {
+            return DEFAULT_DAYS;
+        };
+        v.parse().ok().filter(|days| *days >= 0).unwrap_or_else(|| {
+            tracing::warn!(
+                "ignoring invalid DELETED_USER_RETENTION_DAYS {v}, purging deleted users after \
+                 {DEFAULT_DAYS} days"
+            );
+            DEFAULT_DAYS
+        })
+    });
+    chrono::Duration::days(days)
+}
+
+// Profiles
+
+/// Returns the profile of a user, with defaults for users who never edited
+/// theirs.
+pub async fn get_profile(username: &str) -> Result<Option<Profile>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT u.username, p.display_name, p.bio, \
+                COALESCE(p.timezone, $2) AS timezone, COALESCE(p.locale, $3) AS locale, \
+                p.avatar \
+         FROM users u LEFT JOIN profiles p ON p.username = u.username \
+         WHERE u.username_folded = $1",
+    )
+    .bind(username::fold(username))
+    .bind(crate::profile::DEFAULT_TIMEZONE)
+    .bind(crate::profile::DEFAULT_LOCALE)
+    .fetch_optional(&pool())
+    .await
+}
+
+pub async fn update_profile(username: &str, update: &ProfileUpdate) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO profiles (username, display_name, bio, timezone, locale) \
+         SELECT username, $2, $3, $4, $5 FROM users WHERE username_folded = $1 \
+         ON CONFLICT (username) DO UPDATE SET \
+             display_name = EXCLUDED.display_name, bio = EXCLUDED.bio, \
+             timezone = EXCLUDED.timezone, locale = EXCLUDED.locale, updated_at = NOW()",
+    )
+    .bind(username::fold(username))
+    .bind(update.display_name.as_deref())
+    .bind(update.bio.as_deref())
+    .bind(&update.timezone)
+    .bind(&update.locale)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Sets or clears the avatar of a user and returns the previous one.
+pub async fn set_avatar(
+    username: &str,
+    avatar: Option<&str>,
+) -> Result<Option<String>, sqlx::Error> {
+    let row: Option<(Option<String>,)> = sqlx::query_as(
+        "WITH previous AS ( \
+             SELECT p.avatar FROM profiles p JOIN users u ON u.username = p.username \
+             WHERE u.username_folded = $1 \
+         ) \
+         INSERT INTO profiles (username, avatar) \
+         SELECT username, $2 FROM users WHERE username_folded = $1 \
+         ON CONFLICT (username) DO UPDATE SET avatar = EXCLUDED.avatar, updated_at = NOW() \
+         RETURNING (SELECT avatar FROM previous)",
+    )
+    .bind(username::fold(username))
+    .bind(avatar)
+    .fetch_optional(&pool())
+    .await?;
+    Ok(row.and_then(|(previous,)| previous))
+}
+
+/// Avatars of users soft-deleted before the cutoff, which are removed by
+/// [`purge_deleted_users`].
+pub async fn deleted_user_avatars(cutoff: DateTime<Utc>) -> Result<Vec<String>, sqlx::Error> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        "SELECT p.avatar FROM profiles p JOIN users u ON u.username = p.username \
+         WHERE u.deleted_at < $1 AND p.avatar IS NOT NULL",
+    )
+    .bind(cutoff)
+    .fetch_all(&pool())
+    .await?;
+    Ok(rows.into_iter().map(|(avatar,)| avatar).collect())
+}
+
 // Session management
 
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub token: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
 pub async fn create_session(
     token: &str,
     username: &str,
@@ -63,7 +496,7 @@ pub async fn create_session(
         .bind(token)
         .bind(username)
         .bind(expires_at)
-        .execute(pool())
+        .execute(&pool())
         .await?;
     Ok(())
 }
@@ -71,7 +504,7 @@ pub async fn create_session(
 pub async fn session_exists(token: &str) -> Result<bool, sqlx::Error> {
     let row: Option<(i32,)> = sqlx::query_as("SELECT 1 FROM sessions WHERE token = $1 LIMIT 1")
         .bind(token)
-        .fetch_optional(pool())
+        .fetch_optional(&pool())
         .await?;
     Ok(row.is_some())
 }
@@ -85,7 +518,7 @@ pub async fn update_session(
         .bind(new_token)
         .bind(expires_at)
         .bind(old_token)
-        .execute(pool())
+        .execute(&pool())
         .await?;
     Ok(result.rows_affected() > 0)
 }
@@ -93,14 +526,827 @@ pub async fn update_session(
 pub async fn delete_session(token: &str) -> Result<bool, sqlx::Error> {
     let result = sqlx::query("DELETE FROM sessions WHERE token = $1")
         .bind(token)
-        .execute(pool())
+        .execute(&pool())
         .await?;
     Ok(result.rows_affected() > 0)
 }
 
+pub async fn list_sessions(username: Option<&str>) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT s.token, s.username, s.created_at, s.expires_at \
+         FROM sessions s JOIN users u ON u.username = s.username \
+         WHERE $1::TEXT IS NULL OR u.username_folded = $1 \
+         ORDER BY s.created_at DESC",
+    )
+    .bind(username.map(username::fold))
+    .fetch_all(&pool())
+    .await
+}
+
+pub async fn delete_user_sessions(username: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM sessions WHERE username IN \
+         (SELECT username FROM users WHERE username_folded = $1)",
+    )
+    .bind(username::fold(username))
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected())
+}
+
 pub async fn delete_expired_sessions() -> Result<u64, sqlx::Error> {
     let result = sqlx::query("DELETE FROM sessions WHERE expires_at < NOW()")
-        .execute(pool())
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// Organizations
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Membership {
+    pub organization_id: i64,
+    pub organization: String,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Invitation metadata. The token itself is a credential and not included.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Invitation {
+    pub organization_id: i64,
+    pub organization: String,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub invited_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub accepted_by: Option<String>,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+/// Creates an organization with the given user as its owner.
+pub async fn create_organization(name: &str, owner: &str) -> Result<i64, sqlx::Error> {
+    let mut tx = pool().begin().await?;
+    let (id,): (i64,) = sqlx::query_as("INSERT INTO organizations (name) VALUES ($1) RETURNING id")
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO memberships (organization_id, username, role) \
+         SELECT $1, username, $3 FROM users WHERE username_folded = $2",
+    )
+    .bind(id)
+    .bind(username::fold(owner))
+    .bind(Role::Owner.as_str())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+pub async fn list_memberships(username: &str) -> Result<Vec<Membership>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT m.organization_id, o.name AS organization, m.role, m.created_at \
+         FROM memberships m \
+         JOIN organizations o ON o.id = m.organization_id \
+         JOIN users u ON u.username = m.username \
+         WHERE u.username_folded = $1 \
+         ORDER BY o.name, o.id",
+    )
+    .bind(username::fold(username))
+    .fetch_all(&pool())
+    .await
+}
+
+pub async fn membership_role(
+    organization_id: i64,
+    username: &str,
+) -> Result<Option<Role>, sqlx::Error> {
+    let row: Option<(String,)> = sqlx::query_as(
+        "SELECT m.role FROM memberships m JOIN users u ON u.username = m.username \
+         WHERE m.organization_id = $1 AND u.username_folded = $2",
+    )
+    .bind(organization_id)
+    .bind(username::fold(username))
+    .fetch_optional(&pool())
+    .await?;
+    row.map(|(role,)| {
+        role.parse()
+            .map_err(|e: String| sqlx::Error::Decode(e.into()))
+    })
+    .transpose()
+}
+
+pub async fn create_invitation(
+    token: &str,
+    organization_id: i64,
+    role: Role,
+    invited_by: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO invitations (token, organization_id, role, invited_by, expires_at) \
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(token)
+    .bind(organization_id)
+    .bind(role.as_str())
+    .bind(invited_by)
+    .bind(expires_at)
+    .execute(&pool())
+    .await?;
+    Ok(())
+}
+
+/// Accepts an unused, unexpired invitation and adds the user to its
+/// organization, returning the organization. Existing memberships are kept
+/// unchanged.
+pub async fn accept_invitation(token: &str, username: &str) -> Result<Option<i64>, sqlx::Error> {
+    let mut tx = pool().begin().await?;
+    let organization = redeem_invitation(&mut tx, token, username).await?;
+    tx.commit().await?;
+    Ok(organization)
+}
+
+async fn redeem_invitation(
+    conn: &mut PgConnection,
+    token: &str,
+    username: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let row: Option<(i64, String, String)> = sqlx::query_as(
+        "UPDATE invitations i SET accepted_by = u.username, accepted_at = NOW() \
+         FROM users u \
+         WHERE i.token = $1 AND u.username_folded = $2 \
+           AND i.accepted_at IS NULL AND i.expires_at > NOW() \
+         RETURNING i.organization_id, i.role, u.username",
+    )
+    .bind(token)
+    .bind(username::fold(username))
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some((organization_id, role, username)) = row else {
+        return Ok(None);
+    };
+
+    sqlx::query(
+        "INSERT INTO memberships (organization_id, username, role) VALUES ($1, $2, $3) \
+         ON CONFLICT (organization_id, username) DO NOTHING",
+    )
+    .bind(organization_id)
+    .bind(username)
+    .bind(role)
+    .execute(&mut *conn)
+    .await?;
+    Ok(Some(organization_id))
+}
+
+/// Lists invitations sent or accepted by a user.
+pub async fn list_invitations(username: &str) -> Result<Vec<Invitation>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT i.organization_id, o.name AS organization, i.role, i.invited_by, \
+                i.created_at, i.expires_at, i.accepted_by, i.accepted_at \
+         FROM invitations i \
+         JOIN organizations o ON o.id = i.organization_id \
+         JOIN users u ON u.username IN (i.invited_by, i.accepted_by) \
+         WHERE u.username_folded = $1 \
+         ORDER BY i.created_at DESC",
+    )
+    .bind(username::fold(username))
+    .fetch_all(&pool())
+    .await
+}
+
+// Notes
+
+/// Returns a page of a user's notes, most recently updated first, together
+/// with the total number of notes.
+pub async fn list_notes(
+    username: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<Note>, i64), sqlx::Error> {
+    let notes = sqlx::query_as(
+        "SELECT id, title, body, created_at, updated_at FROM notes \
+         WHERE username = $1 ORDER BY updated_at DESC, id DESC LIMIT $2 OFFSET $3",
+    )
+    .bind(username)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&pool())
+    .await?;
+    let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM notes WHERE username = $1")
+        .bind(username)
+        .fetch_one(&pool())
+        .await?;
+    Ok((notes, total))
+}
+
+pub async fn get_note(id: i64, username: &str) -> Result<Option<Note>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, title, body, created_at, updated_at FROM notes \
+         WHERE id = $1 AND username = $2",
+    )
+    .bind(id)
+    .bind(username)
+    .fetch_optional(&pool())
+    .await
+}
+
+pub async fn create_note(username: &str, title: &str, body: &str) -> Result<Note, sqlx::Error> {
+    sqlx::query_as(
+        "INSERT INTO notes (username, title, body) VALUES ($1, $2, $3) \
+         RETURNING id, title, body, created_at, updated_at",
+    )
+    .bind(username)
+    .bind(title)
+    .bind(body)
+    .fetch_one(&pool())
+    .await
+}
+
+/// Updates a note owned by the user, returning `None` if there is none.
+pub async fn update_note(
+    id: i64,
+    username: &str,
+    title: &str,
+    body: &str,
+) -> Result<Option<Note>, sqlx::Error> {
+    sqlx::query_as(
+        "UPDATE notes SET title = $3, body = $4, updated_at = NOW() \
+         WHERE id = $1 AND username = $2 \
+         RETURNING id, title, body, created_at, updated_at",
+    )
+    .bind(id)
+    .bind(username)
+    .bind(title)
+    .bind(body)
+    .fetch_optional(&pool())
+    .await
+}
+
+pub async fn delete_note(id: i64, username: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM notes WHERE id = $1 AND username = $2")
+        .bind(id)
+        .bind(username)
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Ranked full-text search over a user's notes. Snippets are generated by
+/// `ts_headline` with the given options.
+pub async fn search_notes(
+    username: &str,
+    query: &str,
+    headline_options: &str,
+    limit: i64,
+) -> Result<Vec<search::Hit>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT n.id, n.title, \
+                ts_headline('english', CASE WHEN n.body = '' THEN n.title ELSE n.body END, \
+                            q, $3) AS snippet, \
+                ts_rank(n.search, q) AS rank \
+         FROM notes n, websearch_to_tsquery('english', $2) q \
+         WHERE n.username = $1 AND n.search @@ q \
+         ORDER BY rank DESC, n.updated_at DESC, n.id DESC \
+         LIMIT $4",
+    )
+    .bind(username)
+    .bind(query)
+    .bind(headline_options)
+    .bind(limit)
+    .fetch_all(&pool())
+    .await
+}
+
+/// Case-insensitive substring search over a user's notes using only portable
+/// SQL. Title matches rank before body matches; the snippet is the matching
+/// body, or the title if only that matched.
+pub async fn search_notes_like(
+    username: &str,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<search::Hit>, sqlx::Error> {
+    let escaped = query
+        .to_lowercase()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    sqlx::query_as(
+        "SELECT id, title, \
+                CASE WHEN LOWER(body) LIKE $2 ESCAPE '\\' THEN body ELSE title END AS snippet, \
+                CAST(CASE WHEN LOWER(title) LIKE $2 ESCAPE '\\' THEN 1 ELSE 0.5 END AS REAL) \
+                    AS rank \
+         FROM notes \
+         WHERE username = $1 \
+           AND (LOWER(title) LIKE $2 ESCAPE '\\' OR LOWER(body) LIKE $2 ESCAPE '\\') \
+         ORDER BY rank DESC, updated_at DESC, id DESC \
+         LIMIT $3",
+    )
+    .bind(username)
+    .bind(format!("%{escaped}%"))
+    .bind(limit)
+    .fetch_all(&pool())
+    .await
+}
+
+// Attachments
+
+/// An attachment together with the digest of its contents.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct StoredAttachment {
+    #[sqlx(flatten)]
+    pub attachment: Attachment,
+    pub digest: String,
+}
+
+/// Lists a user's attachments, newest first.
+pub async fn list_attachments(username: &str) -> Result<Vec<Attachment>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, filename, content_type, size, created_at FROM attachments \
+         WHERE username = $1 ORDER BY created_at DESC, id DESC",
+    )
+    .bind(username)
+    .fetch_all(&pool())
+    .await
+}
+
+/// Total size of a user's attachments in bytes.
+pub async fn attachment_usage(username: &str) -> Result<i64, sqlx::Error> {
+    let (used,): (i64,) = sqlx::query_as(
+        "SELECT CAST(COALESCE(SUM(size), 0) AS BIGINT) FROM attachments WHERE username = $1",
+    )
+    .bind(username)
+    .fetch_one(&pool())
+    .await?;
+    Ok(used)
+}
+
+pub async fn get_attachment(
+    id: i64,
+    username: &str,
+) -> Result<Option<StoredAttachment>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, filename, content_type, size, created_at, digest FROM attachments \
+         WHERE id = $1 AND username = $2",
+    )
+    .bind(id)
+    .bind(username)
+    .fetch_optional(&pool())
+    .await
+}
+
+/// Records an attachment if it fits into the user's quota, returning `None`
+/// otherwise. `store` moves the contents into place; it runs while the blob
+/// is locked so that [`purge_orphaned_blobs`] cannot remove it concurrently.
+pub async fn create_attachment<F>(
+    username: &str,
+    filename: &str,
+    content_type: &str,
+    digest: &str,
+    size: i64,
+    quota: i64,
+    store: F,
+) -> Result<Option<Attachment>, sqlx::Error>
+where
+    F: Future<Output = std::io::Result<()>>,
+{
+    let mut tx = pool().begin().await?;
+    // Serializes the uploads of a user, concurrent ones could exceed the
+    // quota together otherwise
+    sqlx::query("SELECT 1 FROM users WHERE username = $1 FOR NO KEY UPDATE")
+        .bind(username)
+        .execute(&mut *tx)
+        .await?;
+    let (used,): (i64,) = sqlx::query_as(
+        "SELECT CAST(COALESCE(SUM(size), 0) AS BIGINT) FROM attachments WHERE username = $1",
+    )
+    .bind(username)
+    .fetch_one(&mut *tx)
+    .await?;
+    if used + size > quota {
+        return Ok(None);
+    }
+
+    sqlx::query(
+        "INSERT INTO blobs (digest, size) VALUES ($1, $2) \
+         ON CONFLICT (digest) DO UPDATE SET size = EXCLUDED.size",
+    )
+    .bind(digest)
+    .bind(size)
+    .execute(&mut *tx)
+    .await?;
+    let attachment = sqlx::query_as(
+        "INSERT INTO attachments (username, digest, filename, content_type, size) \
+         VALUES ($1, $2, $3, $4, $5) \
+         RETURNING id, filename, content_type, size, created_at",
+    )
+    .bind(username)
+    .bind(digest)
+    .bind(filename)
+    .bind(content_type)
+    .bind(size)
+    .fetch_one(&mut *tx)
+    .await?;
+    store.await?;
+    tx.commit().await?;
+    Ok(Some(attachment))
+}
+
+pub async fn delete_attachment(id: i64, username: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM attachments WHERE id = $1 AND username = $2")
+        .bind(id)
+        .bind(username)
+        .execute(&pool())
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes the contents no attachment refers to anymore, returning how many
+/// were removed. Blobs locked by uploads in progress are skipped.
+pub async fn purge_orphaned_blobs(store: &ContentStore) -> Result<u64, sqlx::Error> {
+    let mut tx = pool().begin().await?;
+    let digests: Vec<(String,)> = sqlx::query_as(
+        "SELECT digest FROM blobs b \
+         WHERE NOT EXISTS (SELECT 1 FROM attachments a WHERE a.digest = b.digest) \
+         FOR UPDATE SKIP LOCKED",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let digests: Vec<String> = digests.into_iter().map(|(d,)| d).collect();
+    for digest in &digests {
+        store.delete(digest).await?;
+    }
+    let result = sqlx::query("DELETE FROM blobs WHERE digest = ANY($1)")
+        .bind(&digests)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(result.rows_affected())
+}
+
+// Jobs
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    #[sqlx(try_from = "String")]
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub locked_by: Option<String>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+pub async fn enqueue_job(
+    kind: &str,
+    payload: &serde_json::Value,
+    max_attempts: i32,
+    run_at: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    let (id,): (i64,) = sqlx::query_as(
+        "INSERT INTO jobs (kind, payload, max_attempts, run_at) VALUES ($1, $2, $3, $4) \
+         RETURNING id",
+    )
+    .bind(kind)
+    .bind(payload)
+    .bind(max_attempts)
+    .bind(run_at)
+    .fetch_one(&pool())
+    .await?;
+    Ok(id)
+}
+
+/// Enqueues an occurrence of a scheduled job unless it already exists.
+/// Returns whether it was enqueued.
+pub async fn schedule_job(
+    kind: &str,
+    payload: &serde_json::Value,
+    max_attempts: i32,
+    run_at: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "INSERT INTO jobs (kind, payload, max_attempts, run_at, scheduled_for) \
+         VALUES ($1, $2, $3, $4, $4) \
+         ON CONFLICT (kind, scheduled_for) WHERE scheduled_for IS NOT NULL DO NOTHING",
+    )
+    .bind(kind)
+    .bind(payload)
+    .bind(max_attempts)
+    .bind(run_at)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Claims the next due job of the given kinds for a worker, including jobs
+/// whose previous claim is older than the lease.
+pub async fn claim_job(
+    kinds: &[&str],
+    worker: &str,
+    lease: Duration,
+) -> Result<Option<JobRecord>, sqlx::Error> {
+    sqlx::query_as(
+        "UPDATE jobs SET status = 'running', attempts = attempts + 1, \
+                locked_by = $2, locked_at = NOW() \
+         WHERE id = ( \
+             SELECT id FROM jobs \
+             WHERE kind = ANY($1) \
+               AND ((status = 'pending' AND run_at <= NOW()) \
+                 OR (status = 'running' AND locked_at < NOW() - $3 * INTERVAL '1 second')) \
+             ORDER BY run_at, id \
+             LIMIT 1 \
+             FOR UPDATE SKIP LOCKED \
+         ) \
+         RETURNING id, kind, payload, status, attempts, max_attempts, run_at, locked_by, \
+                   last_error, created_at, finished_at",
+    )
+    .bind(kinds)
+    .bind(worker)
+    .bind(lease.as_secs_f64())
+    .fetch_optional(&pool())
+    .await
+}
+
+/// Marks a job claimed by the worker as done.
+pub async fn complete_job(id: i64, worker: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE jobs SET status = 'done', finished_at = NOW(), locked_by = NULL, \
+                locked_at = NULL, last_error = NULL \
+         WHERE id = $1 AND status = 'running' AND locked_by = $2",
+    )
+    .bind(id)
+    .bind(worker)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Releases a job claimed by the worker to be run again at `run_at`.
+pub async fn retry_job(
+    id: i64,
+    worker: &str,
+    error: &str,
+    run_at: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE jobs SET status = 'pending', run_at = $4, last_error = $3, \
+                locked_by = NULL, locked_at = NULL \
+         WHERE id = $1 AND status = 'running' AND locked_by = $2",
+    )
+    .bind(id)
+    .bind(worker)
+    .bind(error)
+    .bind(run_at)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Gives up a job claimed by the worker.
+pub async fn fail_job(id: i64, worker: &str, error: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE jobs SET status = 'failed', finished_at = NOW(), last_error = $3, \
+                locked_by = NULL, locked_at = NULL \
+         WHERE id = $1 AND status = 'running' AND locked_by = $2",
+    )
+    .bind(id)
+    .bind(worker)
+    .bind(error)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Runs a failed job again with a fresh set of attempts.
+pub async fn retry_failed_job(id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE jobs SET status = 'pending', attempts = 0, run_at = NOW(), finished_at = NULL \
+         WHERE id = $1 AND status = 'failed'",
+    )
+    .bind(id)
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lists jobs, most recently due first.
+pub async fn list_jobs(
+    status: Option<JobStatus>,
+    limit: i64,
+) -> Result<Vec<JobRecord>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, kind, payload, status, attempts, max_attempts, run_at, locked_by, \
+                last_error, created_at, finished_at \
+         FROM jobs WHERE ($1::TEXT IS NULL OR status = $1) \
+         ORDER BY run_at DESC, id DESC LIMIT $2",
+    )
+    .bind(status.map(JobStatus::as_str))
+    .bind(limit)
+    .fetch_all(&pool())
+    .await
+}
+
+pub async fn delete_finished_jobs_before(cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM jobs WHERE status IN ('done', 'failed') AND finished_at < $1")
+            .bind(cutoff)
+            .execute(&pool())
+            .await?;
+    Ok(result.rows_affected())
+}
+
+// Maintenance
+
+/// First key of the advisory locks of maintenance tasks, the second one is
+/// derived from the task name.
+pub const MAINTENANCE_LOCK_NAMESPACE: i32 = 0x6d61_696e;
+
+/// Runs `run` unless another instance holds the lock of the task or already
+/// ran it successfully in the current slot of the interval. The lock is
+/// taken on a connection outside of the pool, which releases it when closed,
+/// so that the pool stays available to the task.
+pub async fn lead_maintenance<F>(
+    task: &str,
+    instance: &str,
+    interval: Duration,
+    run: F,
+) -> Result<maintenance::Outcome, sqlx::Error>
+where
+    F: Future<Output = Result<u64, String>>,
+{
+    let mut lock = PgConnection::connect_with(&pool().connect_options()).await?;
+    let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1, hashtext($2))")
+        .bind(MAINTENANCE_LOCK_NAMESPACE)
+        .bind(task)
+        .fetch_one(&mut lock)
+        .await?;
+    let outcome = if locked {
+        lead_locked_maintenance(task, instance, interval, run).await
+    } else {
+        Ok(maintenance::Outcome::Busy)
+    };
+    lock.close().await?;
+    outcome
+}
+
+async fn lead_locked_maintenance<F>(
+    task: &str,
+    instance: &str,
+    interval: Duration,
+    run: F,
+) -> Result<maintenance::Outcome, sqlx::Error>
+where
+    F: Future<Output = Result<u64, String>>,
+{
+    let (slot,): (i64,) =
+        sqlx::query_as("SELECT CAST(FLOOR(EXTRACT(EPOCH FROM NOW()) / $1) AS BIGINT)")
+            .bind(interval.as_secs_f64().max(1.0))
+            .fetch_one(&pool())
+            .await?;
+    let done: Option<(String,)> = sqlx::query_as(
+        "SELECT instance FROM maintenance_runs WHERE task = $1 AND slot >= $2 AND error IS NULL",
+    )
+    .bind(task)
+    .bind(slot)
+    .fetch_optional(&pool())
+    .await?;
+    if let Some((instance,)) = done {
+        return Ok(maintenance::Outcome::Done { instance });
+    }
+
+    let result = run.await;
+    sqlx::query(
+        "INSERT INTO maintenance_runs (task, slot, instance, finished_at, affected, error) \
+         VALUES ($1, $2, $3, NOW(), $4, $5) \
+         ON CONFLICT (task) DO UPDATE SET slot = EXCLUDED.slot, instance = EXCLUDED.instance, \
+             finished_at = EXCLUDED.finished_at, affected = EXCLUDED.affected, \
+             error = EXCLUDED.error",
+    )
+    .bind(task)
+    .bind(slot)
+    .bind(instance)
+    .bind(
+        result
+            .as_ref()
+            .ok()
+            .map(|&n| i64::try_from(n).unwrap_or(i64::MAX)),
+    )
+    .bind(result.as_ref().err())
+    .execute(&pool())
+    .await?;
+    Ok(maintenance::Outcome::Ran(result))
+}
+
+// Rate limits
+
+/// Refills the token bucket of a client for a policy by `rate` tokens per
+/// second up to `burst`, takes a token if there is one and returns the
+/// tokens left and whether one was taken. Missing buckets start full. Time
+/// is measured by the database clock, so all replicas agree on it.
+pub async fn take_rate_limit_token(
+    client: &str,
+    name: &str,
+    burst: f64,
+    rate: f64,
+) -> Result<(f64, bool), sqlx::Error> {
+    sqlx::query_as(
+        "INSERT INTO rate_limit_buckets AS b (client, name, tokens, allowed, updated_at) \
+         VALUES ($1, $2, $3 - 1, TRUE, NOW()) \
+         ON CONFLICT (client, name) DO UPDATE SET (tokens, allowed, updated_at) = ( \
+             SELECT CASE WHEN refilled >= 1 THEN refilled - 1 ELSE refilled END, \
+                 refilled >= 1, NOW() \
+             FROM (SELECT LEAST($3, b.tokens + \
+                 CAST(EXTRACT(EPOCH FROM NOW() - b.updated_at) AS DOUBLE PRECISION) * $4) \
+                 AS refilled) AS refill) \
+         RETURNING tokens, allowed",
+    )
+    .bind(client)
+    .bind(name)
+    .bind(burst)
+    .bind(rate)
+    .fetch_one(&pool())
+    .await
+}
+
+/// Deletes buckets which have not been used for `idle`. Buckets idle for
+/// longer than it takes to refill them behave like new ones.
+pub async fn delete_idle_rate_limit_buckets(idle: Duration) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM rate_limit_buckets WHERE updated_at < NOW() - make_interval(secs => $1)",
+    )
+    .bind(idle.as_secs_f64())
+    .execute(&pool())
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// Audit events
+
+pub async fn insert_audit_event(
+    event: EventType,
+    outcome: Outcome,
+    actor: Option<&str>,
+    ctx: &audit::Context,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO audit_events (event_type, outcome, actor, ip_address, user_agent) \
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(event.as_str())
+    .bind(outcome.as_str())
+    .bind(actor)
+    .bind(ctx.ip.map(|ip| ip.to_string()))
+    .bind(ctx.user_agent.as_deref())
+    .execute(&pool())
+    .await?;
+    Ok(())
+}
+
+pub async fn list_audit_events(filter: &audit::Filter) -> Result<Vec<AuditEvent>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT id, created_at, event_type, outcome, actor, ip_address, user_agent \
+         FROM audit_events \
+         WHERE ($1::TEXT IS NULL OR LOWER(actor) = LOWER($1)) \
+           AND ($2::TEXT IS NULL OR event_type = $2) \
+           AND ($3::TIMESTAMPTZ IS NULL OR created_at >= $3) \
+         ORDER BY created_at DESC, id DESC \
+         LIMIT $4",
+    )
+    .bind(filter.actor.as_deref())
+    .bind(filter.event_type.map(EventType::as_str))
+    .bind(filter.since)
+    .bind(filter.limit)
+    .fetch_all(&pool())
+    .await
+}
+
+/// Daily counts of audit events since the given day, most recent first.
+pub async fn audit_event_summary(
+    since: Option<DateTime<Utc>>,
+) -> Result<Vec<audit::Summary>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT day, event_type, outcome, events, actors, ip_addresses \
+         FROM audit_event_summary \
+         WHERE $1::TIMESTAMPTZ IS NULL OR day >= date_trunc('day', $1::TIMESTAMPTZ) \
+         ORDER BY day DESC, event_type, outcome",
+    )
+    .bind(since)
+    .fetch_all(&pool())
+    .await
+}
+
+pub async fn delete_audit_events_before(cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM audit_events WHERE created_at < $1")
+        .bind(cutoff)
+        .execute(&pool())
         .await?;
     Ok(result.rows_affected())
 }
@@ -108,70 +1354,376 @@ pub async fn delete_expired_sessions() -> Result<u64, sqlx::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing;
+    use std::collections::HashMap;
 
-    async fn setup() {
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set for tests");
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(&database_url)
-            .await
-            .expect("failed to connect to test database");
-        // Drop existing tables and migration state to apply fresh schema
-        sqlx::query("DROP TABLE IF EXISTS sessions")
-            .execute(&pool)
-            .await
-            .unwrap();
-        sqlx::query("DROP TABLE IF EXISTS users")
-            .execute(&pool)
+    fn config(vars: &[(&str, &str)]) -> Result<Config, sqlx::Error> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Config::from_vars(|key| vars.get(key).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn config_defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.url, "postgres://localhost/webapp");
+        assert_eq!(config.max_connections, 5);
+        assert_eq!(config.acquire_timeout, Duration::from_secs(30));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(config.statement_timeout, None);
+        assert!(config.ssl_mode.is_none());
+        assert_eq!(config.connect_retries, 5);
+    }
+
+    #[test]
+    fn config_from_vars() {
+        let config = config(&[
+            ("DATABASE_URL", "postgres://db/app"),
+            ("DATABASE_MAX_CONNECTIONS", "20"),
+            ("DATABASE_ACQUIRE_TIMEOUT_SECS", "3"),
+            ("DATABASE_IDLE_TIMEOUT_SECS", "0"),
+            ("DATABASE_STATEMENT_TIMEOUT_MS", "1500"),
+            ("DATABASE_SSL_MODE", "verify-full"),
+            ("DATABASE_CONNECT_RETRIES", "0"),
+        ])
+        .unwrap();
+        assert_eq!(config.url, "postgres://db/app");
+        assert_eq!(config.max_connections, 20);
+        assert_eq!(config.acquire_timeout, Duration::from_secs(3));
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.statement_timeout, Some(Duration::from_millis(1500)));
+        assert!(matches!(config.ssl_mode, Some(PgSslMode::VerifyFull)));
+        assert_eq!(config.connect_retries, 0);
+        assert!(config.connect_options().is_ok());
+    }
+
+    #[test]
+    fn config_rejects_invalid_values() {
+        assert!(config(&[("DATABASE_MAX_CONNECTIONS", "many")]).is_err());
+        assert!(config(&[("DATABASE_SSL_MODE", "sometimes")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn database_operations() {
+        testing::isolated(async {
+            // User creation and lookup
+            create_user("alice", "$argon2id$hash").await.unwrap();
+            assert!(user_exists("alice").await.unwrap());
+            assert!(!user_exists("bob").await.unwrap());
+
+            // Lookups are case-insensitive and confusables collide
+            assert!(user_exists("ALICE").await.unwrap());
+            assert!(user_exists("\u{430}lice").await.unwrap());
+            assert!(create_user("Alice", "$argon2id$hash").await.is_err());
+            let (name, _) = get_credentials("Alice").await.unwrap().unwrap();
+            assert_eq!(name, "alice");
+
+            // Password hash retrieval
+            let hash = get_password_hash("alice").await.unwrap();
+            assert_eq!(hash.as_deref(), Some("$argon2id$hash"));
+            assert!(get_password_hash("nobody").await.unwrap().is_none());
+
+            // Session lifecycle
+            let expires = Utc::now() + chrono::Duration::hours(1);
+            create_session("tok1", "alice", expires).await.unwrap();
+            assert!(session_exists("tok1").await.unwrap());
+
+            let updated = update_session("tok1", "tok2", expires).await.unwrap();
+            assert!(updated);
+            assert!(!session_exists("tok1").await.unwrap());
+            assert!(session_exists("tok2").await.unwrap());
+
+            let deleted = delete_session("tok2").await.unwrap();
+            assert!(deleted);
+            assert!(!session_exists("tok2").await.unwrap());
+
+            // Deleting nonexistent session returns false
+            assert!(!delete_session("nonexistent").await.unwrap());
+
+            // Expired session cleanup
+            let past = Utc::now() - chrono::Duration::hours(1);
+            create_session("expired_tok", "alice", past).await.unwrap();
+            let count = delete_expired_sessions().await.unwrap();
+            assert!(count > 0);
+            assert!(!session_exists("expired_tok").await.unwrap());
+
+            // Administration
+            create_user("carol", "$argon2id$carol").await.unwrap();
+            create_session("carol1", "carol", expires).await.unwrap();
+            create_session("carol2", "carol", expires).await.unwrap();
+            let sessions = list_sessions(Some("Carol")).await.unwrap();
+            assert_eq!(sessions.len(), 2);
+            assert!(sessions.iter().all(|s| s.username == "carol"));
+            let users = list_users().await.unwrap();
+            let carol = users.iter().find(|u| u.username == "carol").unwrap();
+            assert_eq!(carol.sessions, 2);
+            assert!(carol.disabled_at.is_none());
+
+            assert!(set_password_hash("carol", "$argon2id$new").await.unwrap());
+            assert!(!set_password_hash("nobody", "$argon2id$new").await.unwrap());
+
+            assert_eq!(delete_user_sessions("carol").await.unwrap(), 2);
+            create_session("carol4", "carol", expires).await.unwrap();
+            assert!(delete_user("carol").await.unwrap());
+            assert!(!delete_user("carol").await.unwrap());
+            assert!(!user_exists("carol").await.unwrap());
+            assert!(!session_exists("carol4").await.unwrap());
+
+            // Audit events
+            let ctx = audit::Context {
+                ip: Some([10, 0, 0, 1].into()),
+                user_agent: Some("test-agent".into()),
+            };
+            insert_audit_event(EventType::Login, Outcome::Success, Some("alice"), &ctx)
+                .await
+                .unwrap();
+            insert_audit_event(EventType::Login, Outcome::Failure, Some("bob"), &ctx)
+                .await
+                .unwrap();
+            let events = list_audit_events(&audit::Filter {
+                actor: Some("alice".into()),
+                ..Default::default()
+            })
             .await
             .unwrap();
-        sqlx::query("DROP TABLE IF EXISTS _sqlx_migrations")
-            .execute(&pool)
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event_type, "login");
+            assert_eq!(events[0].outcome, "success");
+            assert_eq!(events[0].ip_address.as_deref(), Some("10.0.0.1"));
+            assert_eq!(events[0].user_agent.as_deref(), Some("test-agent"));
+            let events = list_audit_events(&audit::Filter {
+                event_type: Some(EventType::Logout),
+                ..Default::default()
+            })
             .await
             .unwrap();
-        sqlx::migrate!()
-            .run(&pool)
-            .await
-            .expect("failed to run migrations");
-        let _ = POOL.set(pool);
+            assert!(events.is_empty());
+            let summary = audit_event_summary(None).await.unwrap();
+            let counts: Vec<_> = summary
+                .iter()
+                .map(|s| (s.event_type.as_str(), s.outcome.as_str(), s.events))
+                .collect();
+            assert_eq!(counts, [("login", "failure", 1), ("login", "success", 1)]);
+            assert!(
+                audit_event_summary(Some(Utc::now() + chrono::Duration::days(2)))
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+
+            // Audit retention
+            assert_eq!(
+                delete_audit_events_before(Utc::now() - chrono::Duration::hours(1))
+                    .await
+                    .unwrap(),
+                0
+            );
+            assert_eq!(
+                delete_audit_events_before(Utc::now() + chrono::Duration::hours(1))
+                    .await
+                    .unwrap(),
+                2
+            );
+        })
+        .await;
     }
 
     #[tokio::test]
-    async fn database_operations() {
-        setup().await;
-
-        // User creation and lookup
-        create_user("alice", "$argon2id$hash").await.unwrap();
-        assert!(user_exists("alice").await.unwrap());
-        assert!(!user_exists("bob").await.unwrap());
-
-        // Password hash retrieval
-        let hash = get_password_hash("alice").await.unwrap();
-        assert_eq!(hash.as_deref(), Some("$argon2id$hash"));
-        assert!(get_password_hash("nobody").await.unwrap().is_none());
-
-        // Session lifecycle
-        let expires = Utc::now() + chrono::Duration::hours(1);
-        create_session("tok1", "alice", expires).await.unwrap();
-        assert!(session_exists("tok1").await.unwrap());
-
-        let updated = update_session("tok1", "tok2", expires).await.unwrap();
-        assert!(updated);
-        assert!(!session_exists("tok1").await.unwrap());
-        assert!(session_exists("tok2").await.unwrap());
-
-        let deleted = delete_session("tok2").await.unwrap();
-        assert!(deleted);
-        assert!(!session_exists("tok2").await.unwrap());
-
-        // Deleting nonexistent session returns false
-        assert!(!delete_session("nonexistent").await.unwrap());
-
-        // Expired session cleanup
-        let past = Utc::now() - chrono::Duration::hours(1);
-        create_session("expired_tok", "alice", past).await.unwrap();
-        let count = delete_expired_sessions().await.unwrap();
-        assert!(count > 0);
-        assert!(!session_exists("expired_tok").await.unwrap());
+    async fn usernames_are_backfilled() {
+        testing::isolated(async {
+            // Rows as left by the SQL migration
+            for name in ["Erin", "Straße"] {
+                sqlx::query(
+                    "INSERT INTO users (username, username_folded, password_hash) \
+                     VALUES ($1, LOWER($1), '$argon2id$x')",
+                )
+                .bind(name)
+                .execute(&pool())
+                .await
+                .unwrap();
+            }
+            create_user("strasse", "$argon2id$strasse").await.unwrap();
+
+            assert_eq!(backfill_usernames(&pool()).await.unwrap(), ["Straße"]);
+            assert!(user_exists("ERIN").await.unwrap());
+            let (skeleton,): (Option<String>,) =
+                sqlx::query_as("SELECT username_skeleton FROM users WHERE username = 'Erin'")
+                    .fetch_one(&pool())
+                    .await
+                    .unwrap();
+            assert_eq!(skeleton, Some(username::skeleton("Erin")));
+
+            // Conflicts are reported until the user is renamed
+            assert_eq!(backfill_usernames(&pool()).await.unwrap(), ["Straße"]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn account_status_lifecycle() {
+        testing::isolated(async {
+            let expires = Utc::now() + chrono::Duration::hours(1);
+            create_user("dave", "$argon2id$dave").await.unwrap();
+            assert_eq!(
+                account_status("Dave").await.unwrap(),
+                Some(AccountStatus::Active)
+            );
+            assert_eq!(account_status("nobody").await.unwrap(), None);
+
+            // Disabling revokes sessions immediately
+            create_session("dave1", "dave", expires).await.unwrap();
+            assert!(disable_user("dave").await.unwrap());
+            assert!(!disable_user("dave").await.unwrap());
+            assert!(!session_exists("dave1").await.unwrap());
+            assert_eq!(
+                account_status("dave").await.unwrap(),
+                Some(AccountStatus::Disabled)
+            );
+            assert!(get_credentials("dave").await.unwrap().is_some());
+
+            assert!(enable_user("dave").await.unwrap());
+            assert!(!enable_user("dave").await.unwrap());
+            assert_eq!(
+                account_status("dave").await.unwrap(),
+                Some(AccountStatus::Active)
+            );
+
+            // Soft deletion hides credentials but keeps the username taken
+            create_session("dave2", "dave", expires).await.unwrap();
+            assert!(soft_delete_user("dave").await.unwrap());
+            assert!(!soft_delete_user("dave").await.unwrap());
+            assert!(!session_exists("dave2").await.unwrap());
+            assert_eq!(
+                account_status("dave").await.unwrap(),
+                Some(AccountStatus::Deleted)
+            );
+            assert!(get_credentials("dave").await.unwrap().is_none());
+            assert!(user_exists("dave").await.unwrap());
+            assert!(!enable_user("dave").await.unwrap());
+            assert!(!disable_user("dave").await.unwrap());
+
+            // Purging only removes users deleted before the cutoff
+            let hour = chrono::Duration::hours(1);
+            assert_eq!(purge_deleted_users(Utc::now() - hour).await.unwrap(), 0);
+            assert_eq!(purge_deleted_users(Utc::now() + hour).await.unwrap(), 1);
+            assert!(!user_exists("dave").await.unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn organizations_and_invitations() {
+        testing::isolated(async {
+            let expires = Utc::now() + chrono::Duration::days(1);
+            create_user("olga", "$argon2id$olga").await.unwrap();
+            create_user("pete", "$argon2id$pete").await.unwrap();
+
+            let org = create_organization("Acme", "Olga").await.unwrap();
+            assert_eq!(
+                membership_role(org, "olga").await.unwrap(),
+                Some(Role::Owner)
+            );
+            assert_eq!(membership_role(org, "pete").await.unwrap(), None);
+
+            // Existing users accept invitations exactly once
+            create_invitation("inv-pete", org, Role::Admin, "olga", expires)
+                .await
+                .unwrap();
+            assert_eq!(
+                accept_invitation("inv-pete", "Pete").await.unwrap(),
+                Some(org)
+            );
+            assert_eq!(accept_invitation("inv-pete", "pete").await.unwrap(), None);
+            assert_eq!(
+                membership_role(org, "pete").await.unwrap(),
+                Some(Role::Admin)
+            );
+            let memberships = list_memberships("pete").await.unwrap();
+            assert_eq!(memberships.len(), 1);
+            assert_eq!(memberships[0].organization, "Acme");
+
+            // Accepting never changes an existing membership
+            create_invitation("inv-olga", org, Role::Member, "pete", expires)
+                .await
+                .unwrap();
+            assert_eq!(
+                accept_invitation("inv-olga", "olga").await.unwrap(),
+                Some(org)
+            );
+            assert_eq!(
+                membership_role(org, "olga").await.unwrap(),
+                Some(Role::Owner)
+            );
+
+            // New users join while registering, invalid invitations roll back
+            create_invitation("inv-quinn", org, Role::Member, "olga", expires)
+                .await
+                .unwrap();
+            assert!(
+                create_user_with_invitation("quinn", "$argon2id$quinn", "inv-quinn")
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                membership_role(org, "quinn").await.unwrap(),
+                Some(Role::Member)
+            );
+            assert!(
+                !create_user_with_invitation("rita", "$argon2id$rita", "inv-quinn")
+                    .await
+                    .unwrap()
+            );
+            assert!(!user_exists("rita").await.unwrap());
+
+            // Expired invitations are rejected
+            let expired = Utc::now() - chrono::Duration::hours(1);
+            create_invitation("inv-old", org, Role::Member, "olga", expired)
+                .await
+                .unwrap();
+            assert_eq!(accept_invitation("inv-old", "quinn").await.unwrap(), None);
+
+            assert_eq!(list_invitations("olga").await.unwrap().len(), 4);
+            assert_eq!(list_invitations("quinn").await.unwrap().len(), 1);
+
+            // Memberships go away with the user
+            assert!(delete_user("pete").await.unwrap());
+            assert_eq!(membership_role(org, "pete").await.unwrap(), None);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn profiles() {
+        testing::isolated(async {
+            create_user("sam", "$argon2id$sam").await.unwrap();
+            let profile = get_profile("Sam").await.unwrap().unwrap();
+            assert_eq!(profile.username, "sam");
+            assert_eq!(profile.display_name, None);
+            assert_eq!(profile.timezone, crate::profile::DEFAULT_TIMEZONE);
+            assert!(get_profile("nobody").await.unwrap().is_none());
+
+            let update = ProfileUpdate::new("Sam S.", "Hi", "Europe/Berlin", "de").unwrap();
+            assert!(update_profile("sam", &update).await.unwrap());
+            assert!(!update_profile("nobody", &update).await.unwrap());
+            let profile = get_profile("sam").await.unwrap().unwrap();
+            assert_eq!(profile.display_name.as_deref(), Some("Sam S."));
+            assert_eq!(profile.locale, "de");
+
+            // Avatars are replaced independently of the other fields
+            assert_eq!(set_avatar("sam", Some("a.png")).await.unwrap(), None);
+            assert_eq!(
+                set_avatar("sam", Some("b.png")).await.unwrap().as_deref(),
+                Some("a.png")
+            );
+            let profile = get_profile("sam").await.unwrap().unwrap();
+            assert_eq!(profile.avatar.as_deref(), Some("b.png"));
+            assert_eq!(profile.bio.as_deref(), Some("Hi"));
+
+            assert!(soft_delete_user("sam").await.unwrap());
+            let later = Utc::now() + chrono::Duration::hours(1);
+            assert_eq!(deleted_user_avatars(later).await.unwrap(), ["b.png"]);
+            assert_eq!(purge_deleted_users(later).await.unwrap(), 1);
+            assert!(deleted_user_avatars(later).await.unwrap().is_empty());
+        })
+        .await;
     }
 }
@@ -0,0 +1,290 @@
+//! Data export for answering data-subject access requests.
+//!
+//! The export is a single JSON document. Its schema is versioned by
+//! `schema_version` and only changes in backwards compatible ways (adding
+//! fields or sections) without a version bump:
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "generated_at": "<RFC 3339 timestamp>",
+//!   "account": {
+//!     "username": "<string>",
+//!     "created_at": "<RFC 3339 timestamp>",
+//!     "disabled_at": "<RFC 3339 timestamp or null>",
+//!     "deleted_at": "<RFC 3339 timestamp or null>"
+//!   },
+//!   "profile": {
+//!     "username": "<string>",
+//!     "display_name": "<string or null>",
+//!     "bio": "<string or null>",
+//!     "timezone": "<IANA time zone name>",
+//!     "locale": "<BCP 47 language tag>",
+//!     "avatar": "<file name below /avatars/ or null>"
+//!   },
+//!   "sessions": [
+//!     { "created_at": "<RFC 3339 timestamp>", "expires_at": "<RFC 3339 timestamp>" }
+//!   ],
+//!   "memberships": [
+//!     {
+//!       "organization_id": <integer>,
+//!       "organization": "<string>",
+//!       "role": "owner" | "admin" | "member",
+//!       "created_at": "<RFC 3339 timestamp>"
+//!     }
+//!   ],
+//!   "invitations": [
+//!     {
+//!       "organization_id": <integer>,
+//!       "organization": "<string>",
+//!       "role": "admin" | "member",
+//!       "invited_by": "<string or null>",
+//!       "created_at": "<RFC 3339 timestamp>",
+//!       "expires_at": "<RFC 3339 timestamp>",
+//!       "accepted_by": "<string or null>",
+//!       "accepted_at": "<RFC 3339 timestamp or null>"
+//!     }
+//!   ],
+//!   "notes": [
+//!     {
+//!       "id": <integer>,
+//!       "title": "<string>",
+//!       "body": "<string>",
+//!       "created_at": "<RFC 3339 timestamp>",
+//!       "updated_at": "<RFC 3339 timestamp>"
+//!     }
+//!   ],
+//!   "attachments": [
+//!     {
+//!       "id": <integer>,
+//!       "filename": "<string>",
+//!       "content_type": "<MIME type>",
+//!       "size": <integer, bytes>,
+//!       "created_at": "<RFC 3339 timestamp>"
+//!     }
+//!   ],
+//!   "audit_events": [
+//!     {
+//!       "id": <integer>,
+//!       "created_at": "<RFC 3339 timestamp>",
+//!       "event_type": "<string>",
+//!       "outcome": "success" | "failure",
+//!       "actor": "<string or null>",
+//!       "ip_address": "<string or null>",
+//!       "user_agent": "<string or null>"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Session and invitation tokens are credentials and therefore only exported
+//! as metadata. Invitations are those sent or accepted by the user.
+//! Attachments are listed without their contents, which are downloaded from
+//! `/attachments/<id>`.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    attachments::Attachment,
+    audit::{self, AuditEvent},
+    database::{self, Invitation, Membership},
+    notes::Note,
+    profile::Profile,
+};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Tables holding user-linked data. Every one of them must be covered by
+/// [`collect`].
+pub const COVERED_TABLES: &[&str] = &[
+    "attachments",
+    "audit_events",
+    "invitations",
+    "memberships",
+    "notes",
+    "profiles",
+    "sessions",
+    "users",
+];
+
+#[derive(Debug, Serialize)]
+pub struct Export {
+    pub schema_version: u32,
+    pub generated_at: DateTime<Utc>,
+    pub account: Account,
+    pub profile: Profile,
+    pub sessions: Vec<SessionMetadata>,
+    pub memberships: Vec<Membership>,
+    pub invitations: Vec<Invitation>,
+    pub notes: Vec<Note>,
+    pub attachments: Vec<Attachment>,
+    pub audit_events: Vec<AuditEvent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Account {
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionMetadata {
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Collects everything stored about a user, or `None` if the user does not
+/// exist.
+pub async fn collect(username: &str) -> Result<Option<Export>, sqlx::Error> {
+    let Some(user) = database::get_user(username).await? else {
+        return Ok(None);
+    };
+
+    let Some(profile) = database::get_profile(&user.username).await? else {
+        return Ok(None);
+    };
+
+    let sessions = database::list_sessions(Some(&user.username))
+        .await?
+        .into_iter()
+        .map(|s| SessionMetadata {
+            created_at: s.created_at,
+            expires_at: s.expires_at,
+        })
+        .collect();
+
+    let memberships = database::list_memberships(&user.username).await?;
+    let invitations = database::list_invitations(&user.username).await?;
+    let (notes, _) = database::list_notes(&user.username, i64::MAX, 0).await?;
+    let attachments = database::list_attachments(&user.username).await?;
+    let audit_events = database::list_audit_events(&audit::Filter {
+        actor: Some(user.username.clone()),
+        limit: i64::MAX,
+        ..Default::default()
+    })
+    .await?;
+
+    Ok(Some(Export {
+        schema_version: SCHEMA_VERSION,
+        generated_at: Utc::now(),
+        account: Account {
+            username: user.username,
+            created_at: user.created_at,
+            disabled_at: user.disabled_at,
+            deleted_at: user.deleted_at,
+        },
+        profile,
+        sessions,
+        memberships,
+        invitations,
+        notes,
+        attachments,
+        audit_events,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::{EventType, Outcome},
+        organizations::Role,
+        testing,
+    };
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn export_contains_user_data() {
+        testing::isolated(async {
+            database::create_user("erin", "$argon2id$erin")
+                .await
+                .unwrap();
+            let expires = Utc::now() + chrono::Duration::hours(1);
+            database::create_session("erin1", "erin", expires)
+                .await
+                .unwrap();
+            let org = database::create_organization("Erin's", "erin")
+                .await
+                .unwrap();
+            database::create_invitation("inv1", org, Role::Member, "erin", expires)
+                .await
+                .unwrap();
+            database::create_note("erin", "Groceries", "Milk")
+                .await
+                .unwrap();
+            let digest = "0".repeat(64);
+            database::create_attachment(
+                "erin",
+                "list.txt",
+                "text/plain",
+                &digest,
+                4,
+                i64::MAX,
+                async { Ok(()) },
+            )
+            .await
+            .unwrap();
+            let ctx = audit::Context::default();
+            database::insert_audit_event(EventType::Login, Outcome::Success, Some("erin"), &ctx)
+                .await
+                .unwrap();
+            database::insert_audit_event(EventType::Login, Outcome::Success, Some("frank"), &ctx)
+                .await
+                .unwrap();
+
+            let export = collect("Erin").await.unwrap().unwrap();
+            assert_eq!(export.schema_version, SCHEMA_VERSION);
+            assert_eq!(export.account.username, "erin");
+            assert_eq!(export.profile.username, "erin");
+            assert_eq!(export.sessions.len(), 1);
+            assert_eq!(export.memberships.len(), 1);
+            assert_eq!(export.memberships[0].role, Role::Owner);
+            assert_eq!(export.invitations.len(), 1);
+            assert_eq!(export.notes[0].title, "Groceries");
+            assert_eq!(export.attachments[0].filename, "list.txt");
+            assert_eq!(export.audit_events.len(), 1);
+
+            let json = serde_json::to_value(&export).unwrap();
+            assert!(json["sessions"][0].get("token").is_none());
+            assert!(json["invitations"][0].get("token").is_none());
+            assert_eq!(json["audit_events"][0]["actor"], "erin");
+
+            assert!(collect("nobody").await.unwrap().is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn export_covers_all_user_linked_tables() {
+        testing::isolated(async {
+            let tables: Vec<(String,)> = sqlx::query_as(
+                "SELECT c.table_name::TEXT FROM information_schema.columns c \
+                 JOIN information_schema.tables t \
+                   ON t.table_schema = c.table_schema AND t.table_name = c.table_name \
+                 WHERE c.table_schema = current_schema() AND t.table_type = 'BASE TABLE' \
+                   AND c.column_name IN ('username', 'actor') \
+                 UNION \
+                 SELECT tc.table_name::TEXT FROM information_schema.table_constraints tc \
+                 JOIN information_schema.constraint_column_usage ccu \
+                   ON ccu.constraint_schema = tc.constraint_schema \
+                  AND ccu.constraint_name = tc.constraint_name \
+                 WHERE tc.table_schema = current_schema() \
+                   AND tc.constraint_type = 'FOREIGN KEY' AND ccu.table_name = 'users'",
+            )
+            .fetch_all(&database::pool())
+            .await
+            .unwrap();
+
+            let linked: BTreeSet<_> = tables.into_iter().map(|(t,)| t).collect();
+            let covered: BTreeSet<_> = COVERED_TABLES.iter().map(|t| t.to_string()).collect();
+            assert_eq!(
+                linked, covered,
+                "update the export for new user-linked tables"
+            );
+        })
+        .await;
+    }
+}
@@ -0,0 +1,111 @@
+use axum::{Json, http::StatusCode};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Instant};
+
+use crate::{
+    database, jobs,
+    maintenance::{self, TaskMetrics},
+    migrations,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    /// Identifies the reporting instance among replicas
+    pub instance: &'static str,
+    pub database: DatabaseReport,
+    /// Maintenance tasks as seen by this instance
+    pub maintenance: BTreeMap<&'static str, TaskMetrics>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Reachable, but with pending migrations
+    Degraded,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseReport {
+    pub reachable: bool,
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+    pub pool: PoolReport,
+    pub migrations: Option<MigrationReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolReport {
+    pub size: u32,
+    pub idle: usize,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationReport {
+    pub version: Option<i64>,
+    pub pending: usize,
+}
+
+/// Liveness check which only verifies that the database answers queries.
+pub async fn live() -> StatusCode {
+    match sqlx::query("SELECT 1").execute(&database::pool()).await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Detailed health report including pool utilization, migration state and
+/// query latency.
+pub async fn details() -> (StatusCode, Json<Report>) {
+    let report = report().await;
+    let code = match report.status {
+        Status::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Ok | Status::Degraded => StatusCode::OK,
+    };
+    (code, Json(report))
+}
+
+pub async fn report() -> Report {
+    let pool = &database::pool();
+
+    let start = Instant::now();
+    let query = sqlx::query("SELECT 1").execute(pool).await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let migrations = match &query {
+        Ok(_) => migrations::status(pool)
+            .await
+            .ok()
+            .map(|status| MigrationReport {
+                version: status.iter().filter(|m| m.applied).map(|m| m.version).max(),
+                pending: status.iter().filter(|m| !m.applied).count(),
+            }),
+        Err(_) => None,
+    };
+
+    let status = match (&query, &migrations) {
+        (Err(_), _) => Status::Unavailable,
+        (Ok(_), Some(m)) if m.pending == 0 => Status::Ok,
+        (Ok(_), _) => Status::Degraded,
+    };
+
+    Report {
+        status,
+        instance: jobs::instance(),
+        database: DatabaseReport {
+            reachable: query.is_ok(),
+            latency_ms: query.is_ok().then_some(latency_ms),
+            error: query.err().map(|e| e.to_string()),
+            pool: PoolReport {
+                size: pool.size(),
+                idle: pool.num_idle(),
+                max_connections: pool.options().get_max_connections(),
+            },
+            migrations,
+        },
+        maintenance: maintenance::metrics(),
+    }
+}
@@ -0,0 +1,609 @@
+//! Durable background jobs stored in Postgres.
+//!
+//! Jobs are rows of the `jobs` table. Workers claim them with
+//! `FOR UPDATE SKIP LOCKED`, so any number of replicas can work on the same
+//! queue without running a job twice. A claim is a lease: jobs of crashed
+//! workers are picked up again once it expires. Failed jobs are retried with
+//! exponential backoff until they run out of attempts.
+//!
+//! Scheduled jobs are enqueued by every replica for the next occurrence of
+//! their [`Cron`] expression. The occurrence is part of a unique key, so only
+//! one job is stored per occurrence however many replicas run. Maintenance
+//! jobs additionally elect a leader per run (see [`maintenance`]).
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use crate::{audit, avatar, database, maintenance, rate_limit, storage};
+
+pub mod cron;
+
+pub use cron::Cron;
+
+pub type JobError = Box<dyn Error + Send + Sync>;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+type Handler = Box<dyn Fn(serde_json::Value) -> BoxFuture<Result<(), JobError>> + Send + Sync>;
+
+/// Jobs running longer are aborted and count as failed.
+pub const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Claims older than this are considered abandoned by a crashed worker.
+pub const LEASE: Duration = Duration::from_secs(900);
+
+/// Delay before the first retry, doubled for every further attempt.
+const BACKOFF_BASE: Duration = Duration::from_secs(10);
+const BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+/// How long idle workers wait before looking for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the next occurrences of scheduled jobs are enqueued.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval of the maintenance jobs, which run every 5 minutes.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(300);
+
+const DEFAULT_WORKERS: usize = 1;
+const DEFAULT_RETENTION_DAYS: i64 = 7;
+
+/// A kind of background work. Jobs are stored as their JSON serialization
+/// and deserialized again by the worker running them.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    /// Identifies the handler of stored jobs, so it must not change once jobs
+    /// of the kind were enqueued.
+    const KIND: &'static str;
+
+    /// Attempts before the job is given up as failed.
+    const MAX_ATTEMPTS: i32 = 5;
+
+    fn run(self) -> impl Future<Output = Result<(), JobError>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "running" => Ok(Self::Running),
+            "done" => Ok(Self::Done),
+            "failed" => Ok(Self::Failed),
+            other => Err(format!("unknown job status: {other}")),
+        }
+    }
+}
+
+impl TryFrom<String> for JobStatus {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Delay before retrying a job that failed its given attempt.
+pub fn backoff(attempt: i32) -> Duration {
+    let exponent = u32::try_from(attempt.saturating_sub(1))
+        .unwrap_or(0)
+        .min(16);
+    (BACKOFF_BASE * 2u32.pow(exponent)).min(BACKOFF_MAX)
+}
+
+/// Identifies this replica in logs, metrics and the database.
+pub fn instance() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        let host = env::var("HOSTNAME").unwrap_or_else(|_| "localhost".into());
+        format!("{host}-{}", std::process::id())
+    })
+}
+
+/// Reads `JOB_WORKERS`, the number of jobs run concurrently per replica.
+pub fn workers() -> usize {
+    env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_WORKERS)
+}
+
+/// Reads `JOB_RETENTION_DAYS`, how long finished jobs are kept, on first use.
+/// Invalid values are logged and replaced by the default.
+pub fn retention() -> chrono::Duration {
+    static DAYS: OnceLock<i64> = OnceLock::new();
+    let days = *DAYS.get_or_init(|| {
+        let Ok(v) = env::var("JOB_RETENTION_DAYS") else {
+            return DEFAULT_RETENTION_DAYS;
+        };
+        v.parse().ok().filter(|days| *days >= 0).unwrap_or_else(|| {
+            tracing::warn!(
+                "ignoring invalid JOB_RETENTION_DAYS {v}, keeping finished jobs for \
+                 {DEFAULT_RETENTION_DAYS} days"
+            );
+            DEFAULT_RETENTION_DAYS
+        })
+    });
+    chrono::Duration::days(days)
+}
+
+fn payload<J: Job>(job: &J) -> Result<serde_json::Value, sqlx::Error> {
+    serde_json::to_value(job).map_err(|e| sqlx::Error::Encode(Box::new(e)))
+}
+
+/// Enqueues a job to run as soon as a worker is free.
+pub async fn enqueue<J: Job>(job: &J) -> Result<i64, sqlx::Error> {
+    enqueue_at(job, Utc::now()).await
+}
+
+/// Enqueues a job to run at the given time.
+pub async fn enqueue_at<J: Job>(job: &J, run_at: DateTime<Utc>) -> Result<i64, sqlx::Error> {
+    database::enqueue_job(J::KIND, &payload(job)?, J::MAX_ATTEMPTS, run_at).await
+}
+
+struct Schedule {
+    kind: &'static str,
+    cron: Cron,
+    payload: serde_json::Value,
+    max_attempts: i32,
+}
+
+/// The registered job handlers and schedules of a replica.
+#[derive(Default)]
+pub struct Queue {
+    handlers: HashMap<&'static str, Handler>,
+    schedules: Vec<Schedule>,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler for jobs of type `J`. Workers only claim jobs
+    /// they have a handler for, so replicas running different versions can
+    /// share a queue.
+    pub fn register<J: Job>(mut self) -> Self {
+        self.handlers.insert(
+            J::KIND,
+            Box::new(|payload| {
+                Box::pin(async move {
+                    let job: J = serde_json::from_value(payload)?;
+                    job.run().await
+                })
+            }),
+        );
+        self
+    }
+
+    /// Registers `J` and runs the given job whenever the cron expression
+    /// matches.
+    pub fn schedule<J: Job>(mut self, cron: Cron, job: J) -> Result<Self, sqlx::Error> {
+        self.schedules.push(Schedule {
+            kind: J::KIND,
+            cron,
+            payload: payload(&job)?,
+            max_attempts: J::MAX_ATTEMPTS,
+        });
+        Ok(self.register::<J>())
+    }
+
+    /// Enqueues the next occurrence of every scheduled job after `now`,
+    /// unless another replica already did.
+    pub async fn enqueue_scheduled(&self, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        for schedule in &self.schedules {
+            let Some(run_at) = schedule.cron.next_after(now) else {
+                continue;
+            };
+            database::schedule_job(
+                schedule.kind,
+                &schedule.payload,
+                schedule.max_attempts,
+                run_at,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Claims and runs a single due job, returning whether there was one.
+    pub async fn run_next(&self, worker: &str) -> Result<bool, sqlx::Error> {
+        let kinds: Vec<&str> = self.handlers.keys().copied().collect();
+        let Some(job) = database::claim_job(&kinds, worker, LEASE).await? else {
+            return Ok(false);
+        };
+
+        // Jobs abandoned by crashed workers still count their attempts
+        let result = if job.attempts > job.max_attempts {
+            Err("attempts exhausted by abandoned runs".into())
+        } else {
+            // Spawned to catch panics, keeping the pool of the current scope
+            let run = (self.handlers[job.kind.as_str()])(job.payload);
+            let mut task = tokio::spawn(database::with_pool(database::pool(), run));
+            match tokio::time::timeout(TIMEOUT, &mut task).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => Err(format!("job panicked: {e}").into()),
+                Err(_) => {
+                    task.abort();
+                    Err(format!("job timed out after {}s", TIMEOUT.as_secs()).into())
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                database::complete_job(job.id, worker).await?;
+                tracing::debug!("job {} ({}) done", job.id, job.kind);
+            }
+            Err(e) if job.attempts < job.max_attempts => {
+                let delay = backoff(job.attempts);
+                let run_at = Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default();
+                database::retry_job(job.id, worker, &e.to_string(), run_at).await?;
+                tracing::warn!(
+                    "job {} ({}) failed attempt {} of {}, retrying in {}s: {e}",
+                    job.id,
+                    job.kind,
+                    job.attempts,
+                    job.max_attempts,
+                    delay.as_secs()
+                );
+            }
+            Err(e) => {
+                database::fail_job(job.id, worker, &e.to_string()).await?;
+                tracing::error!(
+                    "job {} ({}) failed after {} attempts: {e}",
+                    job.id,
+                    job.kind,
+                    job.attempts
+                );
+            }
+        }
+        Ok(true)
+    }
+
+    /// Spawns the scheduler and the given number of workers.
+    pub fn start(self, workers: usize) {
+        let queue = Arc::new(self);
+        let id = instance();
+
+        tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = queue.enqueue_scheduled(Utc::now()).await {
+                        tracing::warn!("failed to enqueue scheduled jobs: {e}");
+                    }
+                }
+            }
+        });
+
+        for n in 0..workers {
+            let queue = queue.clone();
+            let worker = format!("{id}-{n}");
+            tokio::spawn(async move {
+                loop {
+                    match queue.run_next(&worker).await {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(e) => tracing::warn!("failed to run jobs: {e}"),
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            });
+        }
+        tracing::info!("started {workers} job workers as {id}");
+    }
+}
+
+/// Deletes finished jobs older than the [`retention`] period.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneJobs {}
+
+impl Job for PruneJobs {
+    const KIND: &'static str = "prune_jobs";
+
+    async fn run(self) -> Result<(), JobError> {
+        let n = database::delete_finished_jobs_before(Utc::now() - retention()).await?;
+        if n > 0 {
+            tracing::info!("pruned {n} finished jobs");
+        }
+        Ok(())
+    }
+}
+
+/// Runs a maintenance task as the leader of the current interval, failing
+/// the job if the task failed so that it is retried.
+async fn maintain<F>(task: &'static str, run: F) -> Result<(), JobError>
+where
+    F: Future<Output = Result<u64, JobError>>,
+{
+    let run = async { run.await.map_err(|e| e.to_string()) };
+    match maintenance::run_once(task, instance(), MAINTENANCE_INTERVAL, run).await? {
+        maintenance::Outcome::Ran(Err(e)) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes expired login sessions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CleanupSessions {}
+
+impl Job for CleanupSessions {
+    const KIND: &'static str = "cleanup_sessions";
+
+    async fn run(self) -> Result<(), JobError> {
+        maintain(Self::KIND, async {
+            Ok(database::delete_expired_sessions().await?)
+        })
+        .await
+    }
+}
+
+/// Deletes audit events older than the audit retention period.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneAuditEvents {}
+
+impl Job for PruneAuditEvents {
+    const KIND: &'static str = "prune_audit_events";
+
+    async fn run(self) -> Result<(), JobError> {
+        maintain(Self::KIND, async {
+            let cutoff = Utc::now() - audit::retention();
+            Ok(database::delete_audit_events_before(cutoff).await?)
+        })
+        .await
+    }
+}
+
+/// Purges users deleted longer ago than the retention period, with their
+/// avatars.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeDeletedUsers {}
+
+impl Job for PurgeDeletedUsers {
+    const KIND: &'static str = "purge_deleted_users";
+
+    async fn run(self) -> Result<(), JobError> {
+        maintain(Self::KIND, async {
+            let cutoff = Utc::now() - database::deleted_user_retention();
+            for avatar in database::deleted_user_avatars(cutoff).await? {
+                if let Err(e) = storage::storage().delete(&avatar::key(&avatar)).await {
+                    tracing::warn!("failed to delete avatar {avatar}: {e}");
+                }
+            }
+            Ok(database::purge_deleted_users(cutoff).await?)
+        })
+        .await
+    }
+}
+
+/// Deletes attachment files which no attachment refers to any more.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeOrphanedBlobs {}
+
+impl Job for PurgeOrphanedBlobs {
+    const KIND: &'static str = "purge_orphaned_blobs";
+
+    async fn run(self) -> Result<(), JobError> {
+        maintain(Self::KIND, async {
+            Ok(database::purge_orphaned_blobs(storage::content_store()).await?)
+        })
+        .await
+    }
+}
+
+/// Deletes rate limit buckets which have been full for a while.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneRateLimitBuckets {}
+
+impl Job for PruneRateLimitBuckets {
+    const KIND: &'static str = "prune_rate_limit_buckets";
+
+    async fn run(self) -> Result<(), JobError> {
+        maintain(Self::KIND, async {
+            let idle = rate_limit::config().refill();
+            Ok(database::delete_idle_rate_limit_buckets(idle).await?)
+        })
+        .await
+    }
+}
+
+/// The queue with all jobs of the application and their schedules.
+pub fn queue() -> Result<Queue, Box<dyn Error>> {
+    let maintenance: Cron = "*/5 * * * *".parse()?;
+    Ok(Queue::new()
+        .schedule("17 3 * * *".parse()?, PruneJobs {})?
+        .schedule(maintenance.clone(), CleanupSessions {})?
+        .schedule(maintenance.clone(), PruneAuditEvents {})?
+        .schedule(maintenance.clone(), PurgeDeletedUsers {})?
+        .schedule(maintenance.clone(), PurgeOrphanedBlobs {})?
+        .schedule(maintenance, PruneRateLimitBuckets {})?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static FLAKY_RUNS: AtomicU32 = AtomicU32::new(0);
+
+    /// Fails until it ran the given number of times.
+    #[derive(Serialize, Deserialize)]
+    struct Flaky {
+        failures: u32,
+    }
+
+    impl Job for Flaky {
+        const KIND: &'static str = "flaky";
+        const MAX_ATTEMPTS: i32 = 3;
+
+        async fn run(self) -> Result<(), JobError> {
+            if FLAKY_RUNS.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err("flaked".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    async fn status(id: i64) -> (String, i32, Option<String>) {
+        sqlx::query_as("SELECT status, attempts, last_error FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_one(&database::pool())
+            .await
+            .unwrap()
+    }
+
+    /// Makes all pending jobs due now instead of waiting for their backoff.
+    async fn make_due() {
+        sqlx::query("UPDATE jobs SET run_at = NOW() WHERE status = 'pending'")
+            .execute(&database::pool())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(2), Duration::from_secs(20));
+        assert_eq!(backoff(4), Duration::from_secs(80));
+        assert_eq!(backoff(20), BACKOFF_MAX);
+        assert_eq!(backoff(0), BACKOFF_BASE);
+    }
+
+    #[test]
+    fn application_queue() {
+        let queue = queue().unwrap();
+        assert!(queue.handlers.contains_key(CleanupSessions::KIND));
+        assert!(queue.handlers.contains_key(PruneJobs::KIND));
+        assert!(queue.handlers.contains_key(PruneRateLimitBuckets::KIND));
+        assert_eq!(queue.schedules.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn retries_until_exhausted() {
+        testing::isolated(async {
+            let queue = Queue::new().register::<Flaky>();
+            assert!(!queue.run_next("w").await.unwrap());
+
+            FLAKY_RUNS.store(0, Ordering::SeqCst);
+            let recovers = enqueue(&Flaky { failures: 1 }).await.unwrap();
+            assert!(queue.run_next("w").await.unwrap());
+            let (state, attempts, error) = status(recovers).await;
+            assert_eq!((state.as_str(), attempts), ("pending", 1));
+            assert_eq!(error.as_deref(), Some("flaked"));
+
+            // Not due before the backoff passed
+            assert!(!queue.run_next("w").await.unwrap());
+            make_due().await;
+            assert!(queue.run_next("w").await.unwrap());
+            assert_eq!(status(recovers).await.0, "done");
+
+            FLAKY_RUNS.store(0, Ordering::SeqCst);
+            let fails = enqueue(&Flaky { failures: 10 }).await.unwrap();
+            for _ in 0..Flaky::MAX_ATTEMPTS {
+                make_due().await;
+                assert!(queue.run_next("w").await.unwrap());
+            }
+            let (state, attempts, _) = status(fails).await;
+            assert_eq!((state.as_str(), attempts), ("failed", 3));
+
+            // Failed jobs can be run again
+            assert!(database::retry_failed_job(fails).await.unwrap());
+            assert!(!database::retry_failed_job(recovers).await.unwrap());
+            assert_eq!(status(fails).await.0, "pending");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn claims_only_known_and_abandoned_jobs() {
+        testing::isolated(async {
+            let queue = Queue::new().register::<CleanupSessions>();
+            database::enqueue_job("unknown", &serde_json::json!({}), 1, Utc::now())
+                .await
+                .unwrap();
+            assert!(!queue.run_next("w").await.unwrap());
+
+            // A job claimed by a worker that went away
+            let id = enqueue(&CleanupSessions {}).await.unwrap();
+            sqlx::query(
+                "UPDATE jobs SET status = 'running', attempts = 1, locked_by = 'gone', \
+                 locked_at = NOW() - INTERVAL '1 hour' WHERE id = $1",
+            )
+            .bind(id)
+            .execute(&database::pool())
+            .await
+            .unwrap();
+            assert!(queue.run_next("w").await.unwrap());
+            assert_eq!(status(id).await, ("done".into(), 2, None));
+            // Session cleanup runs as a maintenance task
+            assert!(maintenance::metrics()[CleanupSessions::KIND].runs > 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn scheduled_jobs_are_enqueued_once() {
+        testing::isolated(async {
+            let now = Utc::now();
+            // Two replicas with the same schedule
+            for _ in 0..2 {
+                queue().unwrap().enqueue_scheduled(now).await.unwrap();
+            }
+            let jobs = database::list_jobs(Some(JobStatus::Pending), 10)
+                .await
+                .unwrap();
+            assert_eq!(jobs.len(), 6);
+            let cleanup = jobs
+                .iter()
+                .find(|j| j.kind == CleanupSessions::KIND)
+                .unwrap();
+            assert_eq!(
+                Some(cleanup.run_at),
+                "*/5 * * * *".parse::<Cron>().unwrap().next_after(now)
+            );
+        })
+        .await;
+    }
+}
@@ -0,0 +1,219 @@
+//! Cron expressions for scheduled jobs.
+//!
+//! The five fields are minute, hour, day of month, month and day of week
+//! (0 or 7 is Sunday), evaluated in UTC. Each field is `*` or a comma
+//! separated list of values, ranges `a-b` and steps `*/n`, `a-b/n` or `a/n`.
+//! As in classic cron, a day matches if either the day of month or the day of
+//! week matches when both are restricted.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use std::{fmt, str::FromStr};
+
+/// Occurrences are searched this far into the future before giving up, which
+/// covers expressions like the 29th of February.
+const SEARCH_YEARS: i64 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cron expression: {}", self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+/// Parses one field into a bit set of the matching values.
+fn field(spec: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let invalid = || CronError(format!("{spec} (expected values in {min}-{max})"));
+    let number = |s: &str| {
+        s.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(invalid)
+    };
+
+    let mut bits = 0;
+    for item in spec.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(invalid)?,
+            ),
+            None => (item, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (number(first)?, number(last)?),
+                // A single value with a step runs up to the maximum
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if first > last {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError(format!("{s} (expected 5 fields)")));
+        };
+        let mut weekday_bits = field(weekdays, 0, 7)?;
+        // Sunday is both 0 and 7
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+fn matches(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+impl Cron {
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = matches(self.days, date.day());
+        let weekday = matches(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time strictly after `after` matching the expression, or
+    /// `None` if there is none within the next years.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.naive_utc().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(366 * SEARCH_YEARS);
+        let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0);
+
+        let mut t: NaiveDateTime = start;
+        while t < limit {
+            if !matches(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = midnight(NaiveDate::from_ymd_opt(year, month, 1)?)?;
+            } else if !self.day_matches(t.date()) {
+                t = midnight(t.date().succ_opt()?)?;
+            } else if !matches(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if !matches(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t.and_utc());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        expression.parse::<Cron>().unwrap().next_after(at(after))
+    }
+
+    #[test]
+    fn parse_expressions() {
+        for valid in [
+            "* * * * *",
+            "*/5 * * * *",
+            "0 3 * * 1-5",
+            "0,30 8-18/2 1 */3 7",
+        ] {
+            assert_eq!(valid.parse::<Cron>().unwrap().to_string(), valid);
+        }
+        for invalid in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(invalid.parse::<Cron>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn next_occurrences() {
+        let t = "2024-01-31T10:02:30Z";
+        assert_eq!(next("* * * * *", t), Some(at("2024-01-31T10:03:00Z")));
+        assert_eq!(next("*/5 * * * *", t), Some(at("2024-01-31T10:05:00Z")));
+        assert_eq!(next("0 3 * * *", t), Some(at("2024-02-01T03:00:00Z")));
+        // Strictly after, even when exactly on a match
+        assert_eq!(
+            next("0 3 * * *", "2024-02-01T03:00:00Z"),
+            Some(at("2024-02-02T03:00:00Z"))
+        );
+        assert_eq!(next("0 0 1 * *", t), Some(at("2024-02-01T00:00:00Z")));
+        assert_eq!(next("0 0 29 2 *", t), Some(at("2024-02-29T00:00:00Z")));
+        assert_eq!(
+            next("0 0 29 2 *", "2024-03-01T00:00:00Z"),
+            Some(at("2028-02-29T00:00:00Z"))
+        );
+        assert_eq!(next("0 0 31 12 *", t), Some(at("2024-12-31T00:00:00Z")));
+        // 2024-01-31 is a Wednesday
+        assert_eq!(next("30 9 * * 0", t), Some(at("2024-02-04T09:30:00Z")));
+        assert_eq!(next("30 9 * * 7", t), Some(at("2024-02-04T09:30:00Z")));
+        // Day of month or day of week
+        assert_eq!(next("0 0 15 * 5", t), Some(at("2024-02-02T00:00:00Z")));
+        assert_eq!(next("0 0 30 2 *", t), None);
+    }
+}
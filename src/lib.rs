@@ -1,13 +1,46 @@
 pub mod app;
+pub mod attachments;
+#[cfg(feature = "ssr")]
+pub mod audit;
 #[cfg(feature = "ssr")]
 pub mod auth;
 #[cfg(feature = "ssr")]
+pub mod avatar;
+#[cfg(feature = "ssr")]
+pub mod cli;
+pub mod client;
+#[cfg(feature = "ssr")]
+pub mod client_ip;
 pub mod csrf;
 #[cfg(feature = "ssr")]
 pub mod database;
+#[cfg(feature = "ssr")]
+pub mod export;
+#[cfg(feature = "ssr")]
+pub mod health;
+#[cfg(feature = "ssr")]
+pub mod jobs;
+#[cfg(feature = "ssr")]
+pub mod maintenance;
+#[cfg(feature = "ssr")]
+pub mod method;
+#[cfg(feature = "ssr")]
+pub mod migrations;
+pub mod notes;
+pub mod organizations;
 pub mod pages;
+pub mod profile;
 #[cfg(feature = "ssr")]
 pub mod rate_limit;
+#[cfg(feature = "ssr")]
+pub mod route;
+pub mod search;
+#[cfg(feature = "ssr")]
+pub mod storage;
+#[cfg(all(feature = "ssr", any(test, feature = "testing")))]
+pub mod testing;
+#[cfg(feature = "ssr")]
+pub mod username;
 
 #[cfg(feature = "hydrate")]
 #[wasm_bindgen::prelude::wasm_bindgen]
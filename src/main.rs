@@ -1,18 +1,65 @@
 #[cfg(feature = "ssr")]
 #[tokio::main]
 async fn main() {
-    use axum::{Router, http::StatusCode, middleware, routing::get};
+    use clap::Parser;
+    use tracing_subscriber::{EnvFilter, fmt};
+    use webapp::cli::{Cli, Command};
+
+    fmt().with_env_filter(EnvFilter::from_default_env()).init();
+
+    let cli = Cli::parse();
+    let result = match cli.command.unwrap_or_default() {
+        Command::Serve(args) => {
+            serve(args).await;
+            Ok(())
+        }
+        Command::Migrate(command) => webapp::cli::migrate(command, cli.json).await,
+        Command::User(command) => webapp::cli::user(command, cli.json).await,
+        Command::Session(command) => webapp::cli::session(command, cli.json).await,
+        Command::Job(command) => webapp::cli::job(command, cli.json).await,
+        Command::Audit(command) => webapp::cli::audit(command, cli.json).await,
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "ssr")]
+async fn serve(args: webapp::cli::ServeArgs) {
+    use axum::{
+        Router,
+        extract::DefaultBodyLimit,
+        middleware,
+        routing::{get, post},
+    };
     use leptos::prelude::*;
     use leptos_axum::{LeptosRoutes, generate_route_list};
     use tower_http::compression::CompressionLayer;
-    use tracing_subscriber::{EnvFilter, fmt};
     use webapp::app::*;
 
-    fmt().with_env_filter(EnvFilter::from_default_env()).init();
-
-    webapp::database::init()
-        .await
-        .expect("failed to initialize database");
+    if args.skip_migrations {
+        webapp::database::connect()
+            .await
+            .expect("failed to connect to database");
+        let pending = webapp::migrations::pending(&webapp::database::pool())
+            .await
+            .expect("failed to check migration status");
+        if let Some(m) = pending.first() {
+            tracing::error!(
+                "refusing to start with {} pending migrations, starting at {} {}",
+                pending.len(),
+                m.version,
+                m.description
+            );
+            std::process::exit(1);
+        }
+    } else {
+        webapp::database::init()
+            .await
+            .expect("failed to initialize database");
+    }
 
     let conf = get_configuration(None).expect("failed to load leptos configuration");
     let addr = conf.leptos_options.site_addr;
@@ -20,48 +67,51 @@ async fn main() {
     let routes = generate_route_list(App);
 
     let app = Router::new()
+        .route("/healthz", get(webapp::health::live))
+        .route("/healthz/details", get(webapp::health::details))
+        .route("/avatars/{name}", get(webapp::avatar::serve))
         .route(
-            "/healthz",
-            get(|| async {
-                match sqlx::query("SELECT 1")
-                    .execute(webapp::database::pool())
-                    .await
-                {
-                    Ok(_) => StatusCode::OK,
-                    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
-                }
-            }),
+            "/attachments",
+            post(webapp::attachments::http::upload).layer(DefaultBodyLimit::max(
+                webapp::attachments::MAX_FILE_BYTES as usize
+                    + webapp::attachments::http::MULTIPART_OVERHEAD,
+            )),
+        )
+        .route(
+            "/attachments/{id}",
+            get(webapp::attachments::http::download),
         )
         .leptos_routes(&leptos_options, routes, {
             let leptos_options = leptos_options.clone();
             move || shell(leptos_options.clone())
         })
+        .route_layer(middleware::from_fn(webapp::rate_limit::check))
         .fallback(leptos_axum::file_and_error_handler(shell))
         .layer(middleware::from_fn(webapp::csrf::validate))
-        .layer(middleware::from_fn(webapp::rate_limit::check))
+        .layer(middleware::from_fn(webapp::rate_limit::screen))
+        .layer(middleware::from_fn(webapp::client_ip::layer))
         .layer(CompressionLayer::new())
         .with_state(leptos_options);
 
-    // Periodically clean up expired sessions every 5 minutes
-    tokio::spawn(async {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
-        loop {
-            interval.tick().await;
-            match webapp::database::delete_expired_sessions().await {
-                Ok(0) => {}
-                Ok(n) => tracing::info!("cleaned up {n} expired sessions"),
-                Err(e) => tracing::warn!("failed to clean up expired sessions: {e}"),
-            }
-        }
-    });
+    webapp::jobs::queue()
+        .expect("invalid job schedule")
+        .start(webapp::jobs::workers());
+
+    // Refuse to start without the denylist, then pick up changes of the
+    // rate limit allow and deny lists
+    webapp::rate_limit::lists::reload().expect("invalid rate limit lists");
+    webapp::rate_limit::lists::watch();
 
     tracing::info!("listening on http://{addr}");
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .expect("failed to bind to address");
-    axum::serve(listener, app.into_make_service())
-        .await
-        .expect("server error");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .expect("server error");
 }
 
 #[cfg(not(feature = "ssr"))]
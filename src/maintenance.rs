@@ -0,0 +1,230 @@
+//! Leader election for periodic maintenance across replicas.
+//!
+//! Maintenance tasks run as scheduled jobs (see [`crate::jobs`]). Before
+//! running, a task takes its advisory lock on a connection of its own, so
+//! that it never runs on two replicas at once and does not keep a pooled
+//! connection busy. The interval is divided into slots aligned to the
+//! database clock, and a task which already succeeded in the current slot
+//! is skipped, e.g. when a job is retried after its lease expired. The
+//! instance which led each task is logged, recorded in the database and
+//! reported by the detailed health check.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::database;
+
+/// What happened when a replica tried to run a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// This replica led the slot and ran the task.
+    Ran(Result<u64, String>),
+    /// Another replica holds the lock and is running the task.
+    Busy,
+    /// The task already ran in the current slot on the given instance.
+    Done { instance: String },
+}
+
+/// Per-task counters of this replica, reported by the detailed health check.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskMetrics {
+    pub runs: u64,
+    pub failures: u64,
+    pub skipped: u64,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<f64>,
+    pub last_error: Option<String>,
+    /// Instance which most recently led the task, as far as this replica
+    /// knows.
+    pub leader: Option<String>,
+}
+
+static METRICS: Mutex<BTreeMap<&'static str, TaskMetrics>> = Mutex::new(BTreeMap::new());
+
+pub fn metrics() -> BTreeMap<&'static str, TaskMetrics> {
+    METRICS.lock().map(|m| m.clone()).unwrap_or_default()
+}
+
+fn record(task: &'static str, instance: &str, outcome: &Outcome, started: Instant) {
+    let Ok(mut metrics) = METRICS.lock() else {
+        return;
+    };
+    let metrics = metrics.entry(task).or_default();
+    match outcome {
+        Outcome::Ran(result) => {
+            metrics.runs += 1;
+            metrics.last_run_at = Some(Utc::now());
+            metrics.last_duration_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+            metrics.leader = Some(instance.to_owned());
+            metrics.last_error = result.as_ref().err().cloned();
+            if result.is_err() {
+                metrics.failures += 1;
+            }
+        }
+        Outcome::Busy => metrics.skipped += 1,
+        Outcome::Done { instance } => {
+            metrics.skipped += 1;
+            metrics.leader = Some(instance.clone());
+        }
+    }
+}
+
+/// Runs a task on behalf of `instance` if it becomes the task's leader for
+/// the current slot of `interval`. `run` returns the number of affected rows
+/// or files, for logging.
+pub async fn run_once<F>(
+    task: &'static str,
+    instance: &str,
+    interval: Duration,
+    run: F,
+) -> Result<Outcome, sqlx::Error>
+where
+    F: Future<Output = Result<u64, String>>,
+{
+    let started = Instant::now();
+    let outcome = database::lead_maintenance(task, instance, interval, run).await?;
+    record(task, instance, &outcome, started);
+
+    match &outcome {
+        Outcome::Ran(Ok(n)) => {
+            tracing::info!("ran maintenance task {task} as leader {instance} ({n} affected)")
+        }
+        Outcome::Ran(Err(e)) => {
+            tracing::warn!("maintenance task {task} failed on leader {instance}: {e}")
+        }
+        Outcome::Busy => {
+            tracing::debug!("skipped maintenance task {task}: lock held by another instance")
+        }
+        Outcome::Done { instance } => tracing::debug!(
+            "skipped maintenance task {task}: already ran this interval on {instance}"
+        ),
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const INSTANCE: &str = "test-instance";
+    const INTERVAL: Duration = Duration::from_secs(3600);
+
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+
+    async fn count() -> Result<u64, String> {
+        Ok(RUNS.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    #[tokio::test]
+    async fn runs_once_per_slot() {
+        testing::isolated(async {
+            // Advisory locks are shared by all test schemas, hence the
+            // unique name
+            let task = "test_runs_once_per_slot";
+            let run = || run_once(task, INSTANCE, INTERVAL, count());
+
+            // Another replica currently holding the lock
+            let mut other = database::pool().acquire().await.unwrap();
+            sqlx::query("SELECT pg_advisory_lock($1, hashtext($2))")
+                .bind(database::MAINTENANCE_LOCK_NAMESPACE)
+                .bind(task)
+                .execute(&mut *other)
+                .await
+                .unwrap();
+            assert_eq!(run().await.unwrap(), Outcome::Busy);
+            sqlx::query("SELECT pg_advisory_unlock($1, hashtext($2))")
+                .bind(database::MAINTENANCE_LOCK_NAMESPACE)
+                .bind(task)
+                .execute(&mut *other)
+                .await
+                .unwrap();
+
+            assert_eq!(run().await.unwrap(), Outcome::Ran(Ok(1)));
+            assert_eq!(
+                run().await.unwrap(),
+                Outcome::Done {
+                    instance: INSTANCE.into()
+                }
+            );
+            assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+
+            let metrics = &metrics()[task];
+            assert_eq!((metrics.runs, metrics.skipped, metrics.failures), (1, 2, 0));
+            assert_eq!(metrics.leader.as_deref(), Some(INSTANCE));
+
+            // The next slot runs again
+            sqlx::query("UPDATE maintenance_runs SET slot = slot - 1 WHERE task = $1")
+                .bind(task)
+                .execute(&database::pool())
+                .await
+                .unwrap();
+            assert_eq!(run().await.unwrap(), Outcome::Ran(Ok(2)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn failed_runs_are_retried() {
+        testing::isolated(async {
+            let task = "test_failed_runs_are_retried";
+            assert_eq!(
+                run_once(task, INSTANCE, INTERVAL, async { Err("broken".into()) })
+                    .await
+                    .unwrap(),
+                Outcome::Ran(Err("broken".into()))
+            );
+            let (error,): (Option<String>,) =
+                sqlx::query_as("SELECT error FROM maintenance_runs WHERE task = $1")
+                    .bind(task)
+                    .fetch_one(&database::pool())
+                    .await
+                    .unwrap();
+            assert_eq!(error.as_deref(), Some("broken"));
+            assert_eq!(metrics()[task].failures, 1);
+
+            // A failure does not complete the slot
+            assert_eq!(
+                run_once(task, INSTANCE, INTERVAL, async { Ok(3) })
+                    .await
+                    .unwrap(),
+                Outcome::Ran(Ok(3))
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn runs_do_not_hold_pooled_connections() {
+        testing::isolated(async {
+            let pool = &database::pool();
+            let outcome = run_once(
+                "test_runs_do_not_hold_pooled_connections",
+                INSTANCE,
+                INTERVAL,
+                async {
+                    // Every pooled connection is available to the task
+                    let mut connections = Vec::new();
+                    for _ in 0..pool.options().get_max_connections() {
+                        connections.push(pool.acquire().await.map_err(|e| e.to_string())?);
+                    }
+                    Ok(connections.len() as u64)
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                outcome,
+                Outcome::Ran(Ok(u64::from(pool.options().get_max_connections())))
+            );
+        })
+        .await;
+    }
+}
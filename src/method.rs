@@ -0,0 +1,33 @@
+use axum::http::Method;
+
+/// Whether a request method is safe according to RFC 9110, section 9.2.1,
+/// i.e. read-only. All other methods, including unknown extension methods,
+/// may change state and need protection against forgery and abuse.
+pub fn is_safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_every_method() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE] {
+            assert!(is_safe(&method), "{method}");
+        }
+        for method in [
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::CONNECT,
+            Method::from_bytes(b"PURGE").unwrap(),
+        ] {
+            assert!(!is_safe(&method), "{method}");
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use sqlx::{
+    PgPool,
+    migrate::{Migrate, MigrateError, Migrator},
+};
+use std::collections::HashSet;
+
+use crate::database;
+
+/// Migrations embedded from the `migrations` directory at compile time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+    pub reversible: bool,
+}
+
+pub async fn status(pool: &PgPool) -> Result<Vec<Status>, MigrateError> {
+    let applied = applied_versions(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| Status {
+            version: m.version,
+            description: m.description.to_string(),
+            applied: applied.contains(&m.version),
+            reversible: m.migration_type.is_reversible(),
+        })
+        .collect())
+}
+
+pub async fn pending(pool: &PgPool) -> Result<Vec<Status>, MigrateError> {
+    Ok(status(pool)
+        .await?
+        .into_iter()
+        .filter(|s| !s.applied)
+        .collect())
+}
+
+/// Applies pending migrations, followed by the data migrations which need the
+/// application to compute their values.
+pub async fn up(pool: &PgPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(pool).await?;
+
+    let conflicts = database::backfill_usernames(pool).await?;
+    if !conflicts.is_empty() {
+        tracing::error!(
+            "usernames colliding with other users must be renamed: {}",
+            conflicts.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Reverts applied migrations newer than `target`. Without a target only the
+/// most recently applied migration is reverted. Returns the reverted versions.
+pub async fn down(pool: &PgPool, target: Option<i64>) -> Result<Vec<i64>, MigrateError> {
+    let mut applied: Vec<_> = applied_versions(pool).await?.into_iter().collect();
+    applied.sort_unstable();
+
+    let target = match target {
+        Some(target) => target,
+        None => match applied.as_slice() {
+            [.., previous, _] => *previous,
+            _ => 0,
+        },
+    };
+
+    MIGRATOR.undo(pool, target).await?;
+    Ok(applied.into_iter().rev().filter(|&v| v > target).collect())
+}
+
+async fn applied_versions(pool: &PgPool) -> Result<HashSet<i64>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    Ok(conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| m.version)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_migrations_are_reversible() {
+        let versions: Vec<_> = MIGRATOR
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration())
+            .map(|m| m.version)
+            .collect();
+        assert!(!versions.is_empty());
+        for version in versions {
+            assert!(
+                MIGRATOR
+                    .iter()
+                    .any(|m| m.version == version && m.migration_type.is_down_migration()),
+                "migration {version} has no down migration"
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Per-user notes. Every server function only ever touches notes of the
+//! authenticated user; notes of other users are reported as not found.
+
+use chrono::{DateTime, Utc};
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const PAGE_SIZE: u32 = 10;
+pub const MAX_TITLE_LENGTH: usize = 200;
+pub const MAX_BODY_LENGTH: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct Note {
+    pub id: i64,
+    pub title: String,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One page of notes, most recently updated first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotePage {
+    pub notes: Vec<Note>,
+    pub page: u32,
+    pub total: i64,
+}
+
+impl NotePage {
+    pub fn pages(&self) -> u32 {
+        u32::try_from(self.total)
+            .unwrap_or(u32::MAX)
+            .div_ceil(PAGE_SIZE)
+            .max(1)
+    }
+}
+
+/// Trims and validates the title and body of a note.
+#[cfg(feature = "ssr")]
+fn validate(title: &str, body: &str) -> Result<(String, String), ServerFnError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(ServerFnError::new("Title is required"));
+    }
+    if title.chars().count() > MAX_TITLE_LENGTH || body.chars().count() > MAX_BODY_LENGTH {
+        return Err(ServerFnError::new("Input too long"));
+    }
+    Ok((title.to_owned(), body.trim_end().to_owned()))
+}
+
+#[cfg(feature = "ssr")]
+fn not_found() -> ServerFnError {
+    ServerFnError::new("Note not found")
+}
+
+#[server(endpoint = "list_notes", client = crate::client::Client)]
+pub async fn list_notes(token: String, page: u32) -> Result<NotePage, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    let offset = i64::from(page) * i64::from(PAGE_SIZE);
+    let (notes, total) = database::list_notes(&username, i64::from(PAGE_SIZE), offset)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    Ok(NotePage { notes, page, total })
+}
+
+#[server(endpoint = "get_note", client = crate::client::Client)]
+pub async fn get_note(token: String, id: i64) -> Result<Note, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    database::get_note(id, &username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .ok_or_else(not_found)
+}
+
+#[server(endpoint = "create_note", client = crate::client::Client)]
+pub async fn create_note(
+    token: String,
+    title: String,
+    body: String,
+) -> Result<Note, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    let (title, body) = validate(&title, &body)?;
+    database::create_note(&username, &title, &body)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))
+}
+
+#[server(endpoint = "update_note", client = crate::client::Client)]
+pub async fn update_note(
+    token: String,
+    id: i64,
+    title: String,
+    body: String,
+) -> Result<Note, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    let (title, body) = validate(&title, &body)?;
+    database::update_note(id, &username, &title, &body)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .ok_or_else(not_found)
+}
+
+#[server(endpoint = "delete_note", client = crate::client::Client)]
+pub async fn delete_note(token: String, id: i64) -> Result<(), ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    if database::delete_note(id, &username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+    {
+        Ok(())
+    } else {
+        Err(not_found())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_count() {
+        let page = |total| NotePage {
+            total,
+            ..Default::default()
+        };
+        assert_eq!(page(0).pages(), 1);
+        assert_eq!(page(1).pages(), 1);
+        assert_eq!(page(i64::from(PAGE_SIZE)).pages(), 1);
+        assert_eq!(page(i64::from(PAGE_SIZE) + 1).pages(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+//! Organizations, memberships and invitations.
+//!
+//! A session may carry an active organization as a token claim. Server
+//! functions scope data access with [`current`], which re-checks the
+//! membership so that removed members lose access immediately.
+
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Days until an unused invitation expires.
+pub const INVITATION_TTL_DAYS: i64 = 7;
+
+/// Role of a member within an organization, ordered by privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Member => "member",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+
+    /// Whether members with this role may invite others.
+    pub fn can_invite(self) -> bool {
+        self >= Self::Admin
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Self::Member),
+            "admin" => Ok(Self::Admin),
+            "owner" => Ok(Self::Owner),
+            _ => Err(format!("unknown role: {s}")),
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Organization {
+    pub id: i64,
+    pub name: String,
+    pub role: Role,
+}
+
+/// The organizations of the current user and the active one, if any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Organizations {
+    pub active: Option<i64>,
+    pub organizations: Vec<Organization>,
+}
+
+/// Returns the active organization of a session together with the user's
+/// role in it, or `None` if no organization is active or the user is no
+/// longer a member.
+#[cfg(feature = "ssr")]
+pub async fn current(
+    session: &crate::auth::TokenData,
+) -> Result<Option<(i64, Role)>, ServerFnError> {
+    let Some(id) = session.organization else {
+        return Ok(None);
+    };
+    Ok(crate::database::membership_role(id, &session.username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .map(|role| (id, role)))
+}
+
+#[server(endpoint = "list_organizations", client = crate::client::Client)]
+pub async fn list_organizations(token: String) -> Result<Organizations, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let session = authenticate(&token).await?;
+    let active = current(&session).await?.map(|(id, _)| id);
+    let organizations = database::list_memberships(&session.username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .into_iter()
+        .map(|m| Organization {
+            id: m.organization_id,
+            name: m.organization,
+            role: m.role,
+        })
+        .collect();
+
+    Ok(Organizations {
+        active,
+        organizations,
+    })
+}
+
+/// Creates an organization owned by the current user.
+#[server(endpoint = "create_organization", client = crate::client::Client)]
+pub async fn create_organization(token: String, name: String) -> Result<i64, ServerFnError> {
+    use crate::{
+        app::authenticate,
+        audit::{self, EventType, Outcome},
+        database,
+    };
+
+    let ctx = audit::Context::current();
+    let session = authenticate(&token).await?;
+    let result = async {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(ServerFnError::new("Name is required"));
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(ServerFnError::new("Input too long"));
+        }
+
+        database::create_organization(name, &session.username)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))
+    }
+    .await;
+
+    audit::record(
+        EventType::CreateOrganization,
+        Outcome::of(&result),
+        Some(&session.username),
+        &ctx,
+    )
+    .await;
+    result
+}
+
+/// Makes the given organization, or none, the active one and returns the new
+/// session token carrying it.
+#[server(endpoint = "switch_organization", client = crate::client::Client)]
+pub async fn switch_organization(
+    token: String,
+    organization: Option<i64>,
+) -> Result<String, ServerFnError> {
+    use crate::{app::authenticate, auth, database};
+
+    let session = authenticate(&token).await?;
+    if let Some(id) = organization
+        && database::membership_role(id, &session.username)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?
+            .is_none()
+    {
+        return Err(ServerFnError::new("Organization not found"));
+    }
+
+    let new_token =
+        auth::renew_token(&session, organization).map_err(|e| ServerFnError::new(e.to_string()))?;
+    database::update_session(&token, &new_token, auth::token_expiry())
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    Ok(new_token)
+}
+
+/// Creates an invitation to the active organization and returns its token.
+/// Members can grant at most their own role and never ownership.
+#[server(endpoint = "create_invitation", client = crate::client::Client)]
+pub async fn create_invitation(token: String, role: Role) -> Result<String, ServerFnError> {
+    use crate::{
+        app::authenticate,
+        audit::{self, EventType, Outcome},
+        database,
+    };
+
+    let ctx = audit::Context::current();
+    let session = authenticate(&token).await?;
+    let result = async {
+        let (id, own_role) = current(&session)
+            .await?
+            .ok_or_else(|| ServerFnError::new("No active organization"))?;
+        if !own_role.can_invite() || role > own_role || role == Role::Owner {
+            return Err(ServerFnError::new("Insufficient permissions"));
+        }
+
+        let invitation = uuid::Uuid::new_v4().simple().to_string();
+        let expires_at = chrono::Utc::now() + chrono::Duration::days(INVITATION_TTL_DAYS);
+        database::create_invitation(&invitation, id, role, &session.username, expires_at)
+            .await
+            .map_err(|e| ServerFnError::new(e.to_string()))?;
+        Ok(invitation)
+    }
+    .await;
+
+    audit::record(
+        EventType::CreateInvitation,
+        Outcome::of(&result),
+        Some(&session.username),
+        &ctx,
+    )
+    .await;
+    result
+}
+
+/// Accepts an invitation as an existing user and returns the organization.
+#[server(endpoint = "accept_invitation", client = crate::client::Client)]
+pub async fn accept_invitation(token: String, invitation: String) -> Result<i64, ServerFnError> {
+    use crate::{
+        app::authenticate,
+        audit::{self, EventType, Outcome},
+        database,
+    };
+
+    let ctx = audit::Context::current();
+    let session = authenticate(&token).await?;
+    let result = database::accept_invitation(invitation.trim(), &session.username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .ok_or_else(|| ServerFnError::new("Invalid invitation"));
+
+    audit::record(
+        EventType::AcceptInvitation,
+        Outcome::of(&result),
+        Some(&session.username),
+        &ctx,
+    )
+    .await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(Role::Owner > Role::Admin);
+        assert!(Role::Admin > Role::Member);
+        assert!(Role::Owner.can_invite());
+        assert!(Role::Admin.can_invite());
+        assert!(!Role::Member.can_invite());
+    }
+
+    #[test]
+    fn role_round_trip() {
+        for role in [Role::Member, Role::Admin, Role::Owner] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+        assert!("superuser".parse::<Role>().is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+
+use crate::attachments::{Attachments, delete_attachment, format_size, list_attachments};
+
+fn message(error: ServerFnError) -> String {
+    match error {
+        ServerFnError::ServerError(msg) => msg,
+        e => e.to_string(),
+    }
+}
+
+/// Posts the upload form to the streaming upload route, returning the error
+/// message of a rejected upload.
+#[cfg(feature = "hydrate")]
+async fn upload(token: &str, form: &web_sys::HtmlFormElement) -> Result<(), String> {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+
+    let failed = |_| "Upload failed".to_string();
+    let data = web_sys::FormData::new_with_form(form).map_err(failed)?;
+    let headers = web_sys::Headers::new().map_err(failed)?;
+    headers
+        .set("Authorization", &format!("Bearer {token}"))
+        .map_err(failed)?;
+    if let Some(token) = crate::csrf::token() {
+        headers.set(crate::csrf::HEADER, &token).map_err(failed)?;
+    }
+    let init = web_sys::RequestInit::new();
+    init.set_method("POST");
+    init.set_headers(&headers);
+    init.set_body(&data);
+
+    let window = web_sys::window().ok_or("Upload failed")?;
+    let response: web_sys::Response =
+        JsFuture::from(window.fetch_with_str_and_init("/attachments", &init))
+            .await
+            .map_err(failed)?
+            .dyn_into()
+            .map_err(failed)?;
+    if response.ok() {
+        return Ok(());
+    }
+    if response.status() == 429 {
+        let seconds = response
+            .headers()
+            .get("retry-after")
+            .ok()
+            .flatten()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        return Err(crate::client::throttled_message(seconds));
+    }
+    let text = match response.text() {
+        Ok(text) => JsFuture::from(text).await.ok().and_then(|t| t.as_string()),
+        Err(_) => None,
+    };
+    Err(text.unwrap_or_else(|| "Upload failed".into()))
+}
+
+/// The user's attachments with their quota usage, an upload form and
+/// download links.
+#[component]
+pub fn Attachments(token: RwSignal<String>) -> impl IntoView {
+    let attachments = RwSignal::new(Attachments::default());
+    let error = RwSignal::new(Option::<String>::None);
+    let uploading = RwSignal::new(false);
+    let form = NodeRef::<leptos::html::Form>::new();
+
+    let refresh = move || {
+        spawn_local(async move {
+            match list_attachments(token.get_untracked()).await {
+                Ok(list) => attachments.set(list),
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    // Only load once a token is known, not on every session renewal
+    let ready = Memo::new(move |_| !token.get().is_empty());
+    Effect::new(move |_| {
+        if ready.get() {
+            refresh();
+        }
+    });
+
+    let on_upload = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(form) = form.get() else {
+                return;
+            };
+            error.set(None);
+            uploading.set(true);
+            spawn_local(async move {
+                match upload(&token.get_untracked(), &form).await {
+                    Ok(()) => {
+                        form.reset();
+                        refresh();
+                    }
+                    Err(msg) => error.set(Some(msg)),
+                }
+                uploading.set(false);
+            });
+        }
+    };
+
+    let on_delete = move |id: i64| {
+        error.set(None);
+        spawn_local(async move {
+            match delete_attachment(token.get_untracked(), id).await {
+                Ok(()) => refresh(),
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    view! {
+        <div class="attachments">
+            <h2>"Files"</h2>
+            <p class="subtitle">
+                {move || {
+                    attachments
+                        .with(|a| {
+                            format!("{} of {} used", format_size(a.used), format_size(a.quota))
+                        })
+                }}
+            </p>
+            <form node_ref=form on:submit=on_upload>
+                <div class="field">
+                    <input type="file" name="file" required/>
+                </div>
+                <button type="submit" disabled=move || uploading.get()>
+                    {move || if uploading.get() { "Uploading..." } else { "Upload" }}
+                </button>
+            </form>
+            {move || error.get().map(|msg| view! { <div class="error">{msg}</div> })}
+            <ul class="attachment-list">
+                <For
+                    each=move || attachments.get().attachments
+                    key=|attachment| attachment.id
+                    let:attachment
+                >
+                    <li>
+                        <a href=attachment.url() rel="external">
+                            {attachment.filename.clone()}
+                        </a>
+                        <span class="size">{format_size(attachment.size)}</span>
+                        <button class="secondary" on:click=move |_| on_delete(attachment.id)>
+                            "Delete"
+                        </button>
+                    </li>
+                </For>
+            </ul>
+        </div>
+    }
+}
@@ -1,31 +1,74 @@
 use leptos::prelude::*;
 use leptos::task::spawn_local;
-use leptos_router::hooks::use_navigate;
+use leptos_router::{components::A, hooks::use_navigate};
 
-use crate::app::{logout, whoami};
+use crate::app::{ACCOUNT_DISABLED, export_account, logout, whoami};
+use crate::pages::attachments::Attachments;
 use crate::pages::login::{get_cookie, remove_cookie};
+use crate::pages::notes::Notes;
+use crate::pages::organizations::OrganizationSwitcher;
+use crate::pages::search::Search;
+use crate::profile::Profile;
 
 #[cfg(feature = "hydrate")]
 use crate::app::renew_session;
 #[cfg(feature = "hydrate")]
 use crate::pages::login::set_cookie;
 
+/// Offers the given contents as a file download to the user.
+fn download(filename: &str, contents: &str) {
+    #[cfg(feature = "hydrate")]
+    {
+        use wasm_bindgen::JsCast;
+        let parts = js_sys::Array::of1(&contents.into());
+        let options = web_sys::BlobPropertyBag::new();
+        options.set_type("application/json");
+        let Ok(blob) = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options) else {
+            return;
+        };
+        let Ok(url) = web_sys::Url::create_object_url_with_blob(&blob) else {
+            return;
+        };
+        if let Some(anchor) = web_sys::window()
+            .and_then(|w| w.document())
+            .and_then(|d| d.create_element("a").ok())
+            .and_then(|e| e.dyn_into::<web_sys::HtmlAnchorElement>().ok())
+        {
+            anchor.set_href(&url);
+            anchor.set_download(filename);
+            anchor.click();
+        }
+        let _ = web_sys::Url::revoke_object_url(&url);
+    }
+    #[cfg(not(feature = "hydrate"))]
+    {
+        let _ = (filename, contents);
+    }
+}
+
 #[component]
 pub fn ContentPage() -> impl IntoView {
     let navigate = use_navigate();
     let token = RwSignal::new(String::new());
-    let username = RwSignal::new(String::new());
+    let profile = RwSignal::new(Option::<Profile>::None);
     let logging_out = RwSignal::new(false);
+    let exporting = RwSignal::new(false);
 
-    // Check session on mount and fetch username
+    // Check session on mount and fetch the profile
     Effect::new({
         let navigate = navigate.clone();
         move |_| match get_cookie("session_token") {
             Some(t) => {
                 token.set(t.clone());
+                let navigate = navigate.clone();
                 spawn_local(async move {
-                    if let Ok(name) = whoami(t).await {
-                        username.set(name);
+                    match whoami(t).await {
+                        Ok(p) => profile.set(Some(p)),
+                        Err(ServerFnError::ServerError(msg)) if msg == ACCOUNT_DISABLED => {
+                            remove_cookie("session_token");
+                            navigate("/", Default::default());
+                        }
+                        Err(_) => {}
                     }
                 });
             }
@@ -79,26 +122,57 @@ pub fn ContentPage() -> impl IntoView {
         });
     };
 
+    let on_export = move |_| {
+        exporting.set(true);
+        let current_token = token.get();
+
+        spawn_local(async move {
+            if let Ok(contents) = export_account(current_token).await {
+                download("webapp-export.json", &contents);
+            }
+            exporting.set(false);
+        });
+    };
+
     view! {
         <div class="container">
             <div class="card">
+                {move || {
+                    profile
+                        .with(|p| p.as_ref().and_then(Profile::avatar_url))
+                        .map(|url| view! { <img class="avatar" src=url alt="Avatar"/> })
+                }}
                 <h1>"Welcome"</h1>
                 <p>
                     {move || {
-                        let name = username.get();
-                        if name.is_empty() {
-                            "You are logged in to a web application completely written in Rust."
-                                .to_string()
-                        } else {
-                            format!(
-                                "Hello {name}, you are logged in to a web application completely written in Rust.",
-                            )
-                        }
+                        profile
+                            .with(|p| match p {
+                                Some(p) => {
+                                    format!(
+                                        "Hello {}, you are logged in to a web application completely written in Rust.",
+                                        p.name(),
+                                    )
+                                }
+                                None => {
+                                    "You are logged in to a web application completely written in Rust."
+                                        .to_string()
+                                }
+                            })
                     }}
                 </p>
+                <p class="toggle">
+                    <A href="/profile">"Edit profile"</A>
+                </p>
+                <OrganizationSwitcher token/>
+                <Search token/>
+                <Notes token/>
+                <Attachments token/>
                 <button on:click=on_logout disabled=move || logging_out.get()>
                     {move || if logging_out.get() { "Logging out..." } else { "Logout" }}
                 </button>
+                <button class="secondary" on:click=on_export disabled=move || exporting.get()>
+                    {move || if exporting.get() { "Exporting..." } else { "Download my data" }}
+                </button>
             </div>
         </div>
     }
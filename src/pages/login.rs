@@ -1,8 +1,9 @@
 use leptos::prelude::*;
 use leptos::task::spawn_local;
-use leptos_router::hooks::use_navigate;
+use leptos_router::hooks::{use_navigate, use_query_map};
 
-use crate::app::{login, register};
+use crate::app::{ACCOUNT_DISABLED, login, register};
+use crate::client::{throttled, throttled_message};
 
 pub fn get_cookie(name: &str) -> Option<String> {
     #[cfg(feature = "hydrate")]
@@ -72,6 +73,13 @@ pub fn LoginPage() -> impl IntoView {
     let is_register = RwSignal::new(false);
     let navigate = use_navigate();
 
+    // Invitation links point here with `?invite=<token>`
+    let invitation = RwSignal::new(String::new());
+    if let Some(token) = use_query_map().get_untracked().get("invite") {
+        invitation.set(token);
+        is_register.set(true);
+    }
+
     // Check for existing session on mount
     Effect::new({
         let navigate = navigate.clone();
@@ -91,14 +99,16 @@ pub fn LoginPage() -> impl IntoView {
 
         if is_register.get() {
             spawn_local(async move {
-                match register(username.get(), password.get()).await {
+                let invitation = Some(invitation.get()).filter(|i| !i.trim().is_empty());
+                match register(username.get(), password.get(), invitation).await {
                     Ok(()) => {
                         success.set(Some("Account created, you can now log in".into()));
                         is_register.set(false);
                         pending.set(false);
                     }
                     Err(e) => {
-                        error.set(Some(e.to_string()));
+                        let msg = throttled(&e).map_or_else(|| e.to_string(), throttled_message);
+                        error.set(Some(msg));
                         pending.set(false);
                     }
                 }
@@ -110,6 +120,14 @@ pub fn LoginPage() -> impl IntoView {
                         set_cookie("session_token", &token);
                         navigate("/content", Default::default());
                     }
+                    Err(e) if throttled(&e).is_some() => {
+                        error.set(throttled(&e).map(throttled_message));
+                        pending.set(false);
+                    }
+                    Err(ServerFnError::ServerError(msg)) if msg == ACCOUNT_DISABLED => {
+                        error.set(Some("This account has been disabled".into()));
+                        pending.set(false);
+                    }
                     Err(_) => {
                         error.set(Some("Invalid username or password".into()));
                         pending.set(false);
@@ -143,6 +161,16 @@ pub fn LoginPage() -> impl IntoView {
                             on:input=move |ev| password.set(event_target_value(&ev))
                         />
                     </div>
+                    <Show when=move || is_register.get()>
+                        <div class="field">
+                            <input
+                                type="text"
+                                placeholder="Invitation code (optional)"
+                                prop:value=invitation
+                                on:input=move |ev| invitation.set(event_target_value(&ev))
+                            />
+                        </div>
+                    </Show>
                     {move || {
                         error
                             .get()
@@ -1,2 +1,7 @@
+pub mod attachments;
 pub mod content;
 pub mod login;
+pub mod notes;
+pub mod organizations;
+pub mod profile;
+pub mod search;
@@ -0,0 +1,295 @@
+use chrono::Utc;
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+
+use crate::notes::{Note, NotePage, create_note, delete_note, list_notes, update_note};
+
+fn message(error: ServerFnError) -> String {
+    match error {
+        ServerFnError::ServerError(msg) => msg,
+        e => e.to_string(),
+    }
+}
+
+/// Paginated list of the user's notes. Changes are applied to the list
+/// immediately and rolled back if the server rejects them.
+#[component]
+pub fn Notes(token: RwSignal<String>) -> impl IntoView {
+    let page = RwSignal::new(0u32);
+    let refresh = RwSignal::new(0u32);
+    let notes = RwSignal::new(NotePage::default());
+    let error = RwSignal::new(Option::<String>::None);
+    let title = RwSignal::new(String::new());
+    let body = RwSignal::new(String::new());
+    let editing = RwSignal::new(Option::<i64>::None);
+    let edit_title = RwSignal::new(String::new());
+    let edit_body = RwSignal::new(String::new());
+
+    // Only refetch once a token is known, not on every session renewal
+    let ready = Memo::new(move |_| !token.get().is_empty());
+    let resource = LocalResource::new(move || {
+        let page = page.get();
+        let ready = ready.get();
+        refresh.track();
+        let token = token.get_untracked();
+        async move {
+            if ready {
+                list_notes(token, page).await.map(Some)
+            } else {
+                Ok(None)
+            }
+        }
+    });
+    Effect::new(move |_| match resource.get() {
+        Some(Ok(Some(fetched))) => notes.set(fetched),
+        Some(Err(e)) => error.set(Some(message(e))),
+        _ => {}
+    });
+
+    // Step back when deleting the last note of the last page
+    Effect::new(move |_| {
+        let pages = notes.with(NotePage::pages);
+        if page.get_untracked() >= pages {
+            page.set(pages - 1);
+        }
+    });
+
+    let on_create = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        error.set(None);
+        let now = Utc::now();
+        // Temporary negative ids mark notes not yet stored
+        let pending = Note {
+            id: -now.timestamp_micros(),
+            title: title.get_untracked().trim().to_owned(),
+            body: body.get_untracked(),
+            created_at: now,
+            updated_at: now,
+        };
+        let pending_id = pending.id;
+        notes.update(|p| {
+            p.notes.insert(0, pending.clone());
+            p.total += 1;
+        });
+        title.set(String::new());
+        body.set(String::new());
+
+        spawn_local(async move {
+            match create_note(token.get_untracked(), pending.title, pending.body).await {
+                Ok(note) => notes.update(|p| {
+                    if let Some(n) = p.notes.iter_mut().find(|n| n.id == pending_id) {
+                        *n = note;
+                    }
+                }),
+                Err(e) => {
+                    notes.update(|p| {
+                        p.notes.retain(|n| n.id != pending_id);
+                        p.total -= 1;
+                    });
+                    error.set(Some(message(e)));
+                }
+            }
+        });
+    };
+
+    let on_delete = move |id: i64| {
+        error.set(None);
+        let Some(index) = notes.with_untracked(|p| p.notes.iter().position(|n| n.id == id)) else {
+            return;
+        };
+        let mut removed = None;
+        notes.update(|p| {
+            removed = Some(p.notes.remove(index));
+            p.total -= 1;
+        });
+
+        spawn_local(async move {
+            match delete_note(token.get_untracked(), id).await {
+                // Pull the next note onto this page
+                Ok(()) => refresh.update(|n| *n += 1),
+                Err(e) => {
+                    if let Some(note) = removed {
+                        notes.update(|p| {
+                            p.notes.insert(index.min(p.notes.len()), note);
+                            p.total += 1;
+                        });
+                    }
+                    error.set(Some(message(e)));
+                }
+            }
+        });
+    };
+
+    let on_edit = move |note: &Note| {
+        edit_title.set(note.title.clone());
+        edit_body.set(note.body.clone());
+        editing.set(Some(note.id));
+    };
+
+    let on_save = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        error.set(None);
+        let Some(id) = editing.get_untracked() else {
+            return;
+        };
+        editing.set(None);
+        let (new_title, new_body) = (edit_title.get_untracked(), edit_body.get_untracked());
+        let mut previous = None;
+        notes.update(|p| {
+            if let Some(n) = p.notes.iter_mut().find(|n| n.id == id) {
+                previous = Some(n.clone());
+                n.title = new_title.trim().to_owned();
+                n.body = new_body.clone();
+            }
+        });
+
+        spawn_local(async move {
+            let result = update_note(token.get_untracked(), id, new_title, new_body).await;
+            notes.update(|p| {
+                let Some(n) = p.notes.iter_mut().find(|n| n.id == id) else {
+                    return;
+                };
+                match result {
+                    Ok(note) => *n = note,
+                    Err(e) => {
+                        if let Some(previous) = previous {
+                            *n = previous;
+                        }
+                        error.set(Some(message(e)));
+                    }
+                }
+            });
+        });
+    };
+
+    view! {
+        <div class="notes">
+            <h2>"Notes"</h2>
+            <form on:submit=on_create>
+                <div class="field">
+                    <input
+                        type="text"
+                        placeholder="Title"
+                        prop:value=title
+                        on:input=move |ev| title.set(event_target_value(&ev))
+                    />
+                </div>
+                <div class="field">
+                    <textarea
+                        placeholder="Note"
+                        rows="3"
+                        prop:value=body
+                        on:input=move |ev| body.set(event_target_value(&ev))
+                    ></textarea>
+                </div>
+                <button type="submit" disabled=move || title.get().trim().is_empty()>
+                    "Add note"
+                </button>
+            </form>
+            {move || error.get().map(|msg| view! { <div class="error">{msg}</div> })}
+            <Transition fallback=|| view! { <p class="subtitle">"Loading notes..."</p> }>
+                {move || {
+                    resource
+                        .get()
+                        .map(|_| {
+                            view! {
+                        <ul class="note-list">
+                            <For
+                                each=move || notes.get().notes
+                                key=|note| note.clone()
+                                children=move |note| {
+                                    let id = note.id;
+                                    let stored = id > 0;
+                                    view! {
+                                        <li class="note" class:pending=!stored>
+                                            <Show
+                                                when=move || editing.get() == Some(id)
+                                                fallback={
+                                                    let note = note.clone();
+                                                    move || {
+                                                        let edit = note.clone();
+                                                        view! {
+                                                            <h3>{note.title.clone()}</h3>
+                                                            <p>{note.body.clone()}</p>
+                                                            <div class="note-actions">
+                                                                <button
+                                                                    class="secondary"
+                                                                    disabled=!stored
+                                                                    on:click=move |_| on_edit(&edit)
+                                                                >
+                                                                    "Edit"
+                                                                </button>
+                                                                <button
+                                                                    class="secondary"
+                                                                    disabled=!stored
+                                                                    on:click=move |_| on_delete(id)
+                                                                >
+                                                                    "Delete"
+                                                                </button>
+                                                            </div>
+                                                        }
+                                                    }
+                                                }
+                                            >
+                                                <form on:submit=on_save>
+                                                    <div class="field">
+                                                        <input
+                                                            type="text"
+                                                            prop:value=edit_title
+                                                            on:input=move |ev| {
+                                                                edit_title.set(event_target_value(&ev))
+                                                            }
+                                                        />
+                                                    </div>
+                                                    <div class="field">
+                                                        <textarea
+                                                            rows="3"
+                                                            prop:value=edit_body
+                                                            on:input=move |ev| {
+                                                                edit_body.set(event_target_value(&ev))
+                                                            }
+                                                        ></textarea>
+                                                    </div>
+                                                    <div class="note-actions">
+                                                        <button type="submit">"Save"</button>
+                                                        <button
+                                                            type="button"
+                                                            class="secondary"
+                                                            on:click=move |_| editing.set(None)
+                                                        >
+                                                            "Cancel"
+                                                        </button>
+                                                    </div>
+                                                </form>
+                                            </Show>
+                                        </li>
+                                    }
+                                }
+                            />
+                        </ul>
+                            }
+                        })
+                }}
+            </Transition>
+            <div class="pagination">
+                <button
+                    class="secondary"
+                    disabled=move || page.get() == 0
+                    on:click=move |_| page.update(|p| *p = p.saturating_sub(1))
+                >
+                    "Previous"
+                </button>
+                <span>
+                    {move || notes.with(|p| format!("Page {} of {}", page.get() + 1, p.pages()))}
+                </span>
+                <button
+                    class="secondary"
+                    disabled=move || notes.with(|p| page.get() + 1 >= p.pages())
+                    on:click=move |_| page.update(|p| *p += 1)
+                >
+                    "Next"
+                </button>
+            </div>
+        </div>
+    }
+}
@@ -0,0 +1,174 @@
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+
+use crate::organizations::{
+    Organizations, Role, accept_invitation, create_invitation, create_organization,
+    list_organizations, switch_organization,
+};
+use crate::pages::login::set_cookie;
+
+fn message(error: ServerFnError) -> String {
+    match error {
+        ServerFnError::ServerError(msg) => msg,
+        e => e.to_string(),
+    }
+}
+
+/// Lets the user switch the active organization, create organizations, join
+/// one with an invitation code and invite others to the active one.
+#[component]
+pub fn OrganizationSwitcher(token: RwSignal<String>) -> impl IntoView {
+    let organizations = RwSignal::new(Organizations::default());
+    let refresh = RwSignal::new(0u32);
+    let name = RwSignal::new(String::new());
+    let code = RwSignal::new(String::new());
+    let invitation = RwSignal::new(Option::<String>::None);
+    let error = RwSignal::new(Option::<String>::None);
+
+    // Only refetch once a token is known, not on every session renewal
+    let ready = Memo::new(move |_| !token.get().is_empty());
+    Effect::new(move |_| {
+        refresh.track();
+        if !ready.get() {
+            return;
+        }
+        let current = token.get_untracked();
+        spawn_local(async move {
+            if let Ok(list) = list_organizations(current).await {
+                organizations.set(list);
+            }
+        });
+    });
+
+    let switch_to = move |organization: Option<i64>| {
+        let current = token.get_untracked();
+        error.set(None);
+        invitation.set(None);
+        spawn_local(async move {
+            match switch_organization(current, organization).await {
+                Ok(new_token) => {
+                    set_cookie("session_token", &new_token);
+                    token.set(new_token);
+                    refresh.update(|n| *n += 1);
+                }
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    let on_create = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let current = token.get_untracked();
+        error.set(None);
+        spawn_local(async move {
+            match create_organization(current, name.get_untracked()).await {
+                Ok(id) => {
+                    name.set(String::new());
+                    switch_to(Some(id));
+                }
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    let on_join = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let current = token.get_untracked();
+        error.set(None);
+        spawn_local(async move {
+            match accept_invitation(current, code.get_untracked()).await {
+                Ok(id) => {
+                    code.set(String::new());
+                    switch_to(Some(id));
+                }
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    let on_invite = move |_| {
+        let current = token.get_untracked();
+        error.set(None);
+        spawn_local(async move {
+            match create_invitation(current, Role::Member).await {
+                Ok(token) => invitation.set(Some(token)),
+                Err(e) => error.set(Some(message(e))),
+            }
+        });
+    };
+
+    let can_invite = move || {
+        organizations.with(|o| {
+            o.organizations
+                .iter()
+                .any(|org| Some(org.id) == o.active && org.role.can_invite())
+        })
+    };
+
+    view! {
+        <div class="organizations">
+            <div class="field">
+                <select on:change=move |ev| switch_to(event_target_value(&ev).parse().ok())>
+                    <option value="" prop:selected=move || organizations.with(|o| o.active.is_none())>
+                        "Personal"
+                    </option>
+                    <For
+                        each=move || organizations.get().organizations
+                        key=|org| org.id
+                        let:org
+                    >
+                        <option
+                            value=org.id.to_string()
+                            prop:selected=move || organizations.with(|o| o.active == Some(org.id))
+                        >
+                            {format!("{} ({})", org.name, org.role)}
+                        </option>
+                    </For>
+                </select>
+            </div>
+            <Show when=can_invite>
+                <button class="secondary" on:click=on_invite>
+                    "Create invitation"
+                </button>
+                {move || {
+                    invitation
+                        .get()
+                        .map(|token| {
+                            view! {
+                                <p class="invitation">
+                                    "Invitation code: " <code>{token}</code>
+                                </p>
+                            }
+                        })
+                }}
+            </Show>
+            <form on:submit=on_create>
+                <div class="field">
+                    <input
+                        type="text"
+                        placeholder="New organization"
+                        prop:value=name
+                        on:input=move |ev| name.set(event_target_value(&ev))
+                    />
+                </div>
+                <button type="submit" class="secondary" disabled=move || name.get().trim().is_empty()>
+                    "Create organization"
+                </button>
+            </form>
+            <form on:submit=on_join>
+                <div class="field">
+                    <input
+                        type="text"
+                        placeholder="Invitation code"
+                        prop:value=code
+                        on:input=move |ev| code.set(event_target_value(&ev))
+                    />
+                </div>
+                <button type="submit" class="secondary" disabled=move || code.get().trim().is_empty()>
+                    "Join organization"
+                </button>
+            </form>
+            {move || error.get().map(|msg| view! { <div class="error">{msg}</div> })}
+        </div>
+    }
+}
@@ -0,0 +1,180 @@
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+use leptos_router::{components::A, hooks::use_navigate};
+use std::{future::Future, pin::Pin};
+
+use crate::app::whoami;
+use crate::pages::login::get_cookie;
+use crate::profile::{Profile, remove_avatar, update_profile};
+
+#[cfg(feature = "hydrate")]
+use crate::profile::upload_avatar;
+
+type ProfileRequest = Pin<Box<dyn Future<Output = Result<Profile, ServerFnError>>>>;
+
+fn message(error: ServerFnError) -> String {
+    match error {
+        ServerFnError::ServerError(msg) => msg,
+        e => e.to_string(),
+    }
+}
+
+#[component]
+pub fn ProfilePage() -> impl IntoView {
+    let navigate = use_navigate();
+    let token = RwSignal::new(String::new());
+    let profile = RwSignal::new(Option::<Profile>::None);
+    let display_name = RwSignal::new(String::new());
+    let bio = RwSignal::new(String::new());
+    let timezone = RwSignal::new(String::new());
+    let locale = RwSignal::new(String::new());
+    let error = RwSignal::new(Option::<String>::None);
+    let success = RwSignal::new(Option::<String>::None);
+    let pending = RwSignal::new(false);
+    let avatar_form = NodeRef::<leptos::html::Form>::new();
+
+    let show = move |p: Profile| {
+        display_name.set(p.display_name.clone().unwrap_or_default());
+        bio.set(p.bio.clone().unwrap_or_default());
+        timezone.set(p.timezone.clone());
+        locale.set(p.locale.clone());
+        profile.set(Some(p));
+    };
+
+    Effect::new(move |_| match get_cookie("session_token") {
+        Some(t) => {
+            token.set(t.clone());
+            spawn_local(async move {
+                if let Ok(p) = whoami(t).await {
+                    show(p);
+                }
+            });
+        }
+        None => navigate("/", Default::default()),
+    });
+
+    // Runs a profile changing request and shows its outcome
+    let submit = move |request: ProfileRequest, done: &'static str| {
+        pending.set(true);
+        error.set(None);
+        success.set(None);
+        spawn_local(async move {
+            match request.await {
+                Ok(p) => {
+                    show(p);
+                    success.set(Some(done.into()));
+                }
+                Err(e) => error.set(Some(message(e))),
+            }
+            pending.set(false);
+        });
+    };
+
+    let on_save = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        submit(
+            Box::pin(update_profile(
+                token.get(),
+                display_name.get(),
+                bio.get(),
+                timezone.get(),
+                locale.get(),
+            )),
+            "Profile saved",
+        );
+    };
+
+    let on_upload = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        #[cfg(feature = "hydrate")]
+        {
+            let Some(form) = avatar_form.get() else {
+                return;
+            };
+            if let Ok(data) = web_sys::FormData::new_with_form(&form) {
+                submit(Box::pin(upload_avatar(data.into())), "Avatar updated");
+            }
+        }
+    };
+
+    let on_remove = move |_| submit(Box::pin(remove_avatar(token.get())), "Avatar removed");
+
+    let has_avatar = move || profile.with(|p| p.as_ref().is_some_and(|p| p.avatar.is_some()));
+
+    view! {
+        <div class="container">
+            <div class="card">
+                {move || {
+                    profile
+                        .with(|p| p.as_ref().and_then(Profile::avatar_url))
+                        .map(|url| view! { <img class="avatar" src=url alt="Avatar"/> })
+                }}
+                <h1>"Profile"</h1>
+                <p class="subtitle">
+                    {move || profile.with(|p| p.as_ref().map(|p| p.username.clone()))}
+                </p>
+                <form node_ref=avatar_form on:submit=on_upload>
+                    <input type="hidden" name="token" prop:value=token/>
+                    <div class="field">
+                        <input
+                            type="file"
+                            name="avatar"
+                            accept="image/gif,image/jpeg,image/png,image/webp"
+                            required
+                        />
+                    </div>
+                    <button type="submit" class="secondary" disabled=move || pending.get()>
+                        "Upload avatar"
+                    </button>
+                </form>
+                <Show when=has_avatar>
+                    <button class="secondary" on:click=on_remove disabled=move || pending.get()>
+                        "Remove avatar"
+                    </button>
+                </Show>
+                <form class="profile" on:submit=on_save>
+                    <div class="field">
+                        <input
+                            type="text"
+                            placeholder="Display name"
+                            prop:value=display_name
+                            on:input=move |ev| display_name.set(event_target_value(&ev))
+                        />
+                    </div>
+                    <div class="field">
+                        <textarea
+                            placeholder="Bio"
+                            rows="4"
+                            prop:value=bio
+                            on:input=move |ev| bio.set(event_target_value(&ev))
+                        ></textarea>
+                    </div>
+                    <div class="field">
+                        <input
+                            type="text"
+                            placeholder="Timezone, e.g. Europe/Berlin"
+                            prop:value=timezone
+                            on:input=move |ev| timezone.set(event_target_value(&ev))
+                        />
+                    </div>
+                    <div class="field">
+                        <input
+                            type="text"
+                            placeholder="Locale, e.g. en-US"
+                            prop:value=locale
+                            on:input=move |ev| locale.set(event_target_value(&ev))
+                        />
+                    </div>
+                    {move || error.get().map(|msg| view! { <div class="error">{msg}</div> })}
+                    {move || success.get().map(|msg| view! { <div class="success">{msg}</div> })}
+                    <button type="submit" disabled=move || pending.get()>
+                        {move || if pending.get() { "Saving..." } else { "Save" }}
+                    </button>
+                </form>
+                <p class="toggle">
+                    <A href="/content">"Back"</A>
+                </p>
+            </div>
+        </div>
+    }
+}
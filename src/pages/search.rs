@@ -0,0 +1,106 @@
+use leptos::prelude::*;
+use leptos::task::spawn_local;
+use std::time::Duration;
+
+use crate::search::{SearchResult, search_notes};
+
+/// Delay after the last keystroke before a query is sent.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+fn message(error: ServerFnError) -> String {
+    match error {
+        ServerFnError::ServerError(msg) => msg,
+        e => e.to_string(),
+    }
+}
+
+/// Search box over the user's notes with ranked, highlighted results.
+#[component]
+pub fn Search(token: RwSignal<String>) -> impl IntoView {
+    let query = RwSignal::new(String::new());
+    let results = RwSignal::new(Vec::<SearchResult>::new());
+    let searching = RwSignal::new(false);
+    let error = RwSignal::new(Option::<String>::None);
+    // Incremented on every keystroke so that stale queries and responses are
+    // dropped
+    let generation = StoredValue::new(0u64);
+
+    let run = move |current: u64| {
+        if generation.get_value() != current {
+            return;
+        }
+        let q = query.get_untracked();
+        error.set(None);
+        if q.trim().is_empty() {
+            results.set(Vec::new());
+            searching.set(false);
+            return;
+        }
+        searching.set(true);
+        spawn_local(async move {
+            let found = search_notes(token.get_untracked(), q).await;
+            if generation.get_value() == current {
+                match found {
+                    Ok(found) => results.set(found),
+                    Err(e) => {
+                        results.set(Vec::new());
+                        error.set(Some(message(e)));
+                    }
+                }
+                searching.set(false);
+            }
+        });
+    };
+
+    let on_input = move |ev| {
+        query.set(event_target_value(&ev));
+        generation.update_value(|g| *g += 1);
+        let current = generation.get_value();
+        set_timeout(move || run(current), DEBOUNCE);
+    };
+
+    view! {
+        <div class="search">
+            <div class="field">
+                <input
+                    type="search"
+                    placeholder="Search notes"
+                    prop:value=query
+                    on:input=on_input
+                />
+            </div>
+            <Show when=move || !query.get().trim().is_empty()>
+                <ul class="search-results">
+                    <For
+                        each=move || results.get()
+                        key=|result| (result.note_id, result.snippet.clone())
+                        let:result
+                    >
+                        <li>
+                            <strong>{result.title}</strong>
+                            <p>
+                                {result
+                                    .snippet
+                                    .into_iter()
+                                    .map(|fragment| {
+                                        if fragment.highlight {
+                                            view! { <mark>{fragment.text}</mark> }.into_any()
+                                        } else {
+                                            fragment.text.into_any()
+                                        }
+                                    })
+                                    .collect_view()}
+                            </p>
+                        </li>
+                    </For>
+                </ul>
+                {move || error.get().map(|msg| view! { <div class="error">{msg}</div> })}
+                <Show when=move || {
+                    !searching.get() && error.with(Option::is_none) && results.with(Vec::is_empty)
+                }>
+                    <p class="subtitle">"No matching notes"</p>
+                </Show>
+            </Show>
+        </div>
+    }
+}
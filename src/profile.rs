@@ -0,0 +1,328 @@
+//! User profiles with display name, bio, timezone, locale and avatar.
+
+use leptos::{
+    prelude::*,
+    server_fn::codec::{MultipartData, MultipartFormData},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+pub const MAX_BIO_LENGTH: usize = 500;
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+pub const DEFAULT_LOCALE: &str = "en";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(sqlx::FromRow))]
+pub struct Profile {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    /// IANA time zone name
+    pub timezone: String,
+    /// BCP 47 language tag
+    pub locale: String,
+    /// File name of the avatar image
+    pub avatar: Option<String>,
+}
+
+impl Profile {
+    /// The name to greet the user with.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.username)
+    }
+
+    pub fn avatar_url(&self) -> Option<String> {
+        self.avatar.as_ref().map(|name| format!("/avatars/{name}"))
+    }
+}
+
+/// Validated, editable profile fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileUpdate {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: String,
+    pub locale: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileError {
+    DisplayNameTooLong,
+    BioTooLong,
+    ControlCharacter,
+    InvalidTimezone(String),
+    InvalidLocale(String),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DisplayNameTooLong => write!(
+                f,
+                "Display name must be at most {MAX_DISPLAY_NAME_LENGTH} characters"
+            ),
+            Self::BioTooLong => write!(f, "Bio must be at most {MAX_BIO_LENGTH} characters"),
+            Self::ControlCharacter => f.write_str("Input contains control characters"),
+            Self::InvalidTimezone(tz) => write!(f, "Unknown timezone: {tz}"),
+            Self::InvalidLocale(locale) => write!(f, "Invalid locale: {locale}"),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+#[cfg(feature = "ssr")]
+impl ProfileUpdate {
+    /// Trims and validates user input. Blank display names and bios are
+    /// cleared, blank timezones and locales reset to the defaults.
+    pub fn new(
+        display_name: &str,
+        bio: &str,
+        timezone: &str,
+        locale: &str,
+    ) -> Result<Self, ProfileError> {
+        let display_name = display_name.trim();
+        if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+            return Err(ProfileError::DisplayNameTooLong);
+        }
+        if display_name.chars().any(char::is_control) {
+            return Err(ProfileError::ControlCharacter);
+        }
+
+        let bio = bio.trim();
+        if bio.chars().count() > MAX_BIO_LENGTH {
+            return Err(ProfileError::BioTooLong);
+        }
+        if bio
+            .chars()
+            .any(|c| c.is_control() && c != '\n' && c != '\r')
+        {
+            return Err(ProfileError::ControlCharacter);
+        }
+
+        let timezone = match timezone.trim() {
+            "" => DEFAULT_TIMEZONE,
+            tz => tz
+                .parse::<chrono_tz::Tz>()
+                .map_err(|_| ProfileError::InvalidTimezone(tz.to_owned()))?
+                .name(),
+        };
+
+        let locale = match locale.trim() {
+            "" => DEFAULT_LOCALE,
+            locale if is_language_tag(locale) => locale,
+            locale => return Err(ProfileError::InvalidLocale(locale.to_owned())),
+        };
+
+        Ok(Self {
+            display_name: Some(display_name.to_owned()).filter(|s| !s.is_empty()),
+            bio: Some(bio.to_owned()).filter(|s| !s.is_empty()),
+            timezone: timezone.to_owned(),
+            locale: locale.to_owned(),
+        })
+    }
+}
+
+/// Loosely checks the shape of a BCP 47 language tag such as `en` or
+/// `pt-BR`.
+#[cfg(feature = "ssr")]
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(feature = "ssr")]
+pub(crate) async fn load(username: &str) -> Result<Profile, ServerFnError> {
+    crate::database::get_profile(username)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .ok_or_else(|| ServerFnError::new("Session not found"))
+}
+
+/// Replaces the avatar of a user and removes the previous image.
+#[cfg(feature = "ssr")]
+async fn replace_avatar(username: &str, avatar: Option<&str>) -> Result<(), ServerFnError> {
+    use crate::{avatar, database, storage};
+
+    let previous = database::set_avatar(username, avatar)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    if let Some(previous) = previous
+        && let Err(e) = storage::storage().delete(&avatar::key(&previous)).await
+    {
+        tracing::warn!("Failed to delete avatar {previous}: {e}");
+    }
+    Ok(())
+}
+
+#[server(endpoint = "update_profile", client = crate::client::Client)]
+pub async fn update_profile(
+    token: String,
+    display_name: String,
+    bio: String,
+    timezone: String,
+    locale: String,
+) -> Result<Profile, ServerFnError> {
+    use crate::{app::authenticate, database};
+
+    let username = authenticate(&token).await?.username;
+    let update = ProfileUpdate::new(&display_name, &bio, &timezone, &locale)
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    database::update_profile(&username, &update)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    load(&username).await
+}
+
+/// Sets the avatar from a multipart form with a `token` field followed by an
+/// `avatar` file field.
+#[server(input = MultipartFormData, endpoint = "upload_avatar", client = crate::client::Client)]
+pub async fn upload_avatar(data: MultipartData) -> Result<Profile, ServerFnError> {
+    use crate::{
+        app::authenticate,
+        avatar::{self, AvatarError},
+        storage,
+    };
+
+    let mut form = data
+        .into_inner()
+        .ok_or_else(|| ServerFnError::new("Missing form data"))?;
+    let mut token = None;
+    let mut upload = None;
+    while let Some(mut field) = form
+        .next_field()
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+    {
+        match field.name() {
+            Some("token") => {
+                token = Some(
+                    field
+                        .text()
+                        .await
+                        .map_err(|e| ServerFnError::new(e.to_string()))?,
+                )
+            }
+            Some("avatar") => {
+                // Authenticate before buffering the upload
+                let token = token
+                    .as_deref()
+                    .ok_or_else(|| ServerFnError::new("Session not found"))?;
+                let username = authenticate(token).await?.username;
+
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field
+                    .chunk()
+                    .await
+                    .map_err(|e| ServerFnError::new(e.to_string()))?
+                {
+                    if bytes.len() + chunk.len() > avatar::MAX_UPLOAD_BYTES {
+                        return Err(ServerFnError::new(AvatarError::TooLarge.to_string()));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                upload = Some((username, bytes));
+            }
+            _ => {}
+        }
+    }
+
+    let (username, bytes) = upload.ok_or_else(|| ServerFnError::new("No image uploaded"))?;
+    let png = tokio::task::spawn_blocking(move || avatar::process(&bytes))
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+
+    let name = format!("{}.png", uuid::Uuid::new_v4().simple());
+    storage::storage()
+        .put(&avatar::key(&name), png)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))?;
+    replace_avatar(&username, Some(&name)).await?;
+    load(&username).await
+}
+
+#[server(endpoint = "remove_avatar", client = crate::client::Client)]
+pub async fn remove_avatar(token: String) -> Result<Profile, ServerFnError> {
+    use crate::app::authenticate;
+
+    let username = authenticate(&token).await?.username;
+    replace_avatar(&username, None).await?;
+    load(&username).await
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_name_falls_back_to_username() {
+        let mut profile = Profile {
+            username: "alice".into(),
+            display_name: None,
+            bio: None,
+            timezone: DEFAULT_TIMEZONE.into(),
+            locale: DEFAULT_LOCALE.into(),
+            avatar: None,
+        };
+        assert_eq!(profile.name(), "alice");
+        assert_eq!(profile.avatar_url(), None);
+
+        profile.display_name = Some("Alice A.".into());
+        profile.avatar = Some("abc.png".into());
+        assert_eq!(profile.name(), "Alice A.");
+        assert_eq!(profile.avatar_url().as_deref(), Some("/avatars/abc.png"));
+    }
+
+    #[test]
+    fn update_is_normalized() {
+        let update = ProfileUpdate::new("  Alice  ", "", "Europe/Berlin", "de-DE").unwrap();
+        assert_eq!(
+            update,
+            ProfileUpdate {
+                display_name: Some("Alice".into()),
+                bio: None,
+                timezone: "Europe/Berlin".into(),
+                locale: "de-DE".into(),
+            }
+        );
+
+        let update = ProfileUpdate::new("", "Line one\nLine two", " ", "").unwrap();
+        assert_eq!(update.display_name, None);
+        assert_eq!(update.bio.as_deref(), Some("Line one\nLine two"));
+        assert_eq!(update.timezone, DEFAULT_TIMEZONE);
+        assert_eq!(update.locale, DEFAULT_LOCALE);
+    }
+
+    #[test]
+    fn update_rejects_invalid_input() {
+        let long = "x".repeat(MAX_DISPLAY_NAME_LENGTH + 1);
+        assert_eq!(
+            ProfileUpdate::new(&long, "", "", ""),
+            Err(ProfileError::DisplayNameTooLong)
+        );
+        assert_eq!(
+            ProfileUpdate::new("", &"x".repeat(MAX_BIO_LENGTH + 1), "", ""),
+            Err(ProfileError::BioTooLong)
+        );
+        assert_eq!(
+            ProfileUpdate::new("a\u{7}b", "", "", ""),
+            Err(ProfileError::ControlCharacter)
+        );
+        assert_eq!(
+            ProfileUpdate::new("", "", "Mars/Olympus", ""),
+            Err(ProfileError::InvalidTimezone("Mars/Olympus".into()))
+        );
+        for locale in ["e", "english", "en_US", "en-", "de-DE-toolongsubtag"] {
+            assert_eq!(
+                ProfileUpdate::new("", "", "", locale),
+                Err(ProfileError::InvalidLocale(locale.into()))
+            );
+        }
+    }
+}
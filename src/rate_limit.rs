@@ -1,64 +1,432 @@
 use axum::{
+    Json,
     body::Body,
-    http::{Method, Request, StatusCode},
+    extract::MatchedPath,
+    http::{HeaderMap, Request, StatusCode, header},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 use std::{
     collections::HashMap,
+    env,
     net::IpAddr,
-    sync::Mutex,
+    sync::{Mutex, OnceLock},
     time::{Duration, Instant},
 };
 
-const MAX_REQUESTS: usize = 20;
-const WINDOW: Duration = Duration::from_secs(60);
-
-static CLIENTS: Mutex<Option<HashMap<IpAddr, Vec<Instant>>>> = Mutex::new(None);
-
-fn is_allowed(ip: IpAddr) -> bool {
-    let mut guard = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
-    let map = guard.get_or_insert_with(HashMap::new);
-    let now = Instant::now();
-    // Evict IPs with no recent requests to prevent unbounded growth
-    map.retain(|_, v| {
-        v.retain(|t| now.duration_since(*t) < WINDOW);
-        !v.is_empty()
-    });
-    let timestamps = map.entry(ip).or_default();
-    if timestamps.len() >= MAX_REQUESTS {
-        false
-    } else {
-        timestamps.push(now);
+use crate::{
+    audit::{self, EventType, Outcome},
+    client_ip, method,
+    route::{self, Pattern},
+};
+
+pub mod lists;
+mod store;
+
+pub use lists::{Access, Lists};
+pub use store::{Backend, MemoryStore, PostgresStore, RateLimitStore};
+
+/// IPv6 clients usually get at least a /64, so they share buckets per /64
+/// unless configured otherwise.
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+/// Rejections are audited once per client and route within this window, so
+/// that a flood of requests does not turn into a flood of audit events.
+const AUDIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Clients and routes whose rejections are tracked per window. Beyond that,
+/// further rejections go unaudited until the window passed.
+const AUDIT_CAPACITY: usize = 10_000;
+
+/// A token bucket which holds up to `burst` requests and refills at
+/// `requests` per `period`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: u32,
+}
+
+impl Policy {
+    pub const fn new(requests: u32, period: Duration, burst: u32) -> Self {
+        Self {
+            requests,
+            period,
+            burst,
+        }
+    }
+
+    /// Parses `<requests>/<seconds>[:<burst>]`, e.g. `5/60:3`. The burst
+    /// defaults to the number of requests.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst.trim().parse().ok()?)),
+            None => (s, None),
+        };
+        let (requests, seconds) = rate.split_once('/')?;
+        let requests: u32 = requests.trim().parse().ok()?;
+        let seconds: u64 = seconds.trim().parse().ok()?;
+        let burst = burst.unwrap_or(requests);
+        (requests > 0 && seconds > 0 && burst > 0)
+            .then(|| Self::new(requests, Duration::from_secs(seconds), burst))
+    }
+
+    /// Tokens added per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+
+    /// Time to refill an empty bucket.
+    fn refill(&self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.burst) / self.rate())
+    }
+}
+
+/// The policy of routes without their own and the policies by route, which
+/// is the name of a server function, e.g. `login`, or otherwise the path of
+/// the route without its leading slash, e.g. `attachments/{id}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub default: Policy,
+    pub routes: HashMap<String, Policy>,
+    /// Prefix length of the IPv6 networks whose clients share buckets.
+    pub ipv6_prefix: u8,
+    /// Routes which are never limited, e.g. webhooks of other services.
+    pub exempt: Vec<Pattern>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default: Policy::new(20, Duration::from_secs(60), 20),
+            routes: HashMap::from([
+                ("login".into(), Policy::new(10, Duration::from_secs(60), 5)),
+                (
+                    "register".into(),
+                    Policy::new(5, Duration::from_secs(600), 3),
+                ),
+                (
+                    "renew_session".into(),
+                    Policy::new(60, Duration::from_secs(60), 10),
+                ),
+            ]),
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads `RATE_LIMITS`, a comma separated list of `<route>=<policy>`
+    /// entries (see [`Policy::parse`]) which override the built-in policies.
+    /// The route `default` sets the policy of all other routes. Invalid
+    /// entries are logged and skipped. `RATE_LIMIT_IPV6_PREFIX` sets the
+    /// prefix length by which IPv6 clients are bucketed, and the comma
+    /// separated `RATE_LIMIT_EXEMPT` lists routes which are not limited,
+    /// e.g. `/webhooks/{provider}`.
+    pub fn from_env() -> Self {
+        let mut config = Self {
+            exempt: route::parse_list("RATE_LIMIT_EXEMPT"),
+            ..Self::default()
+        };
+        if let Ok(prefix) = env::var("RATE_LIMIT_IPV6_PREFIX") {
+            match prefix.trim().parse() {
+                Ok(prefix @ 0..=128) => config.ipv6_prefix = prefix,
+                _ => tracing::warn!(
+                    "ignoring invalid RATE_LIMIT_IPV6_PREFIX {prefix}, using {DEFAULT_IPV6_PREFIX}"
+                ),
+            }
+        }
+        for entry in env::var("RATE_LIMITS").unwrap_or_default().split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let Some((route, policy)) = entry
+                .split_once('=')
+                .and_then(|(route, policy)| Some((route.trim(), Policy::parse(policy)?)))
+            else {
+                tracing::warn!("ignoring invalid RATE_LIMITS entry {entry}");
+                continue;
+            };
+            if route == "default" {
+                config.default = policy;
+            } else {
+                config.routes.insert(route.to_owned(), policy);
+            }
+        }
+        config
+    }
+
+    /// The longest time any bucket takes to refill, after which unused
+    /// buckets can be discarded.
+    pub fn refill(&self) -> Duration {
+        self.routes
+            .values()
+            .chain([&self.default])
+            .map(Policy::refill)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// The configuration read from the environment on first use.
+pub fn config() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(Config::from_env)
+}
+
+/// The route of a request. Server functions are served at their name below
+/// `/api/`, other routes by the path they were added with, e.g.
+/// `/attachments/{id}`, so that all attachments share a bucket.
+fn route<B>(req: &Request<B>) -> &str {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(req.uri().path(), MatchedPath::as_str);
+    path.strip_prefix("/api/")
+        .unwrap_or_else(|| path.trim_start_matches('/'))
+}
+
+/// The outcome of a request, with the state of its bucket afterwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The burst of the policy.
+    pub limit: u32,
+    /// Requests which would be allowed right now.
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until the next request is allowed, zero if it was allowed.
+    pub retry_after: Duration,
+}
+
+impl Decision {
+    /// The decision for a bucket of the policy which holds `tokens` after
+    /// the request.
+    fn new(policy: Policy, tokens: f64, allowed: bool) -> Self {
+        let rate = policy.rate();
+        Self {
+            allowed,
+            limit: policy.burst,
+            remaining: tokens as u32,
+            reset: Duration::from_secs_f64((f64::from(policy.burst) - tokens).max(0.0) / rate),
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64((1.0 - tokens).max(0.0) / rate)
+            },
+        }
+    }
+
+    /// Adds the `RateLimit-Limit`, `RateLimit-Remaining` and
+    /// `RateLimit-Reset` headers of the IETF draft, and `Retry-After` if the
+    /// request was rejected.
+    fn headers(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", self.limit.into());
+        headers.insert("ratelimit-remaining", self.remaining.into());
+        headers.insert("ratelimit-reset", seconds(self.reset).into());
+        if !self.allowed {
+            headers.insert(header::RETRY_AFTER, seconds(self.retry_after).into());
+        }
+    }
+}
+
+/// Whole seconds, rounded up so that clients do not retry too early.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Token buckets per client and policy, kept in a [`RateLimitStore`].
+/// Routes with their own policy have their own buckets, all other routes
+/// share one.
+pub struct Limiter {
+    config: Config,
+    store: Box<dyn RateLimitStore>,
+    audits: Audits,
+}
+
+impl Limiter {
+    pub fn new(config: Config, store: Box<dyn RateLimitStore>) -> Self {
+        Self {
+            config,
+            store,
+            audits: Audits::new(AUDIT_WINDOW, AUDIT_CAPACITY),
+        }
+    }
+
+    /// Takes a token from the client's bucket for the route, if there is one.
+    pub async fn allow(&self, ip: IpAddr, route: &str) -> Result<Decision, sqlx::Error> {
+        let (name, policy) = match self.config.routes.get_key_value(route) {
+            Some((name, policy)) => (name.as_str(), *policy),
+            None => ("default", self.config.default),
+        };
+        let client = network(ip, self.config.ipv6_prefix);
+        self.store.take(client, name, policy).await
+    }
+}
+
+/// When rejections of a client for a route were last audited.
+struct Audits {
+    window: Duration,
+    capacity: usize,
+    last: Mutex<HashMap<(IpAddr, String), Instant>>,
+}
+
+impl Audits {
+    fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a rejection at `now` is to be audited, which is the case for
+    /// the first one of the client and route in a window.
+    fn audit(&self, client: IpAddr, route: &str, now: Instant) -> bool {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let recent = |at: &Instant| now.saturating_duration_since(*at) < self.window;
+        let key = (client, route.to_owned());
+        if last.get(&key).is_some_and(recent) {
+            return false;
+        }
+        if last.len() >= self.capacity {
+            last.retain(|_, at| recent(at));
+            if last.len() >= self.capacity {
+                return false;
+            }
+        }
+        last.insert(key, now);
         true
     }
 }
 
-fn extract_ip(req: &Request<Body>) -> IpAddr {
-    req.headers()
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .and_then(|s| s.trim().parse::<IpAddr>().ok())
-        .unwrap_or(IpAddr::from([127, 0, 0, 1]))
+/// The address whose buckets a client uses. A single IPv6 client can use
+/// every address of its network, so those are bucketed by network.
+fn network(ip: IpAddr, ipv6_prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(u32::from(128 - ipv6_prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(v6) & mask).into())
+        }
+        IpAddr::V4(_) => ip,
+    }
 }
 
-pub async fn check(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    if req.method() != Method::POST {
-        return Ok(next.run(req).await);
+fn limiter() -> &'static Limiter {
+    static LIMITER: OnceLock<Limiter> = OnceLock::new();
+    LIMITER.get_or_init(|| Limiter::new(config().clone(), Backend::from_env().store()))
+}
+
+/// Marks a request as exempt from rate limiting, as [`screen`] does for
+/// allowed clients.
+///
+/// Insert it as a request extension from a layer wrapping [`check`], as
+/// route layers only run after it. Exempt routes are configured by
+/// [`Config::exempt`] instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Exempt;
+
+/// Rejects denied clients and exempts allowed ones from [`check`]. Add it
+/// as a layer of the whole router, so that it also covers requests which no
+/// route matches.
+pub async fn screen(req: Request<Body>, next: Next) -> Response {
+    screen_with(&lists::current(), req, next).await
+}
+
+/// Requests of unknown clients are rejected rather than all sharing a bucket.
+/// They only occur if the server was started without connect info.
+fn unknown_client() -> Response {
+    tracing::error!("rejected request of unknown client, the server lacks connect info");
+    let body = serde_json::json!({"error": "Internal server error"});
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+}
+
+async fn screen_with(lists: &Lists, mut req: Request<Body>, next: Next) -> Response {
+    let Some(ip) = client_ip::of(&req) else {
+        return unknown_client();
+    };
+    match lists.access(ip) {
+        Access::Deny => {
+            tracing::debug!("rejected request of denied client {ip}");
+            let body = serde_json::json!({"error": "Forbidden"});
+            return (StatusCode::FORBIDDEN, Json(body)).into_response();
+        }
+        Access::Allow => {
+            req.extensions_mut().insert(Exempt);
+        }
+        Access::Limit => {}
     }
+    next.run(req).await
+}
 
-    if is_allowed(extract_ip(&req)) {
-        Ok(next.run(req).await)
-    } else {
-        Err(StatusCode::TOO_MANY_REQUESTS)
+/// Limits requests by client and route. Add it as a route layer, which sees
+/// the route a request matched (see [`Config`]).
+pub async fn check(req: Request<Body>, next: Next) -> Response {
+    enforce(limiter(), req, next).await
+}
+
+async fn enforce(limiter: &Limiter, req: Request<Body>, next: Next) -> Response {
+    let exempt = req.extensions().get::<Exempt>().is_some()
+        || limiter
+            .config
+            .exempt
+            .iter()
+            .any(|route| route.matches(req.uri().path()));
+    if method::is_safe(req.method()) || exempt {
+        return next.run(req).await;
     }
+
+    let Some(ip) = client_ip::of(&req) else {
+        return unknown_client();
+    };
+    let route = route(&req);
+    let decision = match limiter.allow(ip, route).await {
+        Ok(decision) => decision,
+        Err(e) => {
+            // Failing open keeps the application available when the store
+            // is not, at the cost of not limiting requests meanwhile
+            tracing::warn!("failed to check rate limit, allowing request: {e}");
+            return next.run(req).await;
+        }
+    };
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        let client = network(ip, limiter.config.ipv6_prefix);
+        if limiter.audits.audit(client, route, Instant::now()) {
+            let ctx = audit::Context::new(Some(ip), req.headers());
+            tokio::spawn(async move {
+                audit::record(EventType::RateLimited, Outcome::Failure, None, &ctx).await;
+            });
+        }
+        let body = serde_json::json!({
+            "error": "Too many requests",
+            "retry_after": seconds(decision.retry_after),
+        });
+        (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response()
+    };
+    decision.headers(response.headers_mut());
+    response
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::{Router, middleware, routing::get, routing::post};
+    use crate::client_ip::ClientIp;
+    use axum::{
+        Extension, Router,
+        extract::ConnectInfo,
+        http::Method,
+        middleware,
+        routing::{any, get, post},
+    };
+    use http_body_util::BodyExt;
+    use std::{net::SocketAddr, sync::Arc};
     use tower::ServiceExt;
 
     fn app() -> Router {
@@ -68,6 +436,10 @@ mod tests {
             .layer(middleware::from_fn(check))
     }
 
+    fn burst() -> usize {
+        config().default.burst as usize
+    }
+
     #[tokio::test]
     async fn get_requests_bypass_rate_limit() {
         let resp = app()
@@ -77,15 +449,24 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn requests_of_unknown_clients_are_rejected() {
+        let resp = app()
+            .oneshot(Request::post("/test").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
     #[tokio::test]
     async fn post_requests_are_rate_limited() {
         // Use a unique IP to avoid interference from other tests
         let ip = "10.99.99.99";
-        for i in 0..MAX_REQUESTS {
+        for i in 0..burst() {
             let resp = app()
                 .oneshot(
                     Request::post("/test")
-                        .header("x-forwarded-for", ip)
+                        .extension(ClientIp(ip.parse().unwrap()))
                         .body(Body::empty())
                         .unwrap(),
                 )
@@ -98,7 +479,7 @@ mod tests {
         let resp = app()
             .oneshot(
                 Request::post("/test")
-                    .header("x-forwarded-for", ip)
+                    .extension(ClientIp(ip.parse().unwrap()))
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -107,27 +488,373 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
+    fn methods() -> Vec<Method> {
+        [
+            "GET", "HEAD", "OPTIONS", "TRACE", "POST", "PUT", "PATCH", "DELETE", "CONNECT", "PURGE",
+        ]
+        .into_iter()
+        .map(|m| Method::from_bytes(m.as_bytes()).unwrap())
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn unsafe_methods_are_rate_limited() {
+        let app = Router::new()
+            .route("/any", any(|| async { "ok" }))
+            .layer(middleware::from_fn(check));
+        for (i, method) in methods().into_iter().enumerate() {
+            let ip = format!("10.97.0.{i}");
+            let request = || {
+                Request::builder()
+                    .method(method.clone())
+                    .uri("/any")
+                    .extension(ClientIp(ip.parse().unwrap()))
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            for _ in 0..burst() {
+                let resp = app.clone().oneshot(request()).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK, "{method}");
+            }
+            let resp = app.clone().oneshot(request()).await.unwrap();
+            let expected = if method::is_safe(&method) {
+                StatusCode::OK
+            } else {
+                StatusCode::TOO_MANY_REQUESTS
+            };
+            assert_eq!(resp.status(), expected, "{method}");
+        }
+    }
+
+    #[tokio::test]
+    async fn exempt_requests_bypass_rate_limit() {
+        let app = Router::new()
+            .route("/test", post(|| async { "ok" }))
+            .layer(middleware::from_fn(check))
+            .layer(Extension(Exempt));
+        for _ in 0..=burst() {
+            let resp = app
+                .clone()
+                .oneshot(
+                    Request::post("/test")
+                        .extension(ClientIp(IpAddr::from([10, 98, 98, 98])))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn exempt_routes_bypass_rate_limit() {
+        let limiter = Arc::new(Limiter::new(
+            Config {
+                exempt: vec!["/webhooks/{provider}".parse().unwrap()],
+                ..Config::default()
+            },
+            Box::new(MemoryStore::new()),
+        ));
+        let app = Router::new()
+            .route("/webhooks/{provider}", post(|| async { "ok" }))
+            .route("/test", post(|| async { "ok" }))
+            .layer(middleware::from_fn(move |req, next| {
+                let limiter = limiter.clone();
+                async move { enforce(&limiter, req, next).await }
+            }));
+        let request = |uri: &str| {
+            Request::post(uri)
+                .extension(ClientIp(IpAddr::from([10, 96, 96, 96])))
+                .body(Body::empty())
+                .unwrap()
+        };
+        for _ in 0..=burst() {
+            let resp = app
+                .clone()
+                .oneshot(request("/webhooks/github"))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!resp.headers().contains_key("ratelimit-limit"));
+        }
+        for _ in 0..burst() {
+            let resp = app.clone().oneshot(request("/test")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = app.oneshot(request("/test")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_of_untrusted_peers_is_ignored() {
+        let request = |forwarded_for: String| {
+            Request::post("/test")
+                .header("x-forwarded-for", forwarded_for)
+                .extension(ConnectInfo(SocketAddr::from(([10, 96, 96, 96], 4711))))
+                .body(Body::empty())
+                .unwrap()
+        };
+        for i in 0..burst() {
+            let resp = app().oneshot(request(format!("1.2.3.{i}"))).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = app().oneshot(request("1.2.3.255".into())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn responses_carry_rate_limit_headers() {
+        let request = || {
+            Request::post("/test")
+                .extension(ClientIp(IpAddr::from([10, 95, 95, 95])))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = app().oneshot(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let headers = resp.headers();
+        assert_eq!(headers["ratelimit-limit"], burst().to_string());
+        assert_eq!(headers["ratelimit-remaining"], (burst() - 1).to_string());
+        assert_eq!(headers["ratelimit-reset"], "3");
+        assert!(headers.get(header::RETRY_AFTER).is_none());
+
+        for _ in 1..burst() {
+            app().oneshot(request()).await.unwrap();
+        }
+        let resp = app().oneshot(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "3");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"error": "Too many requests", "retry_after": 3})
+        );
+
+        // Safe methods are not limited and do not carry the headers
+        let resp = app()
+            .oneshot(Request::get("/get").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(resp.headers().get("ratelimit-limit").is_none());
+    }
+
+    #[tokio::test]
+    async fn routes_have_their_own_policies() {
+        let limiter = Limiter::new(Config::default(), Box::new(MemoryStore::new()));
+        let ip = IpAddr::from([10, 77, 77, 77]);
+        let allow = async |route| limiter.allow(ip, route).await.unwrap().allowed;
+        for _ in 0..5 {
+            assert!(allow("login").await);
+        }
+        assert!(!allow("login").await);
+        // Renewing sessions neither uses nor is limited by the login budget
+        for _ in 0..10 {
+            assert!(allow("renew_session").await);
+        }
+        assert!(!allow("renew_session").await);
+        // Routes without a policy share the default bucket
+        for _ in 0..10 {
+            assert!(allow("update_note").await);
+            assert!(allow("attachments").await);
+        }
+        assert!(!allow("create_note").await);
+    }
+
+    #[tokio::test]
+    async fn ipv6_clients_are_bucketed_by_network() {
+        let limiter = Limiter::new(
+            Config {
+                default: Policy::new(1, Duration::from_secs(60), 2),
+                ..Config::default()
+            },
+            Box::new(MemoryStore::new()),
+        );
+        let allow = async |ip: &str| {
+            limiter
+                .allow(ip.parse().unwrap(), "test")
+                .await
+                .unwrap()
+                .allowed
+        };
+        assert!(allow("2001:db8:1:1::1").await);
+        assert!(allow("2001:db8:1:1:ffff::2").await);
+        // Any address of the same /64 shares the bucket
+        assert!(!allow("2001:db8:1:1::3").await);
+        assert!(allow("2001:db8:1:2::1").await);
+        // IPv4 addresses are never aggregated
+        assert!(allow("10.76.0.1").await);
+        assert!(allow("10.76.0.1").await);
+        assert!(allow("10.76.0.2").await);
+    }
+
     #[test]
-    fn extract_ip_from_forwarded_header() {
-        let req = Request::post("/test")
-            .header("x-forwarded-for", "192.168.1.1, 10.0.0.1")
-            .body(Body::empty())
+    fn rejections_are_audited_once_per_window() {
+        let audits = Audits::new(Duration::from_secs(60), 2);
+        let start = Instant::now();
+        let ip = |last: u8| IpAddr::from([10, 0, 0, last]);
+        assert!(audits.audit(ip(1), "login", start));
+        assert!(!audits.audit(ip(1), "login", start + Duration::from_secs(59)));
+        assert!(audits.audit(ip(1), "register", start));
+        // Nothing expired yet to make room for another client
+        assert!(!audits.audit(ip(2), "login", start + Duration::from_secs(30)));
+        assert!(audits.audit(ip(1), "login", start + Duration::from_secs(60)));
+        assert!(audits.audit(ip(2), "login", start + Duration::from_secs(61)));
+        assert_eq!(audits.last.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn networks_of_clients() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(network(ip("2001:db8::1:2:3:4"), 64), ip("2001:db8::"));
+        assert_eq!(network(ip("2001:db8::1:2:3:4"), 48), ip("2001:db8::"));
+        assert_eq!(network(ip("2001:db8:5::1"), 32), ip("2001:db8::"));
+        assert_eq!(network(ip("2001:db8::1"), 128), ip("2001:db8::1"));
+        assert_eq!(network(ip("2001:db8::1"), 0), ip("::"));
+        assert_eq!(network(ip("192.0.2.1"), 64), ip("192.0.2.1"));
+    }
+
+    #[tokio::test]
+    async fn allowed_and_denied_clients() {
+        let lists = Arc::new(
+            Lists::parse("allow 10.75.0.0/16\ndeny 10.74.0.0/16\ndeny 10.75.6.6").unwrap(),
+        );
+        let app = Router::new()
+            .route("/any", any(|| async { "ok" }))
+            .route_layer(middleware::from_fn(check))
+            .layer(middleware::from_fn(move |req, next| {
+                let lists = lists.clone();
+                async move { screen_with(&lists, req, next).await }
+            }));
+        let request = |method: Method, ip: [u8; 4]| {
+            Request::builder()
+                .method(method)
+                .uri("/any")
+                .extension(ClientIp(IpAddr::from(ip)))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Allowed clients are never limited and get no headers
+        for _ in 0..=burst() {
+            let resp = app
+                .clone()
+                .oneshot(request(Method::POST, [10, 75, 0, 1]))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(resp.headers().get("ratelimit-limit").is_none());
+        }
+        // Denied clients are rejected outright, whatever the method
+        for (method, ip) in [
+            (Method::GET, [10, 74, 0, 1]),
+            (Method::POST, [10, 74, 0, 1]),
+            (Method::POST, [10, 75, 6, 6]),
+        ] {
+            let resp = app.clone().oneshot(request(method, ip)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, serde_json::json!({"error": "Forbidden"}));
+        }
+        // Everyone else is limited
+        let resp = app
+            .clone()
+            .oneshot(request(Method::POST, [10, 73, 0, 1]))
+            .await
             .unwrap();
-        assert_eq!(extract_ip(&req), IpAddr::from([192, 168, 1, 1]));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["ratelimit-limit"], burst().to_string());
+        // Denied clients are also rejected where no route matches
+        let resp = app
+            .oneshot(
+                Request::post("/missing")
+                    .extension(ClientIp(IpAddr::from([10, 74, 0, 1])))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
     }
 
     #[test]
-    fn extract_ip_default_without_header() {
-        let req = Request::post("/test").body(Body::empty()).unwrap();
-        assert_eq!(extract_ip(&req), IpAddr::from([127, 0, 0, 1]));
+    fn refill_time_of_slowest_policy() {
+        assert_eq!(Config::default().refill(), Duration::from_secs(360));
+        let config = Config {
+            default: Policy::new(1, Duration::from_secs(3600), 2),
+            routes: HashMap::new(),
+            ..Config::default()
+        };
+        assert_eq!(config.refill(), Duration::from_secs(7200));
+    }
+
+    #[tokio::test]
+    async fn route_names() {
+        let app = Router::new()
+            .route("/api/login", post(|| async { "ok" }))
+            .route("/api/totp2", post(|| async { "ok" }))
+            .route("/attachments", post(|| async { "ok" }))
+            .route("/attachments/{id}", post(|| async { "ok" }))
+            .route_layer(middleware::from_fn(
+                |req: Request<Body>, next: Next| async move {
+                    let route = route(&req).to_owned();
+                    let mut response = next.run(req).await;
+                    response
+                        .headers_mut()
+                        .insert("route", route.parse().unwrap());
+                    response
+                },
+            ));
+        for (path, expected) in [
+            ("/api/login", "login"),
+            ("/api/totp2", "totp2"),
+            ("/attachments", "attachments"),
+            ("/attachments/5", "attachments/{id}"),
+            ("/attachments/6", "attachments/{id}"),
+        ] {
+            let resp = app
+                .clone()
+                .oneshot(Request::post(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(resp.headers()["route"], expected, "{path}");
+        }
+        // Without a matched route, e.g. outside of route layers
+        let req = Request::post("/attachments/7").body(()).unwrap();
+        assert_eq!(route(&req), "attachments/7");
     }
 
     #[test]
-    fn is_allowed_enforces_limit() {
-        let ip = IpAddr::from([10, 88, 88, 88]);
-        for _ in 0..MAX_REQUESTS {
-            assert!(is_allowed(ip));
+    fn server_functions_are_served_at_their_name() {
+        for (path, _) in leptos::server_fn::axum::server_fn_paths() {
+            let name = path.strip_prefix("/api/").unwrap_or_default();
+            // Generated paths end in a hash of 20 digits at most
+            let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            assert!(
+                !name.is_empty() && !name.contains('/') && digits < 8,
+                "server function at {path} needs an endpoint"
+            );
         }
-        assert!(!is_allowed(ip));
+    }
+
+    #[test]
+    fn policy_parsing() {
+        assert_eq!(
+            Policy::parse("5/60:3"),
+            Some(Policy::new(5, Duration::from_secs(60), 3))
+        );
+        assert_eq!(
+            Policy::parse(" 20 / 60 "),
+            Some(Policy::new(20, Duration::from_secs(60), 20))
+        );
+        assert_eq!(Policy::parse("5"), None);
+        assert_eq!(Policy::parse("0/60"), None);
+        assert_eq!(Policy::parse("5/0"), None);
+        assert_eq!(Policy::parse("5/60:0"), None);
+        assert_eq!(Policy::parse("5/60:x"), None);
     }
 }
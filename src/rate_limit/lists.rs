@@ -0,0 +1,258 @@
+//! Networks which bypass rate limiting or are rejected outright.
+//!
+//! The lists combine `RATE_LIMIT_ALLOW` and `RATE_LIMIT_DENY` with the file
+//! named by `RATE_LIMIT_LISTS`, which is reloaded whenever it changes so
+//! that abusive networks can be blocked without a restart.
+
+use std::{
+    env, fs,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+
+use crate::client_ip::Cidr;
+
+/// How often the lists file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do with the requests of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Never limited, e.g. health checkers and internal networks.
+    Allow,
+    /// Rejected outright.
+    Deny,
+    /// Limited by the policies.
+    Limit,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lists {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Lists {
+    /// Parses lines of `allow <network>` or `deny <network>`. Empty lines and
+    /// lines starting with `#` are ignored.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut lists = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |e: String| format!("line {}: {e}", number + 1);
+            let (list, network) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(format!("expected allow or deny and a network: {line}")))?;
+            let network = network.parse().map_err(invalid)?;
+            match list {
+                "allow" => lists.allow.push(network),
+                "deny" => lists.deny.push(network),
+                other => return Err(invalid(format!("unknown list {other}"))),
+            }
+        }
+        Ok(lists)
+    }
+
+    /// Reads the lists from a file, see [`Lists::parse`].
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::parse(&content).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Reads `RATE_LIMIT_ALLOW` and `RATE_LIMIT_DENY`. Unlike other lists of
+    /// networks an invalid entry is an error, since dropping it from the
+    /// denylist would let the network through.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let networks = |key: &str| {
+            var(key)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| entry.parse().map_err(|e| format!("{key}: {e}")))
+                .collect::<Result<Vec<Cidr>, _>>()
+        };
+        Ok(Self {
+            allow: networks("RATE_LIMIT_ALLOW")?,
+            deny: networks("RATE_LIMIT_DENY")?,
+        })
+    }
+
+    /// Reads the lists from the environment and the lists file.
+    pub fn load() -> Result<Self, String> {
+        Self::load_with(env::var_os("RATE_LIMIT_LISTS").as_deref().map(Path::new))
+    }
+
+    fn load_with(file: Option<&Path>) -> Result<Self, String> {
+        let mut lists = Self::from_env()?;
+        if let Some(path) = file {
+            let file = Self::read(path)?;
+            lists.allow.extend(file.allow);
+            lists.deny.extend(file.deny);
+        }
+        Ok(lists)
+    }
+
+    /// Denied networks take precedence over allowed ones.
+    pub fn access(&self, ip: IpAddr) -> Access {
+        let contains = |list: &[Cidr]| list.iter().any(|cidr| cidr.contains(ip));
+        if contains(&self.deny) {
+            Access::Deny
+        } else if contains(&self.allow) {
+            Access::Allow
+        } else {
+            Access::Limit
+        }
+    }
+}
+
+fn lists() -> &'static RwLock<Arc<Lists>> {
+    static LISTS: OnceLock<RwLock<Arc<Lists>>> = OnceLock::new();
+    LISTS.get_or_init(|| {
+        // Running without the denylist is worse than not running
+        let lists = Lists::load().unwrap_or_else(|e| panic!("invalid rate limit lists: {e}"));
+        RwLock::new(Arc::new(lists))
+    })
+}
+
+/// The lists currently in effect, loaded on first use. Call [`reload`] on
+/// startup to fail early if the lists file is invalid.
+pub fn current() -> Arc<Lists> {
+    lists().read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Loads the lists again. They are left unchanged if they are invalid, so that
+/// a typo does not lift the denylist.
+pub fn reload() -> Result<(), String> {
+    let loaded = Lists::load()?;
+    tracing::info!(
+        "loaded rate limit lists with {} allowed and {} denied networks",
+        loaded.allow.len(),
+        loaded.deny.len()
+    );
+    *lists().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+    Ok(())
+}
+
+/// Reloads the lists whenever the file of `RATE_LIMIT_LISTS` changes.
+pub fn watch() {
+    let Ok(path) = env::var("RATE_LIMIT_LISTS") else {
+        return;
+    };
+    tokio::spawn(async move {
+        let modified = async || {
+            tokio::fs::metadata(&path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        };
+        let mut last = modified().await;
+        let mut ticks = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let current = modified().await;
+            if current == last {
+                continue;
+            }
+            last = current;
+            if let Err(e) = reload() {
+                tracing::warn!("keeping previous rate limit lists: {e}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        let lists = Lists::parse(
+            "# Health checks\nallow 10.0.0.0/8\n\n  deny 203.0.113.7\ndeny\t2001:db8::/32\n",
+        )
+        .unwrap();
+        assert_eq!(lists.allow, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(
+            lists.deny,
+            vec![
+                "203.0.113.7".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap()
+            ]
+        );
+
+        assert_eq!(
+            Lists::parse("allow 10.0.0.0/8\nblock 1.2.3.4").unwrap_err(),
+            "line 2: unknown list block"
+        );
+        assert_eq!(
+            Lists::parse("deny 1.2.3.4/33").unwrap_err(),
+            "line 1: invalid network 1.2.3.4/33"
+        );
+        assert!(Lists::parse("deny").is_err());
+        assert_eq!(Lists::parse("").unwrap(), Lists::default());
+    }
+
+    #[test]
+    fn reading_files() {
+        let path = env::temp_dir().join(format!("rate-limit-lists-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "deny 203.0.113.0/24\n").unwrap();
+        let lists = Lists::read(&path).unwrap();
+        assert_eq!(lists.access(ip("203.0.113.9")), Access::Deny);
+        fs::remove_file(&path).unwrap();
+        assert!(Lists::read(&path).is_err());
+    }
+
+    #[test]
+    fn invalid_files_fail_to_load() {
+        let path = env::temp_dir().join(format!("rate-limit-lists-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "deny 203.0.113.0/24\nblock 10.0.0.0/8\n").unwrap();
+        let error = Lists::load_with(Some(&path)).unwrap_err();
+        assert!(error.ends_with("line 2: unknown list block"), "{error}");
+        fs::remove_file(&path).unwrap();
+        assert!(Lists::load_with(Some(&path)).is_err());
+        assert_eq!(Lists::load_with(None), Lists::from_env());
+    }
+
+    #[test]
+    fn invalid_variables_fail_to_load() {
+        let vars = |allow: &'static str, deny: &'static str| {
+            Lists::from_vars(move |key| match key {
+                "RATE_LIMIT_ALLOW" => Some(allow.into()),
+                "RATE_LIMIT_DENY" => Some(deny.into()),
+                _ => None,
+            })
+        };
+        let lists = vars("10.0.0.0/8", " 203.0.113.7, 2001:db8::/32 ,").unwrap();
+        assert_eq!(lists.allow.len(), 1);
+        assert_eq!(lists.deny.len(), 2);
+        assert_eq!(
+            vars("", "203.0.113.7, 203.0.113.0/33").unwrap_err(),
+            "RATE_LIMIT_DENY: invalid network 203.0.113.0/33"
+        );
+        assert!(vars("10.0.0.0/8x", "").is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let lists = Lists::parse("allow 10.0.0.0/8\ndeny 10.6.6.0/24\nallow ::1").unwrap();
+        assert_eq!(lists.access(ip("10.1.1.1")), Access::Allow);
+        assert_eq!(lists.access(ip("10.6.6.6")), Access::Deny);
+        assert_eq!(lists.access(ip("::1")), Access::Allow);
+        assert_eq!(lists.access(ip("8.8.8.8")), Access::Limit);
+        assert_eq!(Lists::default().access(ip("10.1.1.1")), Access::Limit);
+    }
+}
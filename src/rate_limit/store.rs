@@ -0,0 +1,390 @@
+//! Where the token buckets of the [`Limiter`](super::Limiter) live.
+//!
+//! The in-memory store is fast but only limits the requests each replica
+//! sees, so behind a load balancer clients get the limit once per replica.
+//! The Postgres store shares the buckets between all replicas, at the cost
+//! of a query per limited request.
+
+use futures::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::IpAddr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use super::{Decision, Policy};
+use crate::database;
+
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the client's bucket of the named policy if there
+    /// is one. Missing buckets start full.
+    fn take<'a>(
+        &'a self,
+        client: IpAddr,
+        name: &'a str,
+        policy: Policy,
+    ) -> BoxFuture<'a, Result<Decision, sqlx::Error>>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+impl Backend {
+    /// Reads `RATE_LIMIT_STORE`, which is either `memory` (default) or
+    /// `postgres`.
+    pub fn from_env() -> Self {
+        match std::env::var("RATE_LIMIT_STORE").as_deref() {
+            Ok("postgres") => Self::Postgres,
+            Ok("memory") | Err(_) => Self::Memory,
+            Ok(other) => {
+                tracing::warn!("unknown RATE_LIMIT_STORE {other}, using memory");
+                Self::Memory
+            }
+        }
+    }
+
+    pub fn store(self) -> Box<dyn RateLimitStore> {
+        match self {
+            Self::Memory => {
+                let store = MemoryStore::new();
+                store.evict_every(EVICTION_INTERVAL);
+                Box::new(store)
+            }
+            Self::Postgres => Box::new(PostgresStore),
+        }
+    }
+}
+
+/// Shards of the in-memory store. Requests for different clients mostly
+/// hit different shards, so they rarely wait for each other.
+const SHARDS: usize = 64;
+
+/// How often buckets which have refilled are dropped from memory.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Theoretical arrival times of the next request per client and policy, in
+/// nanoseconds since the store was created.
+type Shard = RwLock<HashMap<IpAddr, HashMap<Box<str>, AtomicU64>>>;
+
+/// Keeps the buckets in memory of this replica.
+///
+/// Buckets are implemented with the generic cell rate algorithm (GCRA),
+/// which behaves exactly like a token bucket but only stores a single
+/// timestamp, updated by compare-and-swap. Requests of known clients only
+/// take a shared lock of their shard, so they never block each other.
+/// Buckets which have refilled completely are evicted in the background,
+/// see [`MemoryStore::evict_every`].
+#[derive(Debug)]
+pub struct MemoryStore {
+    epoch: Instant,
+    shards: Arc<[Shard]>,
+    hasher: RandomState,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            shards: (0..SHARDS).map(|_| Shard::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn shard(&self, client: IpAddr) -> &Shard {
+        &self.shards[self.hasher.hash_one(client) as usize % SHARDS]
+    }
+
+    fn nanos(&self, instant: Instant) -> u64 {
+        let nanos = instant.saturating_duration_since(self.epoch).as_nanos();
+        u64::try_from(nanos).unwrap_or(u64::MAX)
+    }
+
+    fn take_at(&self, client: IpAddr, name: &str, policy: Policy, now: Instant) -> Decision {
+        let shard = self.shard(client);
+        {
+            let buckets = shard.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(tat) = buckets.get(&client).and_then(|names| names.get(name)) {
+                return self.update(tat, policy, now);
+            }
+        }
+        let mut buckets = shard.write().unwrap_or_else(|e| e.into_inner());
+        let tat = buckets
+            .entry(client)
+            .or_default()
+            .entry(name.into())
+            .or_insert_with(|| AtomicU64::new(0));
+        self.update(tat, policy, now)
+    }
+
+    /// Takes a token if the request does not arrive earlier than the burst
+    /// allows, i.e. if the arrival time after it is within the burst's
+    /// worth of emission intervals from now.
+    fn update(&self, tat: &AtomicU64, policy: Policy, now: Instant) -> Decision {
+        let now = self.nanos(now);
+        let interval = u64::try_from(policy.period.as_nanos() / u128::from(policy.requests))
+            .unwrap_or(u64::MAX)
+            .max(1);
+        let capacity = interval.saturating_mul(u64::from(policy.burst));
+        let tokens = |tat: u64| capacity.saturating_sub(tat - now) as f64 / interval as f64;
+
+        let mut current = tat.load(Ordering::Acquire);
+        loop {
+            let start = current.max(now);
+            let next = start.saturating_add(interval);
+            if next - now > capacity {
+                // Rejected requests leave the bucket untouched
+                return Decision::new(policy, tokens(start), false);
+            }
+            match tat.compare_exchange_weak(current, next, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Decision::new(policy, tokens(next), true),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Drops the buckets which have refilled completely by `now`, as they
+    /// behave like new ones.
+    fn evict_at(shards: &[Shard], now: u64) {
+        for shard in shards {
+            let mut buckets = shard.write().unwrap_or_else(|e| e.into_inner());
+            buckets.retain(|_, names| {
+                names.retain(|_, tat| *tat.get_mut() > now);
+                !names.is_empty()
+            });
+        }
+    }
+
+    /// Evicts refilled buckets periodically for as long as the store lives.
+    /// Must be called within a Tokio runtime.
+    pub fn evict_every(&self, interval: Duration) {
+        let shards = Arc::downgrade(&self.shards);
+        let epoch = self.epoch;
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(shards) = shards.upgrade() else {
+                    break;
+                };
+                let now = Instant::now().saturating_duration_since(epoch).as_nanos();
+                Self::evict_at(&shards, u64::try_from(now).unwrap_or(u64::MAX));
+            }
+        });
+    }
+
+    /// Number of buckets currently kept.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let buckets = shard.read().unwrap_or_else(|e| e.into_inner());
+                buckets.values().map(HashMap::len).sum::<usize>()
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take<'a>(
+        &'a self,
+        client: IpAddr,
+        name: &'a str,
+        policy: Policy,
+    ) -> BoxFuture<'a, Result<Decision, sqlx::Error>> {
+        let decision = self.take_at(client, name, policy, Instant::now());
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+/// Keeps the buckets in the database shared by all replicas. Each request
+/// updates its bucket with a single atomic upsert.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostgresStore;
+
+impl RateLimitStore for PostgresStore {
+    fn take<'a>(
+        &'a self,
+        client: IpAddr,
+        name: &'a str,
+        policy: Policy,
+    ) -> BoxFuture<'a, Result<Decision, sqlx::Error>> {
+        Box::pin(async move {
+            let (tokens, allowed) = database::take_rate_limit_token(
+                &client.to_string(),
+                name,
+                f64::from(policy.burst),
+                policy.rate(),
+            )
+            .await?;
+            Ok(Decision::new(policy, tokens, allowed))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rate_limit::seconds, testing};
+
+    /// Behavior every store must have.
+    async fn behaves_like_token_buckets(store: &dyn RateLimitStore) {
+        let policy = Policy::new(1, Duration::from_secs(60), 3);
+        let client = IpAddr::from([10, 1, 1, 1]);
+        for remaining in [2, 1, 0] {
+            let decision = store.take(client, "test", policy).await.unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.limit, 3);
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.retry_after, Duration::ZERO);
+        }
+        let decision = store.take(client, "test", policy).await.unwrap();
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(seconds(decision.retry_after), 60);
+        assert_eq!(seconds(decision.reset), 180);
+
+        // Other clients and policies have their own buckets
+        let other = IpAddr::from([10, 1, 1, 2]);
+        assert!(store.take(other, "test", policy).await.unwrap().allowed);
+        assert!(store.take(client, "other", policy).await.unwrap().allowed);
+        let v6 = "2001:db8::1".parse().unwrap();
+        assert!(store.take(v6, "test", policy).await.unwrap().allowed);
+
+        // Buckets refill over time
+        let fast = Policy::new(2, Duration::from_secs(1), 1);
+        assert!(store.take(client, "fast", fast).await.unwrap().allowed);
+        let decision = store.take(client, "fast", fast).await.unwrap();
+        assert!(!decision.allowed);
+        assert!(decision.retry_after <= Duration::from_millis(500));
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(store.take(client, "fast", fast).await.unwrap().allowed);
+        assert!(!store.take(client, "fast", fast).await.unwrap().allowed);
+
+        // Concurrent requests never take more tokens than the bucket holds
+        let client = IpAddr::from([10, 1, 1, 3]);
+        let decisions = futures::future::join_all((0..20).map(|_| {
+            store.take(
+                client,
+                "concurrent",
+                Policy::new(1, Duration::from_secs(60), 5),
+            )
+        }))
+        .await;
+        let allowed = decisions
+            .into_iter()
+            .filter(|decision| decision.as_ref().unwrap().allowed)
+            .count();
+        assert_eq!(allowed, 5);
+    }
+
+    #[tokio::test]
+    async fn memory_store_behaves_like_token_buckets() {
+        behaves_like_token_buckets(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn postgres_store_behaves_like_token_buckets() {
+        testing::isolated(async {
+            behaves_like_token_buckets(&PostgresStore).await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn postgres_store_deletes_idle_buckets() {
+        testing::isolated(async {
+            let policy = Policy::new(1, Duration::from_secs(60), 3);
+            let client = IpAddr::from([10, 2, 2, 2]);
+            PostgresStore.take(client, "test", policy).await.unwrap();
+            let deleted = database::delete_idle_rate_limit_buckets(Duration::from_secs(60))
+                .await
+                .unwrap();
+            assert_eq!(deleted, 0);
+
+            sqlx::query("UPDATE rate_limit_buckets SET updated_at = NOW() - INTERVAL '2 minutes'")
+                .execute(&database::pool())
+                .await
+                .unwrap();
+            let deleted = database::delete_idle_rate_limit_buckets(Duration::from_secs(60))
+                .await
+                .unwrap();
+            assert_eq!(deleted, 1);
+        })
+        .await;
+    }
+
+    #[test]
+    fn memory_buckets_refill_and_are_evicted() {
+        let store = MemoryStore::new();
+        let policy = Policy::new(1, Duration::from_secs(10), 3);
+        let ip = IpAddr::from([10, 88, 88, 88]);
+        let start = Instant::now();
+        let take = |ip, now| store.take_at(ip, "test", policy, now).allowed;
+        for _ in 0..3 {
+            assert!(take(ip, start));
+        }
+        assert!(!take(ip, start));
+        assert!(!take(ip, start + Duration::from_secs(9)));
+        assert!(take(ip, start + Duration::from_secs(10)));
+        assert!(!take(ip, start + Duration::from_secs(10)));
+
+        assert!(take(IpAddr::from([10, 88, 88, 90]), start));
+        assert_eq!(store.len(), 2);
+
+        // Refilled buckets are evicted, but never exceed the burst
+        let later = start + Duration::from_secs(3600);
+        MemoryStore::evict_at(&store.shards, store.nanos(start + Duration::from_secs(20)));
+        assert_eq!(store.len(), 1);
+        MemoryStore::evict_at(&store.shards, store.nanos(later));
+        assert!(store.is_empty());
+        for _ in 0..3 {
+            assert!(take(ip, later));
+        }
+        assert!(!take(ip, later));
+    }
+
+    #[test]
+    fn decisions_report_bucket_state() {
+        let store = MemoryStore::new();
+        let policy = Policy::new(1, Duration::from_secs(10), 3);
+        let ip = IpAddr::from([10, 86, 86, 86]);
+        let start = Instant::now();
+        let decision = store.take_at(ip, "test", policy, start);
+        assert_eq!(
+            decision,
+            Decision {
+                allowed: true,
+                limit: 3,
+                remaining: 2,
+                reset: Duration::from_secs(10),
+                retry_after: Duration::ZERO,
+            }
+        );
+        store.take_at(ip, "test", policy, start);
+        store.take_at(ip, "test", policy, start);
+        let decision = store.take_at(ip, "test", policy, start + Duration::from_millis(2500));
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(seconds(decision.retry_after), 8);
+        assert_eq!(seconds(decision.reset), 28);
+    }
+}
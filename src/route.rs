@@ -0,0 +1,106 @@
+//! Route patterns, which select routes of the router in the configuration of
+//! middleware wrapping it, e.g. to exempt them from CSRF protection.
+
+use std::{env, str::FromStr};
+
+/// A path in the syntax of the router, e.g. `/webhooks/{provider}`, where
+/// `{name}` matches one segment and a trailing `{*name}` the rest of the
+/// path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern(String);
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid route {s}");
+        let rest = s.strip_prefix('/').ok_or_else(invalid)?;
+        let segments: Vec<_> = rest.split('/').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            let wildcard = segment.starts_with("{*");
+            let braces = segment.contains(['{', '}']);
+            let param = segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}');
+            if (braces && !param) || (wildcard && i + 1 < segments.len()) {
+                return Err(invalid());
+            }
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl Pattern {
+    pub fn matches(&self, path: &str) -> bool {
+        let mut segments = path.trim_start_matches('/').split('/');
+        for expected in self.0.trim_start_matches('/').split('/') {
+            if expected.starts_with("{*") {
+                return true;
+            }
+            match segments.next() {
+                Some(segment) if expected.starts_with('{') => {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                }
+                Some(segment) if segment == expected => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+/// Parses a comma separated list of patterns from an environment variable.
+pub fn parse_list(name: &str) -> Vec<Pattern> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            entry
+                .parse()
+                .inspect_err(|e| tracing::warn!("ignoring {name} entry: {e}"))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matching() {
+        let webhook = pattern("/webhooks/{provider}");
+        assert!(webhook.matches("/webhooks/github"));
+        assert!(!webhook.matches("/webhooks"));
+        assert!(!webhook.matches("/webhooks/"));
+        assert!(!webhook.matches("/webhooks/github/push"));
+        assert!(!webhook.matches("/webhook/github"));
+
+        let files = pattern("/files/{*path}");
+        assert!(files.matches("/files/a"));
+        assert!(files.matches("/files/a/b/c"));
+        assert!(!files.matches("/other/a"));
+
+        assert!(pattern("/").matches("/"));
+        assert!(!pattern("/").matches("/healthz"));
+        assert!(pattern("/healthz").matches("/healthz"));
+    }
+
+    #[test]
+    fn parsing() {
+        assert!("/webhooks/{provider}".parse::<Pattern>().is_ok());
+        assert_eq!(
+            "webhooks".parse::<Pattern>().unwrap_err(),
+            "invalid route webhooks"
+        );
+        assert!("/files/{*path}/more".parse::<Pattern>().is_err());
+        assert!("/files/{}".parse::<Pattern>().is_err());
+        assert!("/files/x{id}".parse::<Pattern>().is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Search over the notes of the current user.
+//!
+//! The default backend uses the generated `tsvector` column of the notes
+//! table for ranked full-text search with stemming. The LIKE backend only
+//! relies on portable SQL and serves databases without full-text search.
+
+use leptos::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_QUERY_LENGTH: usize = 200;
+pub const MAX_RESULTS: i64 = 20;
+
+/// Marks the start and end of a highlighted match in raw snippets.
+#[cfg(feature = "ssr")]
+const START_MATCH: char = '\u{2}';
+#[cfg(feature = "ssr")]
+const STOP_MATCH: char = '\u{3}';
+
+/// Characters of context shown around a match by the LIKE backend.
+#[cfg(feature = "ssr")]
+const SNIPPET_CONTEXT: usize = 40;
+
+/// A piece of a snippet, highlighted if it matched the query.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Fragment {
+    pub text: String,
+    pub highlight: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub note_id: i64,
+    pub title: String,
+    pub snippet: Vec<Fragment>,
+    pub rank: f32,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    FullText,
+    Like,
+}
+
+#[cfg(feature = "ssr")]
+impl Backend {
+    /// Reads `SEARCH_BACKEND`, which is either `fulltext` (default) or `like`.
+    pub fn from_env() -> Self {
+        match std::env::var("SEARCH_BACKEND").as_deref() {
+            Ok("like") => Self::Like,
+            Ok("fulltext") | Err(_) => Self::FullText,
+            Ok(other) => {
+                tracing::warn!("unknown SEARCH_BACKEND {other}, using fulltext");
+                Self::FullText
+            }
+        }
+    }
+}
+
+/// A matching note as returned by the database, with the snippet delimited
+/// by [`START_MATCH`] and [`STOP_MATCH`].
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Hit {
+    pub id: i64,
+    pub title: String,
+    pub snippet: String,
+    pub rank: f32,
+}
+
+/// Searches the notes of a user, best matches first.
+#[cfg(feature = "ssr")]
+pub async fn search(
+    backend: Backend,
+    username: &str,
+    query: &str,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    use crate::database;
+
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let results = match backend {
+        Backend::FullText => {
+            let options = format!(
+                "StartSel={START_MATCH}, StopSel={STOP_MATCH}, \
+                 MaxFragments=2, MaxWords=20, MinWords=5, FragmentDelimiter=\" … \""
+            );
+            database::search_notes(username, query, &options, MAX_RESULTS)
+                .await?
+                .into_iter()
+                .map(|hit| SearchResult {
+                    note_id: hit.id,
+                    title: hit.title,
+                    snippet: parse_snippet(&hit.snippet),
+                    rank: hit.rank,
+                })
+                .collect()
+        }
+        Backend::Like => database::search_notes_like(username, query, MAX_RESULTS)
+            .await?
+            .into_iter()
+            .map(|hit| SearchResult {
+                note_id: hit.id,
+                snippet: highlight(&hit.snippet, query),
+                title: hit.title,
+                rank: hit.rank,
+            })
+            .collect(),
+    };
+    Ok(results)
+}
+
+/// Splits a snippet delimited by [`START_MATCH`] and [`STOP_MATCH`] into
+/// fragments.
+#[cfg(feature = "ssr")]
+fn parse_snippet(snippet: &str) -> Vec<Fragment> {
+    let mut fragments = Vec::new();
+    let mut rest = snippet;
+    while let Some(start) = rest.find(START_MATCH) {
+        let (before, after) = rest.split_at(start);
+        let after = &after[START_MATCH.len_utf8()..];
+        let end = after.find(STOP_MATCH).unwrap_or(after.len());
+        push(&mut fragments, before, false);
+        push(&mut fragments, &after[..end], true);
+        rest = after.get(end + STOP_MATCH.len_utf8()..).unwrap_or_default();
+    }
+    push(&mut fragments, rest, false);
+    fragments
+}
+
+/// Builds a snippet around the first case-insensitive occurrence of the query
+/// in the text, highlighting all occurrences within it.
+#[cfg(feature = "ssr")]
+fn highlight(text: &str, query: &str) -> Vec<Fragment> {
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    let needle: Vec<char> = query.chars().flat_map(|c| c.to_lowercase()).collect();
+    // Lowercasing may change the length, in which case positions do not map
+    // back to the original text
+    let find = |from: usize| {
+        (lower.len() == chars.len() && !needle.is_empty())
+            .then(|| {
+                lower[from..]
+                    .windows(needle.len())
+                    .position(|w| w == needle)
+            })
+            .flatten()
+            .map(|i| i + from)
+    };
+
+    let first = find(0).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_CONTEXT);
+    let end = (first + needle.len() + SNIPPET_CONTEXT).min(chars.len());
+
+    let mut fragments = Vec::new();
+    let mut pos = start;
+    if start > 0 {
+        push(&mut fragments, "…", false);
+    }
+    while let Some(found) = find(pos).filter(|&i| i + needle.len() <= end) {
+        push(
+            &mut fragments,
+            &chars[pos..found].iter().collect::<String>(),
+            false,
+        );
+        let matched: String = chars[found..found + needle.len()].iter().collect();
+        push(&mut fragments, &matched, true);
+        pos = found + needle.len();
+    }
+    push(
+        &mut fragments,
+        &chars[pos..end].iter().collect::<String>(),
+        false,
+    );
+    if end < chars.len() {
+        push(&mut fragments, "…", false);
+    }
+    fragments
+}
+
+/// Appends text to the fragments, merging it with the previous fragment if
+/// both are (not) highlighted.
+#[cfg(feature = "ssr")]
+fn push(fragments: &mut Vec<Fragment>, text: &str, highlight: bool) {
+    if text.is_empty() {
+        return;
+    }
+    match fragments.last_mut() {
+        Some(last) if last.highlight == highlight => last.text.push_str(text),
+        _ => fragments.push(Fragment {
+            text: text.to_owned(),
+            highlight,
+        }),
+    }
+}
+
+#[server(endpoint = "search_notes", client = crate::client::Client)]
+pub async fn search_notes(
+    token: String,
+    query: String,
+) -> Result<Vec<SearchResult>, ServerFnError> {
+    use crate::app::authenticate;
+
+    let username = authenticate(&token).await?.username;
+    if query.chars().count() > MAX_QUERY_LENGTH {
+        return Err(ServerFnError::new("Input too long"));
+    }
+    search(Backend::from_env(), &username, &query)
+        .await
+        .map_err(|e| ServerFnError::new(e.to_string()))
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use super::*;
+    use crate::{database, testing};
+
+    fn fragment(text: &str, highlight: bool) -> Fragment {
+        Fragment {
+            text: text.into(),
+            highlight,
+        }
+    }
+
+    fn text(fragments: &[Fragment]) -> Vec<(&str, bool)> {
+        fragments
+            .iter()
+            .map(|f| (f.text.as_str(), f.highlight))
+            .collect()
+    }
+
+    #[test]
+    fn parse_delimited_snippet() {
+        assert_eq!(
+            parse_snippet("a \u{2}quick\u{3} brown \u{2}fox\u{3}"),
+            [
+                fragment("a ", false),
+                fragment("quick", true),
+                fragment(" brown ", false),
+                fragment("fox", true),
+            ]
+        );
+        assert_eq!(parse_snippet("plain"), [fragment("plain", false)]);
+        assert_eq!(parse_snippet("\u{2}open"), [fragment("open", true)]);
+        assert!(parse_snippet("").is_empty());
+    }
+
+    #[test]
+    fn highlight_occurrences() {
+        assert_eq!(
+            text(&highlight("The Fox and the fox", "fox")),
+            [
+                ("The ", false),
+                ("Fox", true),
+                (" and the ", false),
+                ("fox", true)
+            ]
+        );
+        assert_eq!(text(&highlight("nothing", "fox")), [("nothing", false)]);
+
+        let long = format!("{}needle{}", "x".repeat(100), "y".repeat(100));
+        let fragments = highlight(&long, "needle");
+        assert_eq!(
+            fragments[0].text,
+            format!("…{}", "x".repeat(SNIPPET_CONTEXT))
+        );
+        assert_eq!(fragments[1], fragment("needle", true));
+        assert_eq!(
+            fragments[2].text,
+            format!("{}…", "y".repeat(SNIPPET_CONTEXT))
+        );
+    }
+
+    #[tokio::test]
+    async fn backends_find_own_notes() {
+        testing::isolated(async {
+            for user in ["uma", "vic"] {
+                database::create_user(user, "$argon2id$test").await.unwrap();
+            }
+            database::create_note("uma", "Running", "Morning runs in the park")
+                .await
+                .unwrap();
+            database::create_note("uma", "Groceries", "Milk, eggs and running shoes")
+                .await
+                .unwrap();
+            database::create_note("vic", "Running", "Vic's secret running plan")
+                .await
+                .unwrap();
+
+            // Stemming matches "runs" and "running", title matches rank first
+            let results = search(Backend::FullText, "uma", "run").await.unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].title, "Running");
+            assert!(results[0].rank >= results[1].rank);
+            assert!(results[0].snippet.iter().any(|f| f.highlight));
+
+            let results = search(Backend::Like, "uma", "RUNNING").await.unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].title, "Running");
+            assert!(results[1].snippet.contains(&fragment("running", true)));
+
+            // LIKE wildcards are matched literally
+            assert!(search(Backend::Like, "uma", "%").await.unwrap().is_empty());
+
+            for backend in [Backend::FullText, Backend::Like] {
+                assert!(search(backend, "uma", "  ").await.unwrap().is_empty());
+                assert!(search(backend, "uma", "secret").await.unwrap().is_empty());
+                assert_eq!(search(backend, "vic", "secret").await.unwrap().len(), 1);
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,328 @@
+//! Blob storage for user uploads.
+//!
+//! Uploads go through the [`Storage`] trait so that deployments can swap the
+//! local disk for another backend. Keys are relative, slash-separated paths
+//! such as `avatars/<id>.png`.
+
+use std::{
+    env,
+    future::Future,
+    io,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::OnceLock,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait Storage: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Returns `None` if nothing is stored under the key.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>>;
+
+    /// Deleting a missing key is not an error.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Stores blobs as files below a root directory.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        let valid = !key.is_empty()
+            && relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage key: {key}"),
+            ));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Write to a temporary file first so readers never see partial data
+            let tmp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4().simple()));
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &path).await
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            match tokio::fs::read(self.path(key)?).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// Content-addressed blob store on the local file system. Blobs are hashed
+/// while being written to a temporary file and then moved to a path derived
+/// from their SHA-256 digest, so identical contents are only stored once.
+#[derive(Debug, Clone)]
+pub struct ContentStore {
+    root: PathBuf,
+}
+
+impl ContentStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Starts writing a new blob.
+    pub async fn create(&self) -> io::Result<BlobWriter> {
+        let dir = self.root.join("tmp");
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(uuid::Uuid::new_v4().simple().to_string());
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(BlobWriter {
+            file,
+            path: Some(path),
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    /// Moves a finished blob to its content address. Storing contents that
+    /// already exist replaces them with identical data.
+    pub async fn commit(&self, mut blob: Blob) -> io::Result<()> {
+        let path = self.path(&blob.digest)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if let Some(tmp) = blob.tmp.take() {
+            tokio::fs::rename(tmp, path).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if no blob with the digest is stored.
+    pub async fn open(&self, digest: &str) -> io::Result<Option<tokio::fs::File>> {
+        match tokio::fs::File::open(self.path(digest)?).await {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deleting a missing blob is not an error.
+    pub async fn delete(&self, digest: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(digest)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, digest: &str) -> io::Result<PathBuf> {
+        let valid = digest.len() == 64
+            && digest
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid digest: {digest}"),
+            ));
+        }
+        Ok(self.root.join(&digest[..2]).join(digest))
+    }
+}
+
+/// A blob being written. The temporary file is removed if the writer is
+/// dropped before being finished.
+#[derive(Debug)]
+pub struct BlobWriter {
+    file: tokio::fs::File,
+    path: Option<PathBuf>,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Flushes the contents to disk and returns the blob with its digest.
+    pub async fn finish(mut self) -> io::Result<Blob> {
+        self.file.sync_all().await?;
+        let digest = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        Ok(Blob {
+            digest,
+            size: self.size,
+            tmp: self.path.take(),
+        })
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A completely written blob, not yet moved to its content address by
+/// [`ContentStore::commit`]. Dropping it discards the contents.
+#[derive(Debug)]
+pub struct Blob {
+    pub digest: String,
+    pub size: u64,
+    tmp: Option<PathBuf>,
+}
+
+impl Drop for Blob {
+    fn drop(&mut self) {
+        if let Some(path) = self.tmp.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+static CONTENT_STORE: OnceLock<ContentStore> = OnceLock::new();
+
+fn root() -> PathBuf {
+    env::var("STORAGE_DIR")
+        .unwrap_or_else(|_| "data".into())
+        .into()
+}
+
+/// The configured storage, a [`LocalStorage`] below `STORAGE_DIR` (default
+/// `data`) unless [`set`] installed another one.
+pub fn storage() -> &'static dyn Storage {
+    STORAGE
+        .get_or_init(|| Box::new(LocalStorage::new(root())))
+        .as_ref()
+}
+
+/// The content store for attachments, below `blobs` in `STORAGE_DIR`.
+pub fn content_store() -> &'static ContentStore {
+    CONTENT_STORE.get_or_init(|| ContentStore::new(root().join("blobs")))
+}
+
+/// Installs a storage backend. Fails if one is already in use.
+pub fn set(storage: Box<dyn Storage>) -> Result<(), Box<dyn Storage>> {
+    STORAGE.set(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_storage_round_trip() {
+        let root = env::temp_dir().join(format!("webapp-{}", uuid::Uuid::new_v4().simple()));
+        let storage = LocalStorage::new(&root);
+
+        assert_eq!(storage.get("avatars/a.png").await.unwrap(), None);
+        storage.put("avatars/a.png", b"one".to_vec()).await.unwrap();
+        storage.put("avatars/a.png", b"two".to_vec()).await.unwrap();
+        assert_eq!(
+            storage.get("avatars/a.png").await.unwrap(),
+            Some(b"two".to_vec())
+        );
+
+        storage.delete("avatars/a.png").await.unwrap();
+        storage.delete("avatars/a.png").await.unwrap();
+        assert_eq!(storage.get("avatars/a.png").await.unwrap(), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn content_store_deduplicates() {
+        let root = env::temp_dir().join(format!("webapp-{}", uuid::Uuid::new_v4().simple()));
+        let store = ContentStore::new(&root);
+
+        let mut digests = Vec::new();
+        for _ in 0..2 {
+            let mut writer = store.create().await.unwrap();
+            writer.write(b"hello ").await.unwrap();
+            writer.write(b"world").await.unwrap();
+            assert_eq!(writer.size(), 11);
+            let blob = writer.finish().await.unwrap();
+            digests.push(blob.digest.clone());
+            store.commit(blob).await.unwrap();
+        }
+        let digest = &digests[0];
+        assert_eq!(digests[1], *digest);
+        assert_eq!(
+            digest,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        let mut contents = String::new();
+        let mut file = store.open(digest).await.unwrap().unwrap();
+        tokio::io::AsyncReadExt::read_to_string(&mut file, &mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, "hello world");
+
+        // Abandoned uploads leave no files behind
+        let mut writer = store.create().await.unwrap();
+        writer.write(b"partial").await.unwrap();
+        drop(writer);
+        let blob = store.create().await.unwrap().finish().await.unwrap();
+        drop(blob);
+        assert_eq!(std::fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+        store.delete(digest).await.unwrap();
+        store.delete(digest).await.unwrap();
+        assert!(store.open(digest).await.unwrap().is_none());
+        assert!(store.open("../../etc/passwd").await.is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_storage_rejects_escaping_keys() {
+        let storage = LocalStorage::new(env::temp_dir());
+        for key in ["", "../etc/passwd", "/etc/passwd", "avatars/../../x", "./a"] {
+            let err = storage.get(key).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{key}");
+        }
+    }
+}
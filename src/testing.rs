@@ -0,0 +1,102 @@
+//! Test fixtures for running database tests in isolation and in parallel.
+
+use sqlx::{Connection, PgConnection, PgPool, postgres::PgPoolOptions};
+use std::{env, future::Future, panic, thread};
+
+use crate::{database, migrations};
+
+/// A uniquely named schema with all migrations applied, reachable through a
+/// pool whose `search_path` only contains that schema. Dropping it closes the
+/// pool and drops the schema with everything in it.
+pub struct TestDatabase {
+    pub pool: PgPool,
+    pub schema: String,
+    url: String,
+}
+
+impl TestDatabase {
+    pub async fn new() -> Self {
+        let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set for tests");
+        let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+
+        let mut conn = PgConnection::connect(&url)
+            .await
+            .expect("failed to connect to test database");
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&mut conn)
+            .await
+            .expect("failed to create test schema");
+        conn.close().await.ok();
+
+        let options = database::Config::from_env()
+            .and_then(|config| config.connect_options())
+            .expect("invalid test database configuration")
+            .application_name(&schema)
+            .options([("search_path", &schema)]);
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await
+            .expect("failed to connect to test schema");
+        migrations::up(&pool)
+            .await
+            .expect("failed to run migrations");
+
+        Self { pool, schema, url }
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        // Closing starts when called; awaiting it would wait for connections
+        // which the blocked runtime of the test is still returning to the pool.
+        drop(self.pool.close());
+
+        let schema = self.schema.clone();
+        let url = self.url.clone();
+        let cleanup = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to start cleanup runtime");
+            runtime.block_on(async {
+                let mut conn = PgConnection::connect(&url)
+                    .await
+                    .expect("failed to connect to test database");
+                // Connections not yet closed could hold locks on the schema
+                sqlx::query(
+                    "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+                     WHERE application_name = $1",
+                )
+                .bind(&schema)
+                .execute(&mut conn)
+                .await
+                .expect("failed to close test connections");
+                sqlx::query(&format!("DROP SCHEMA {schema} CASCADE"))
+                    .execute(&mut conn)
+                    .await
+                    .expect("failed to drop test schema");
+                conn.close().await.ok();
+            });
+        });
+        if cleanup.join().is_err() && !thread::panicking() {
+            panic!("failed to clean up test database {}", self.schema);
+        }
+    }
+}
+
+/// Runs a test against a fresh [`TestDatabase`], which all functions in
+/// [`database`] use for the duration of the test. The schema is dropped
+/// afterwards, even if the test panics.
+pub async fn isolated<F>(test: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let db = TestDatabase::new().await;
+    let result = tokio::spawn(database::with_pool(db.pool.clone(), test)).await;
+    db.pool.close().await;
+    drop(db);
+    if let Err(e) = result {
+        panic::resume_unwind(e.into_panic());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, confusable_detection};
+
+const MIN_LENGTH: usize = 3;
+pub const MAX_LENGTH: usize = 32;
+
+const RESERVED: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "healthz",
+    "moderator",
+    "null",
+    "root",
+    "support",
+    "system",
+    "webapp",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+    InvalidSeparator,
+    Reserved,
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "Username must be at least {MIN_LENGTH} characters"),
+            Self::TooLong => write!(f, "Username must be at most {MAX_LENGTH} characters"),
+            Self::InvalidCharacter(c) => write!(f, "Username contains invalid character {c:?}"),
+            Self::InvalidSeparator => {
+                write!(f, "Username must start and end with a letter or digit")
+            }
+            Self::Reserved => write!(f, "Username is reserved"),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// Applies NFKC normalization and trims surrounding whitespace. This is the
+/// form in which usernames are stored and displayed.
+pub fn normalize(input: &str) -> String {
+    input.trim().nfkc().collect()
+}
+
+/// Normalizes a username given to sign in, or returns `None` if it is longer
+/// than any valid username, so that it is neither looked up nor audited.
+pub fn candidate(input: &str) -> Option<String> {
+    Some(normalize(input)).filter(|username| username.chars().count() <= MAX_LENGTH)
+}
+
+/// Full Unicode case folding of a normalized username, used for uniqueness and
+/// lookup, so that e.g. "Straße" and "STRASSE" are the same user.
+pub fn fold(username: &str) -> String {
+    caseless::default_case_fold_str(username).nfkc().collect()
+}
+
+/// UTS 39 confusable skeleton of the folded username, so that e.g. a Cyrillic
+/// "а" collides with a Latin "a".
+pub fn skeleton(username: &str) -> String {
+    confusable_detection::skeleton(&fold(username)).collect()
+}
+
+/// Normalizes and validates a username for registration, returning the form
+/// to be stored.
+pub fn validate(input: &str) -> Result<String, UsernameError> {
+    let username = normalize(input);
+
+    let len = username.chars().count();
+    if len < MIN_LENGTH {
+        return Err(UsernameError::TooShort);
+    }
+    if len > MAX_LENGTH {
+        return Err(UsernameError::TooLong);
+    }
+
+    if let Some(c) = username.chars().find(|&c| !is_allowed(c)) {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+
+    if !username.starts_with(char::is_alphanumeric) || !username.ends_with(char::is_alphanumeric) {
+        return Err(UsernameError::InvalidSeparator);
+    }
+
+    let skeleton = skeleton(&username);
+    if RESERVED.iter().any(|r| self::skeleton(r) == skeleton) {
+        return Err(UsernameError::Reserved);
+    }
+
+    Ok(username)
+}
+
+fn is_allowed(c: char) -> bool {
+    matches!(c, '-' | '_' | '.') || (c.is_alphanumeric() && c.identifier_allowed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_usernames() {
+        assert_eq!(validate("alice").unwrap(), "alice");
+        assert_eq!(validate("Alice_92").unwrap(), "Alice_92");
+        assert_eq!(validate("jean-luc.picard").unwrap(), "jean-luc.picard");
+        assert_eq!(validate("müller").unwrap(), "müller");
+    }
+
+    #[test]
+    fn normalization_applies_nfkc_and_trims() {
+        assert_eq!(validate("  alice ").unwrap(), "alice");
+        // Fullwidth letters normalize to ASCII
+        assert_eq!(validate("ａｌｉｃｅ").unwrap(), "alice");
+        // Decomposed and precomposed forms are equal after normalization
+        assert_eq!(normalize("mu\u{308}ller"), normalize("müller"));
+    }
+
+    #[test]
+    fn candidates_are_bounded() {
+        assert_eq!(candidate(" alice "), Some("alice".into()));
+        assert_eq!(candidate(&"a".repeat(32)), Some("a".repeat(32)));
+        assert_eq!(candidate(&"a".repeat(33)), None);
+    }
+
+    #[test]
+    fn folding_is_case_insensitive() {
+        assert_eq!(fold("Alice"), fold("alice"));
+        assert_eq!(fold("ALICE"), "alice");
+        assert_eq!(fold("Straße"), fold("STRASSE"));
+        // Final and non-final sigma
+        assert_eq!(fold("ΟΔΥΣΣΕΥΣ"), fold("οδυσσευς"));
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        assert_eq!(validate(""), Err(UsernameError::TooShort));
+        assert_eq!(validate("ab"), Err(UsernameError::TooShort));
+        assert_eq!(validate(&"a".repeat(33)), Err(UsernameError::TooLong));
+        assert!(validate(&"a".repeat(32)).is_ok());
+    }
+
+    #[test]
+    fn invalid_characters() {
+        assert_eq!(
+            validate("alice bob"),
+            Err(UsernameError::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            validate("alice@bob"),
+            Err(UsernameError::InvalidCharacter('@'))
+        );
+        assert!(matches!(
+            validate("alice\u{200b}"),
+            Err(UsernameError::InvalidCharacter(_))
+        ));
+        assert_eq!(validate("_alice"), Err(UsernameError::InvalidSeparator));
+        assert_eq!(validate("alice."), Err(UsernameError::InvalidSeparator));
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert_eq!(validate("admin"), Err(UsernameError::Reserved));
+        assert_eq!(validate("Root"), Err(UsernameError::Reserved));
+        // Cyrillic "а" in place of the Latin one
+        assert_eq!(validate("\u{430}dmin"), Err(UsernameError::Reserved));
+    }
+
+    #[test]
+    fn confusable_skeletons_collide() {
+        assert_eq!(skeleton("alice"), skeleton("\u{430}lice"));
+        assert_eq!(skeleton("Alice"), skeleton("alice"));
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+    }
+}
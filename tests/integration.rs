@@ -1,119 +1,225 @@
 #![cfg(feature = "ssr")]
 
-use std::env;
+use webapp::{auth, database, health, migrations, notes, testing};
 
-use sqlx::postgres::PgPoolOptions;
-use webapp::{auth, database};
-
-async fn setup() {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set for tests");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await
-        .expect("failed to connect to test database");
-    sqlx::query("DROP TABLE IF EXISTS sessions")
-        .execute(&pool)
+/// Creates a user with a session and returns the session token.
+async fn login(username: &str) -> String {
+    database::create_user(username, "$argon2id$test")
         .await
         .unwrap();
-    sqlx::query("DROP TABLE IF EXISTS users")
-        .execute(&pool)
+    let token = auth::create_token(username).unwrap();
+    database::create_session(&token, username, auth::token_expiry())
         .await
         .unwrap();
-    sqlx::query("DROP TABLE IF EXISTS _sqlx_migrations")
-        .execute(&pool)
-        .await
-        .unwrap();
-    drop(pool);
-    database::init().await.unwrap();
+    token
 }
 
 #[tokio::test]
 async fn auth_and_session_flow() {
-    setup().await;
-    let pool = database::pool();
-
-    // Register a user
-    let hash = auth::hash_password("secret123").unwrap();
-    database::create_user("testuser", &hash).await.unwrap();
-    assert!(database::user_exists("testuser").await.unwrap());
-    assert!(!database::user_exists("nobody").await.unwrap());
-
-    // Verify password
-    let stored_hash = database::get_password_hash("testuser")
-        .await
-        .unwrap()
-        .unwrap();
-    assert!(auth::verify_password("secret123", &stored_hash).unwrap());
-    assert!(!auth::verify_password("wrong", &stored_hash).unwrap());
-    assert!(
-        database::get_password_hash("nobody")
+    testing::isolated(async {
+        // Register a user
+        let hash = auth::hash_password("secret123").unwrap();
+        database::create_user("testuser", &hash).await.unwrap();
+        assert!(database::user_exists("testuser").await.unwrap());
+        assert!(!database::user_exists("nobody").await.unwrap());
+
+        // Verify password
+        let stored_hash = database::get_password_hash("testuser")
             .await
             .unwrap()
-            .is_none()
-    );
+            .unwrap();
+        assert!(auth::verify_password("secret123", &stored_hash).unwrap());
+        assert!(!auth::verify_password("wrong", &stored_hash).unwrap());
+        assert!(
+            database::get_password_hash("nobody")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // Duplicate user fails
+        assert!(database::create_user("testuser", &hash).await.is_err());
+
+        // Create session
+        let token = auth::create_token("testuser").unwrap();
+        let expires = auth::token_expiry();
+        database::create_session(&token, "testuser", expires)
+            .await
+            .unwrap();
+        assert!(database::session_exists(&token).await.unwrap());
+
+        // Verify token
+        let username = auth::verify_token(&token).unwrap();
+        assert_eq!(username, "testuser");
+
+        // Invalid token fails
+        assert!(auth::verify_token("garbage").is_err());
+
+        // Password hashes use unique salts
+        let h1 = auth::hash_password("same").unwrap();
+        let h2 = auth::hash_password("same").unwrap();
+        assert_ne!(h1, h2);
+
+        // Renew session
+        let new_token = auth::create_token("testuser").unwrap();
+        let new_expires = auth::token_expiry();
+        assert!(
+            database::update_session(&token, &new_token, new_expires)
+                .await
+                .unwrap()
+        );
+        assert!(!database::session_exists(&token).await.unwrap());
+        assert!(database::session_exists(&new_token).await.unwrap());
+
+        // Logout
+        assert!(database::delete_session(&new_token).await.unwrap());
+        assert!(!database::session_exists(&new_token).await.unwrap());
+        assert!(!database::delete_session(&new_token).await.unwrap());
+
+        // Expired session cleanup
+        let past = chrono::Utc::now() - chrono::Duration::hours(2);
+        database::create_session("old_token", "testuser", past)
+            .await
+            .unwrap();
+        let future = chrono::Utc::now() + chrono::Duration::hours(1);
+        database::create_session("fresh_token", "testuser", future)
+            .await
+            .unwrap();
+
+        let cleaned = database::delete_expired_sessions().await.unwrap();
+        assert_eq!(cleaned, 1);
+        assert!(!database::session_exists("old_token").await.unwrap());
+        assert!(database::session_exists("fresh_token").await.unwrap());
+
+        // Health report
+        let report = health::report().await;
+        assert_eq!(report.status, health::Status::Ok);
+        assert!(report.database.reachable);
+        assert!(report.database.latency_ms.is_some());
+        assert!(report.database.pool.size >= 1);
+        let migrations = report.database.migrations.unwrap();
+        assert_eq!(migrations.pending, 0);
+        assert!(migrations.version.is_some());
+    })
+    .await;
+}
 
-    // Duplicate user fails
-    assert!(database::create_user("testuser", &hash).await.is_err());
+#[tokio::test]
+async fn notes_are_private() {
+    testing::isolated(async {
+        let alice = login("alice").await;
+        let bob = login("bob").await;
 
-    // Create session
-    let token = auth::create_token("testuser").unwrap();
-    let expires = auth::token_expiry();
-    database::create_session(&token, "testuser", expires)
-        .await
-        .unwrap();
-    assert!(database::session_exists(&token).await.unwrap());
+        let note = notes::create_note(alice.clone(), "Diary".into(), "Secret".into())
+            .await
+            .unwrap();
+        assert_eq!(notes::get_note(alice.clone(), note.id).await.unwrap(), note);
+
+        // Bob can neither see, read, change nor delete Alice's note
+        let page = notes::list_notes(bob.clone(), 0).await.unwrap();
+        assert!(page.notes.is_empty());
+        assert_eq!(page.total, 0);
+        assert!(notes::get_note(bob.clone(), note.id).await.is_err());
+        assert!(
+            notes::update_note(bob.clone(), note.id, "Mine".into(), String::new())
+                .await
+                .is_err()
+        );
+        assert!(notes::delete_note(bob.clone(), note.id).await.is_err());
+        assert_eq!(notes::get_note(alice.clone(), note.id).await.unwrap(), note);
+
+        // Not even with a forged token for a session that does not exist
+        let forged = auth::create_token("alice").unwrap();
+        assert!(notes::list_notes(forged, 0).await.is_err());
+
+        // Alice can edit and delete her own note
+        let updated = notes::update_note(alice.clone(), note.id, "Diary".into(), "New".into())
+            .await
+            .unwrap();
+        assert_eq!(updated.body, "New");
+        notes::delete_note(alice.clone(), note.id).await.unwrap();
+        assert!(notes::get_note(alice, note.id).await.is_err());
+    })
+    .await;
+}
 
-    // Verify token
-    let username = auth::verify_token(&token).unwrap();
-    assert_eq!(username, "testuser");
+#[tokio::test]
+async fn notes_are_paginated() {
+    testing::isolated(async {
+        let token = login("carol").await;
+        let count = notes::PAGE_SIZE + 3;
+        for i in 0..count {
+            notes::create_note(token.clone(), format!("Note {i}"), String::new())
+                .await
+                .unwrap();
+        }
+
+        let first = notes::list_notes(token.clone(), 0).await.unwrap();
+        assert_eq!(first.notes.len(), notes::PAGE_SIZE as usize);
+        assert_eq!(first.total, i64::from(count));
+        assert_eq!(first.pages(), 2);
+        assert_eq!(first.notes[0].title, format!("Note {}", count - 1));
+
+        let second = notes::list_notes(token.clone(), 1).await.unwrap();
+        assert_eq!(second.notes.len(), 3);
+        assert_eq!(second.notes[2].title, "Note 0");
+
+        assert!(
+            notes::create_note(token, "   ".into(), String::new())
+                .await
+                .is_err()
+        );
+    })
+    .await;
+}
 
-    // Invalid token fails
-    assert!(auth::verify_token("garbage").is_err());
+#[tokio::test]
+async fn migrations_round_trip() {
+    testing::isolated(async {
+        let pool = &database::pool();
+        let total = migrations::status(pool).await.unwrap().len();
+        assert!(migrations::pending(pool).await.unwrap().is_empty());
+
+        // Revert only the latest migration
+        let reverted = migrations::down(pool, None).await.unwrap();
+        assert_eq!(reverted.len(), 1);
+        assert_eq!(migrations::pending(pool).await.unwrap().len(), 1);
+
+        // Revert everything, then re-apply
+        migrations::down(pool, Some(0)).await.unwrap();
+        assert_eq!(migrations::pending(pool).await.unwrap().len(), total);
+        migrations::up(pool).await.unwrap();
+        assert!(migrations::pending(pool).await.unwrap().is_empty());
+    })
+    .await;
+}
 
-    // Password hashes use unique salts
-    let h1 = auth::hash_password("same").unwrap();
-    let h2 = auth::hash_password("same").unwrap();
-    assert_ne!(h1, h2);
+#[tokio::test]
+async fn tests_are_isolated() {
+    let db = testing::TestDatabase::new().await;
+    let other = testing::TestDatabase::new().await;
+    assert_ne!(db.schema, other.schema);
 
-    // Renew session
-    let new_token = auth::create_token("testuser").unwrap();
-    let new_expires = auth::token_expiry();
-    assert!(
-        database::update_session(&token, &new_token, new_expires)
+    database::with_pool(db.pool.clone(), async {
+        database::create_user("isolated", "$argon2id$hash")
             .await
-            .unwrap()
-    );
-    assert!(!database::session_exists(&token).await.unwrap());
-    assert!(database::session_exists(&new_token).await.unwrap());
-
-    // Logout
-    assert!(database::delete_session(&new_token).await.unwrap());
-    assert!(!database::session_exists(&new_token).await.unwrap());
-    assert!(!database::delete_session(&new_token).await.unwrap());
-
-    // Expired session cleanup
-    let past = chrono::Utc::now() - chrono::Duration::hours(2);
-    database::create_session("old_token", "testuser", past)
-        .await
-        .unwrap();
-    let future = chrono::Utc::now() + chrono::Duration::hours(1);
-    database::create_session("fresh_token", "testuser", future)
-        .await
-        .unwrap();
-
-    let cleaned = database::delete_expired_sessions().await.unwrap();
-    assert_eq!(cleaned, 1);
-    assert!(!database::session_exists("old_token").await.unwrap());
-    assert!(database::session_exists("fresh_token").await.unwrap());
-
-    // Clean up for unit tests that may run after
-    sqlx::query("DELETE FROM sessions")
-        .execute(pool)
-        .await
-        .unwrap();
-    sqlx::query("DELETE FROM users")
-        .execute(pool)
-        .await
-        .unwrap();
+            .unwrap();
+        assert!(database::user_exists("isolated").await.unwrap());
+    })
+    .await;
+    database::with_pool(other.pool.clone(), async {
+        assert!(!database::user_exists("isolated").await.unwrap());
+    })
+    .await;
+
+    // Dropping a database drops its schema
+    let schema = db.schema.clone();
+    drop(db);
+    let (exists,): (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)")
+            .bind(&schema)
+            .fetch_one(&other.pool)
+            .await
+            .unwrap();
+    assert!(!exists);
 }